@@ -0,0 +1,14 @@
+-- in-progress resumable (tus) uploads, the partial data lives in the upload dir
+CREATE TABLE IF NOT EXISTS uploads (
+    id TEXT PRIMARY KEY NOT NULL,
+    space_id TEXT NOT NULL,
+    original_filename TEXT NOT NULL,
+    mime_type TEXT,
+    upload_length BIGINT NOT NULL,
+    upload_offset BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (space_id) REFERENCES spaces(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_uploads_space_id ON uploads(space_id);
@@ -0,0 +1,16 @@
+-- one row per stored blob (files are stored by their checksum), refcount = number of files rows using it
+CREATE TABLE IF NOT EXISTS blobs (
+    checksum TEXT PRIMARY KEY NOT NULL,
+    size_bytes BIGINT NOT NULL,
+    refcount BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO blobs (checksum, size_bytes, refcount)
+SELECT checksum, MAX(file_size_bytes), COUNT(*) FROM files GROUP BY checksum
+ON CONFLICT (checksum) DO NOTHING;
+
+ALTER TABLE files
+ADD FOREIGN KEY (checksum) REFERENCES blobs(checksum);
+
+CREATE INDEX IF NOT EXISTS idx_blobs_unreferenced ON blobs(checksum) WHERE refcount = 0;
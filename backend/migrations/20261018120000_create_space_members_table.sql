@@ -0,0 +1,16 @@
+-- ordered from least to most privileged, so roles can be compared with >=
+CREATE TYPE space_role AS ENUM ('viewer', 'uploader', 'editor', 'owner');
+
+-- spaces created before accounts existed have no members, an owner has to be assigned to them
+CREATE TABLE IF NOT EXISTS space_members (
+    space_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    role space_role NOT NULL,
+    added_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (space_id, user_id),
+    FOREIGN KEY (space_id) REFERENCES spaces(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_space_members_user_id ON space_members(user_id);
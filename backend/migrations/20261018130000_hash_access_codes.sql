@@ -0,0 +1,17 @@
+-- plaintext codes left in here are hashed with argon2 on the next startup
+ALTER TABLE spaces RENAME COLUMN access_code TO access_code_hash;
+ALTER TABLE spaces ADD COLUMN has_access_code BOOLEAN NOT NULL GENERATED ALWAYS AS (access_code_hash IS NOT NULL) STORED;
+
+CREATE TABLE IF NOT EXISTS space_tokens (
+    token_hash TEXT PRIMARY KEY NOT NULL, -- SHA256 of the token handed out by the unlock endpoint
+    space_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ NOT NULL,
+
+    FOREIGN KEY (space_id) REFERENCES spaces(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_space_tokens_space_id ON space_tokens(space_id);
+CREATE INDEX IF NOT EXISTS idx_space_tokens_expires_at ON space_tokens(expires_at);
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS share_links (
+    id TEXT PRIMARY KEY NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE, -- SHA256 of the token in the /s/{token} url
+    space_id TEXT NOT NULL,
+    file_id TEXT, -- NULL shares the whole space
+    created_by TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ,
+    max_downloads INTEGER,
+    download_count INTEGER NOT NULL DEFAULT 0,
+    password_hash TEXT, -- argon2 PHC string
+    revoked_at TIMESTAMPTZ,
+
+    FOREIGN KEY (space_id) REFERENCES spaces(id) ON DELETE CASCADE,
+    FOREIGN KEY (file_id) REFERENCES files(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_share_links_space_id ON share_links(space_id);
@@ -0,0 +1,2 @@
+-- NULL until the thumbnails of an image blob have been generated, 'failed' for content that can't be decoded
+ALTER TABLE blobs ADD COLUMN thumbnail_status TEXT CHECK (thumbnail_status IN ('ready', 'failed'));
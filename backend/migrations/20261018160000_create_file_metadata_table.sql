@@ -0,0 +1,22 @@
+-- metadata describes the content, so it is stored per blob and shared by all files with that content
+CREATE TABLE IF NOT EXISTS file_metadata (
+    checksum TEXT PRIMARY KEY NOT NULL,
+    captured_at TIMESTAMPTZ, -- capture time, UTC if the file doesn't say which time zone it was taken in
+    camera_make TEXT,
+    camera_model TEXT,
+    width INTEGER,
+    height INTEGER,
+    orientation SMALLINT, -- EXIF orientation 1-8
+    latitude DOUBLE PRECISION,
+    longitude DOUBLE PRECISION,
+    altitude DOUBLE PRECISION,
+    duration_seconds DOUBLE PRECISION,
+    title TEXT,
+    artist TEXT,
+    album TEXT,
+    extracted_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (checksum) REFERENCES blobs(checksum) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_metadata_captured_at ON file_metadata(captured_at);
@@ -0,0 +1,2 @@
+-- spaces that hide where their photos and videos were taken, from the API and from downloads
+ALTER TABLE spaces ADD COLUMN strip_gps BOOLEAN NOT NULL DEFAULT FALSE;
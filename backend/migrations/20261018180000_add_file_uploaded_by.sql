@@ -0,0 +1,9 @@
+-- who uploaded a file, kept as NULL for files from before accounts existed or of deleted users
+ALTER TABLE files ADD COLUMN uploaded_by TEXT REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE uploads ADD COLUMN created_by TEXT REFERENCES users(id) ON DELETE SET NULL;
+
+-- the sort orders of file listings, the id breaks ties between pages
+CREATE INDEX IF NOT EXISTS idx_files_space_upload_date ON files(space_id, upload_date, id);
+CREATE INDEX IF NOT EXISTS idx_files_space_name ON files(space_id, lower(original_filename), id);
+CREATE INDEX IF NOT EXISTS idx_files_space_size ON files(space_id, file_size_bytes, id);
+CREATE INDEX IF NOT EXISTS idx_files_space_download_count ON files(space_id, download_count, id);
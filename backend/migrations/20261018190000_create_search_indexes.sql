@@ -0,0 +1,16 @@
+-- fuzzy filename search, the btree index on original_filename only helps exact and prefix matches
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE INDEX IF NOT EXISTS idx_files_original_filename_trgm ON files USING GIN (lower(original_filename) gin_trgm_ops);
+
+-- text of documents (plain text, PDF) for full-text search, stored per blob like file_metadata
+CREATE TABLE IF NOT EXISTS file_texts (
+    checksum TEXT PRIMARY KEY NOT NULL,
+    content TEXT NOT NULL,
+    -- 'simple' doesn't stem, the files of a space are in any language
+    search_vector TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', content)) STORED,
+    extracted_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (checksum) REFERENCES blobs(checksum) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_texts_search_vector ON file_texts USING GIN (search_vector);
@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS folders (
+    id TEXT PRIMARY KEY NOT NULL,
+    space_id TEXT NOT NULL,
+    parent_id TEXT, -- NULL for folders at the top of the space
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (space_id) REFERENCES spaces(id) ON DELETE CASCADE,
+    FOREIGN KEY (parent_id) REFERENCES folders(id) ON DELETE CASCADE,
+    -- directory uploads look folders up by name, so names are unique next to each other
+    UNIQUE NULLS NOT DISTINCT (space_id, parent_id, name)
+);
+
+CREATE INDEX IF NOT EXISTS idx_folders_parent_id ON folders(parent_id);
+
+-- no cascade, the files of a deleted folder have to release their blobs first
+ALTER TABLE files ADD COLUMN folder_id TEXT REFERENCES folders(id);
+CREATE INDEX IF NOT EXISTS idx_files_folder_id ON files(folder_id);
+
+-- where a resumable upload ends up: a folder and the folders of its relative path below it
+ALTER TABLE uploads ADD COLUMN folder_id TEXT REFERENCES folders(id) ON DELETE SET NULL;
+ALTER TABLE uploads ADD COLUMN folder_path TEXT;
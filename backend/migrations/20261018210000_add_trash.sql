@@ -0,0 +1,6 @@
+-- soft delete: trashed rows are kept until the retention period is over, then purged
+ALTER TABLE files ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE spaces ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_files_deleted_at ON files(deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_spaces_deleted_at ON spaces(deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,27 @@
+-- what happens when a file is uploaded next to one with the same name
+ALTER TABLE spaces ADD COLUMN conflict_policy TEXT NOT NULL DEFAULT 'keep_both'
+    CHECK (conflict_policy IN ('keep_both', 'new_version', 'reject'));
+
+-- the files row always holds the current version, earlier ones are kept here
+ALTER TABLE files ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+-- each version holds a reference on its blob, like files rows do
+CREATE TABLE IF NOT EXISTS file_versions (
+    file_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    checksum TEXT NOT NULL,
+    file_size_bytes BIGINT NOT NULL,
+    mime_type TEXT,
+    uploaded_by TEXT,
+    upload_date TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (file_id, version),
+    FOREIGN KEY (file_id) REFERENCES files(id) ON DELETE CASCADE,
+    FOREIGN KEY (checksum) REFERENCES blobs(checksum),
+    FOREIGN KEY (uploaded_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_versions_checksum ON file_versions(checksum);
+
+-- conflicts are looked up by name next to each other
+CREATE INDEX IF NOT EXISTS idx_files_conflict ON files(space_id, folder_id, original_filename) WHERE deleted_at IS NULL;
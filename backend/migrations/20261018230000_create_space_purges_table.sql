@@ -0,0 +1,8 @@
+-- spaces being deleted for good by the background job, a row goes away together with its space
+CREATE TABLE IF NOT EXISTS space_purges (
+    space_id TEXT PRIMARY KEY NOT NULL,
+    requested_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    files_purged BIGINT NOT NULL DEFAULT 0,
+
+    FOREIGN KEY (space_id) REFERENCES spaces(id) ON DELETE CASCADE
+);
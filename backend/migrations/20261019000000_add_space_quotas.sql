@@ -0,0 +1,3 @@
+-- storage limits of a space, NULL falls back to the instance defaults
+ALTER TABLE spaces ADD COLUMN quota_bytes BIGINT CHECK (quota_bytes >= 0);
+ALTER TABLE spaces ADD COLUMN max_file_size_bytes BIGINT CHECK (max_file_size_bytes >= 0);
@@ -0,0 +1,4 @@
+-- a PATCH claims the upload it writes to instead of keeping the row locked while the body arrives,
+-- a claim nobody refreshed for a while is stale (the request died with the server)
+ALTER TABLE uploads ADD COLUMN claim TEXT;
+ALTER TABLE uploads ADD COLUMN claimed_at TIMESTAMPTZ;
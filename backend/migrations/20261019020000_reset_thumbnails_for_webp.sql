@@ -0,0 +1,2 @@
+-- thumbnails are stored as WebP next to JPEG now, regenerate the ones rendered before on their next request
+UPDATE blobs SET thumbnail_status = NULL WHERE thumbnail_status = 'ready';
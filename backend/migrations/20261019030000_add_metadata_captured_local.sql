@@ -0,0 +1,3 @@
+-- the capture time as the camera's clock showed it, for grouping by the day a photo was taken
+-- even when the file doesn't say which time zone that was; NULL for metadata extracted before
+ALTER TABLE file_metadata ADD COLUMN captured_local TIMESTAMP;
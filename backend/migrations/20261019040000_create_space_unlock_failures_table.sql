@@ -0,0 +1,13 @@
+-- wrong access codes per user and space, past a few of them unlocking backs off exponentially
+CREATE TABLE IF NOT EXISTS space_unlock_failures (
+    space_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failed_at TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (space_id, user_id),
+    FOREIGN KEY (space_id) REFERENCES spaces(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_space_unlock_failures_last_failed_at ON space_unlock_failures(last_failed_at);
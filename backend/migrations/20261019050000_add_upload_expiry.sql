@@ -0,0 +1,4 @@
+-- tus expiration extension: an upload nobody made progress on for a while is swept together with
+-- its partial file
+ALTER TABLE uploads ADD COLUMN expires_at TIMESTAMPTZ NOT NULL DEFAULT NOW() + INTERVAL '1 day';
+CREATE INDEX IF NOT EXISTS idx_uploads_expires_at ON uploads(expires_at);
@@ -0,0 +1,3 @@
+-- wrong passwords per share link, past a few of them opening the link backs off exponentially
+ALTER TABLE share_links ADD COLUMN password_failures INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE share_links ADD COLUMN last_password_failure_at TIMESTAMPTZ;
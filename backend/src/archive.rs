@@ -0,0 +1,669 @@
+use std::{collections::HashSet, io, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{
+    Json,
+    body::{Body, Bytes},
+    debug_handler,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    errors::{AppError, ErrorType, IntoAppError},
+    geo::{Redactions, gps_redactions, redact, space_strips_gps},
+    members::{SpaceRole, SpaceToken, require_access},
+    storage::{BlobStore, ByteStream},
+};
+
+// ZIP archives (https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT) are written
+// without compression, most of what's shared (photos, videos) is compressed already.
+// Since every size is known up front, so is the length of the whole archive.
+const LOCAL_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const ZIP64_END: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+
+// sizes and crc follow in the data descriptor, names are UTF-8
+const FLAGS: u16 = 0x0008 | 0x0800;
+const VERSION_ZIP64: u16 = 45;
+const VERSION_DEFAULT: u16 = 20;
+// upper byte 3 = unix, so the external attributes carry file permissions
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+const UNIX_FILE_MODE: u32 = 0o100644;
+
+const U32_MAX: u64 = 0xFFFF_FFFF;
+
+struct ArchiveEntry {
+    name: String,
+    checksum: String,
+    size: u64,
+    modified: OffsetDateTime,
+    redactions: Redactions,
+}
+
+impl ArchiveEntry {
+    fn zip64(&self) -> bool {
+        self.size >= U32_MAX
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+    fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(v);
+        self
+    }
+}
+
+/// MS-DOS time and date, which can't represent anything before 1980.
+fn dos_timestamp(dt: OffsetDateTime) -> (u16, u16) {
+    let dt = dt.to_offset(time::UtcOffset::UTC);
+    if dt.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = ((dt.hour() as u16) << 11) | ((dt.minute() as u16) << 5) | (dt.second() as u16 / 2);
+    let date =
+        (((dt.year() - 1980).min(127) as u16) << 9) | ((dt.month() as u16) << 5) | dt.day() as u16;
+    (time, date)
+}
+
+/// Extended timestamp extra field, carries the modification time with second precision.
+fn extended_timestamp(w: &mut Writer, dt: OffsetDateTime) {
+    let mtime = dt.unix_timestamp().clamp(0, u32::MAX as i64) as u32;
+    w.u16(0x5455).u16(5).bytes(&[1]).u32(mtime);
+}
+
+fn local_header(entry: &ArchiveEntry) -> Vec<u8> {
+    let (time, date) = dos_timestamp(entry.modified);
+    let mut extra = Writer(Vec::new());
+    extended_timestamp(&mut extra, entry.modified);
+    if entry.zip64() {
+        // the real sizes are in the data descriptor
+        extra.u16(0x0001).u16(16).u64(0).u64(0);
+    }
+    let sizes = if entry.zip64() { u32::MAX } else { 0 };
+
+    let mut w = Writer(Vec::new());
+    w.u32(LOCAL_HEADER)
+        .u16(if entry.zip64() {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        })
+        .u16(FLAGS)
+        .u16(0) // stored
+        .u16(time)
+        .u16(date)
+        .u32(0) // crc, in the data descriptor
+        .u32(sizes)
+        .u32(sizes)
+        .u16(entry.name.len() as u16)
+        .u16(extra.0.len() as u16)
+        .bytes(entry.name.as_bytes())
+        .bytes(&extra.0);
+    w.0
+}
+
+fn data_descriptor(entry: &ArchiveEntry, crc: u32) -> Vec<u8> {
+    let mut w = Writer(Vec::new());
+    w.u32(DATA_DESCRIPTOR).u32(crc);
+    if entry.zip64() {
+        w.u64(entry.size).u64(entry.size);
+    } else {
+        w.u32(entry.size as u32).u32(entry.size as u32);
+    }
+    w.0
+}
+
+fn central_header(entry: &ArchiveEntry, offset: u64, crc: u32) -> Vec<u8> {
+    let (time, date) = dos_timestamp(entry.modified);
+    let offset_zip64 = offset >= U32_MAX;
+    let mut extra = Writer(Vec::new());
+    extended_timestamp(&mut extra, entry.modified);
+    if entry.zip64() || offset_zip64 {
+        // only the fields that overflowed are present, in this order
+        let mut zip64 = Writer(Vec::new());
+        if entry.zip64() {
+            zip64.u64(entry.size).u64(entry.size);
+        }
+        if offset_zip64 {
+            zip64.u64(offset);
+        }
+        extra.u16(0x0001).u16(zip64.0.len() as u16).bytes(&zip64.0);
+    }
+    let size = entry.size.min(U32_MAX) as u32;
+
+    let mut w = Writer(Vec::new());
+    w.u32(CENTRAL_HEADER)
+        .u16(VERSION_MADE_BY)
+        .u16(if entry.zip64() || offset_zip64 {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        })
+        .u16(FLAGS)
+        .u16(0)
+        .u16(time)
+        .u16(date)
+        .u32(crc)
+        .u32(size)
+        .u32(size)
+        .u16(entry.name.len() as u16)
+        .u16(extra.0.len() as u16)
+        .u16(0) // comment length
+        .u16(0) // disk number
+        .u16(0) // internal attributes
+        .u32(UNIX_FILE_MODE << 16)
+        .u32(offset.min(U32_MAX) as u32)
+        .bytes(entry.name.as_bytes())
+        .bytes(&extra.0);
+    w.0
+}
+
+fn end_of_central_directory(count: u64, offset: u64, size: u64) -> Vec<u8> {
+    let mut w = Writer(Vec::new());
+    if count >= 0xFFFF || offset >= U32_MAX || size >= U32_MAX {
+        let zip64_end = offset + size;
+        w.u32(ZIP64_END)
+            .u64(44) // size of the rest of this record
+            .u16(VERSION_MADE_BY)
+            .u16(VERSION_ZIP64)
+            .u32(0)
+            .u32(0)
+            .u64(count)
+            .u64(count)
+            .u64(size)
+            .u64(offset);
+        w.u32(ZIP64_LOCATOR).u32(0).u64(zip64_end).u32(1);
+    }
+    w.u32(END_OF_CENTRAL_DIRECTORY)
+        .u16(0)
+        .u16(0)
+        .u16(count.min(0xFFFF) as u16)
+        .u16(count.min(0xFFFF) as u16)
+        .u32(size.min(U32_MAX) as u32)
+        .u32(offset.min(U32_MAX) as u32)
+        .u16(0);
+    w.0
+}
+
+/// Length of the archive holding `entries`, the crc doesn't change the size of any record.
+fn archive_len(entries: &[ArchiveEntry]) -> u64 {
+    let mut offset = 0;
+    let mut central_size = 0;
+    for entry in entries {
+        central_size += central_header(entry, offset, 0).len() as u64;
+        offset += (local_header(entry).len() + data_descriptor(entry, 0).len()) as u64 + entry.size;
+    }
+    offset
+        + central_size
+        + end_of_central_directory(entries.len() as u64, offset, central_size).len() as u64
+}
+
+/// Writes the archive into `sender` while streaming every blob from the store.
+/// Stops as soon as the receiving side (the response body) is dropped.
+async fn write_archive(
+    store: Arc<dyn BlobStore>,
+    entries: Vec<ArchiveEntry>,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+) -> Result<(), AppError> {
+    let send = |chunk: Vec<u8>| sender.send(Ok(Bytes::from(chunk)));
+    let closed = || anyhow!("Archive download aborted by the client");
+
+    let mut offset = 0;
+    let mut central = Vec::new();
+    for entry in &entries {
+        let header = local_header(entry);
+        let header_len = header.len() as u64;
+        send(header)
+            .await
+            .map_err(|_| closed())
+            .into_internal_error()?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut written = 0;
+        let mut blob = redact(
+            store.get(&entry.checksum).await?,
+            0,
+            entry.redactions.clone(),
+        );
+        while let Some(chunk) = blob.next().await {
+            let chunk = chunk.into_internal_error()?;
+            hasher.update(&chunk);
+            written += chunk.len() as u64;
+            sender
+                .send(Ok(chunk))
+                .await
+                .map_err(|_| closed())
+                .into_internal_error()?;
+        }
+        if written != entry.size {
+            return Err(AppError::new(
+                ErrorType::Internal("Stored file is corrupted".into()),
+                anyhow!(
+                    "Blob {} has {} bytes, expected {}",
+                    entry.checksum,
+                    written,
+                    entry.size
+                ),
+            ));
+        }
+
+        let crc = hasher.finalize();
+        let descriptor = data_descriptor(entry, crc);
+        let descriptor_len = descriptor.len() as u64;
+        send(descriptor)
+            .await
+            .map_err(|_| closed())
+            .into_internal_error()?;
+
+        central.extend(central_header(entry, offset, crc));
+        offset += header_len + entry.size + descriptor_len;
+    }
+
+    let central_size = central.len() as u64;
+    central.extend(end_of_central_directory(
+        entries.len() as u64,
+        offset,
+        central_size,
+    ));
+    send(central)
+        .await
+        .map_err(|_| closed())
+        .into_internal_error()?;
+    Ok(())
+}
+
+fn archive_stream(store: Arc<dyn BlobStore>, entries: Vec<ArchiveEntry>) -> ByteStream {
+    let (sender, receiver) = mpsc::channel(8);
+    tokio::spawn(async move {
+        if let Err(e) = write_archive(store, entries, sender.clone()).await {
+            tracing::warn!("Writing archive failed: {:?}", e);
+            // aborts the response, so the client doesn't mistake the archive for complete
+            let _ = sender
+                .send(Err(io::Error::other("archive incomplete")))
+                .await;
+        }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+    .boxed()
+}
+
+/// Makes `name` usable as an entry name that's unique in the archive: "a.jpg", "a (1).jpg", ...
+/// Names are compared case-insensitively, some file systems would merge them otherwise.
+fn unique_name(name: &str, taken: &mut HashSet<String>) -> String {
+    // path separators would create directories (or escape the extraction directory)
+    let name: String = name
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\') { '_' } else { c })
+        .collect();
+    let name = if name.is_empty() || name == "." || name == ".." {
+        "file".to_string()
+    } else {
+        name
+    };
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name.as_str(), ""),
+    };
+    let mut candidate = name.clone();
+    let mut n = 1;
+    while !taken.insert(candidate.to_lowercase()) {
+        candidate = format!("{} ({}){}", stem, n, extension);
+        n += 1;
+    }
+    candidate
+}
+
+async fn archive_response(
+    pool: &PgPool,
+    store: Arc<dyn BlobStore>,
+    space_id: &str,
+    file_ids: Option<Vec<String>>,
+) -> Result<Response, AppError> {
+    let space = sqlx::query!(
+        "SELECT name FROM spaces WHERE id = $1 AND deleted_at IS NULL",
+        space_id
+    )
+    .fetch_one(pool)
+    .await
+    .into_db_error()?;
+
+    let files = sqlx::query!(
+        r#"
+        SELECT id, original_filename, checksum, file_size_bytes, upload_date, mime_type FROM files
+        WHERE space_id = $1 AND deleted_at IS NULL AND ($2::TEXT[] IS NULL OR id = ANY($2))
+        ORDER BY upload_date, id
+        "#,
+        space_id,
+        file_ids.as_deref()
+    )
+    .fetch_all(pool)
+    .await
+    .into_db_error()?;
+
+    if let Some(file_ids) = &file_ids {
+        let found: HashSet<&str> = files.iter().map(|f| f.id.as_str()).collect();
+        if let Some(missing) = file_ids.iter().find(|id| !found.contains(id.as_str())) {
+            return Err(AppError::new(
+                ErrorType::Validation("File not found".into()),
+                anyhow!("File {} is not part of space {}", missing, space_id),
+            ));
+        }
+    }
+
+    let strip_gps = space_strips_gps(pool, space_id).await?;
+    let mut taken = HashSet::new();
+    let mut entries: Vec<ArchiveEntry> = Vec::with_capacity(files.len());
+    for f in files {
+        let size = f.file_size_bytes as u64;
+        // the redactions keep the size, so the archive length is known up front all the same
+        let redactions = if strip_gps {
+            let redactions = gps_redactions(
+                pool,
+                store.as_ref(),
+                &f.checksum,
+                f.mime_type.as_deref(),
+                size,
+            )
+            .await?;
+            // one file whose location can't be removed doesn't hold back the rest
+            let Some(redactions) = redactions else {
+                tracing::warn!(
+                    "Leaving file {} out of the archive of space {}, its location can't be removed",
+                    f.id,
+                    space_id
+                );
+                continue;
+            };
+            redactions
+        } else {
+            Redactions::default()
+        };
+        entries.push(ArchiveEntry {
+            name: unique_name(&f.original_filename, &mut taken),
+            checksum: f.checksum,
+            size,
+            modified: f.upload_date,
+            redactions,
+        });
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(archive_len(&entries)),
+    );
+    // header values have to be visible ASCII
+    let filename: String = space
+        .name
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() || c == ' ') && !matches!(c, '"' | '\\') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{}.zip\"", filename))
+            .into_internal_error()?,
+    );
+
+    let body = Body::from_stream(archive_stream(store, entries));
+    Ok((StatusCode::OK, headers, body).into_response())
+}
+
+#[debug_handler()]
+pub async fn space_archive_get(
+    State(AppState { pool, store, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(space_id): Path<String>,
+) -> Result<Response, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Viewer).await?;
+
+    archive_response(&pool, store, &space_id, None).await
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveRequest {
+    file_ids: Vec<String>,
+}
+
+#[debug_handler()]
+pub async fn space_archive_post(
+    State(AppState { pool, store, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(space_id): Path<String>,
+    Json(payload): Json<ArchiveRequest>,
+) -> Result<Response, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Viewer).await?;
+
+    if payload.file_ids.is_empty() {
+        return Err(AppError::new(
+            ErrorType::Validation("Select at least one file".into()),
+            anyhow!("Archive requested without files"),
+        ));
+    }
+
+    archive_response(&pool, store, &space_id, Some(payload.file_ids)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+    use crate::storage::LocalStore;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn entry(name: &str, checksum: &str, size: u64) -> ArchiveEntry {
+        ArchiveEntry {
+            name: name.to_string(),
+            checksum: checksum.to_string(),
+            size,
+            modified: datetime!(2024-05-17 14:30:08 UTC),
+            redactions: Arc::default(),
+        }
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    /// The body of the extra field `id` in the extra fields `extra`.
+    fn extra_field(extra: &[u8], id: u16) -> Option<&[u8]> {
+        let mut at = 0;
+        while at + 4 <= extra.len() {
+            let len = u16_at(extra, at + 2) as usize;
+            if u16_at(extra, at) == id {
+                return Some(&extra[at + 4..at + 4 + len]);
+            }
+            at += 4 + len;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn stored_archive_round_trip() {
+        let root = std::env::temp_dir().join(format!("spaces-archive-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let store = LocalStore::new(&root);
+        let contents: [(&str, &[u8]); 2] = [("a.txt", b"hello"), ("b.bin", &[0, 1, 2, 255])];
+        for (name, content) in contents {
+            let source = root.join(format!(".{}", name));
+            std::fs::write(&source, content).unwrap();
+            store.put(name, &source).await.unwrap();
+        }
+        let entries: Vec<_> = contents
+            .iter()
+            .map(|(name, content)| entry(name, name, content.len() as u64))
+            .collect();
+        let expected_len = archive_len(&entries);
+
+        let mut archive = Vec::new();
+        let mut stream = archive_stream(Arc::new(store), entries);
+        while let Some(chunk) = stream.next().await {
+            archive.extend_from_slice(&chunk.unwrap());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(archive.len() as u64, expected_len);
+
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(&archive, end), END_OF_CENTRAL_DIRECTORY);
+        assert_eq!(u16_at(&archive, end + 10), 2);
+        let mut at = u32_at(&archive, end + 16) as usize;
+        for (name, content) in contents {
+            assert_eq!(u32_at(&archive, at), CENTRAL_HEADER);
+            let crc = u32_at(&archive, at + 16);
+            let size = u32_at(&archive, at + 24) as usize;
+            let name_len = u16_at(&archive, at + 28) as usize;
+            let extra_len = u16_at(&archive, at + 30) as usize;
+            let offset = u32_at(&archive, at + 42) as usize;
+            assert_eq!(&archive[at + 46..at + 46 + name_len], name.as_bytes());
+            assert_eq!(size, content.len());
+            assert_eq!(crc, crc32fast::hash(content));
+            at += 46 + name_len + extra_len;
+
+            assert_eq!(u32_at(&archive, offset), LOCAL_HEADER);
+            let data = offset
+                + 30
+                + u16_at(&archive, offset + 26) as usize
+                + u16_at(&archive, offset + 28) as usize;
+            assert_eq!(&archive[data..data + size], content);
+            assert_eq!(u32_at(&archive, data + size), DATA_DESCRIPTOR);
+            assert_eq!(u32_at(&archive, data + size + 4), crc);
+        }
+        assert_eq!(at, end);
+    }
+
+    #[test]
+    fn zip64_headers_round_trip() {
+        let large = entry("large.mov", "large", 5 * GIB);
+        assert!(large.zip64());
+
+        let local = local_header(&large);
+        assert_eq!(u16_at(&local, 4), VERSION_ZIP64);
+        assert_eq!(u32_at(&local, 18), u32::MAX);
+        assert_eq!(u32_at(&local, 22), u32::MAX);
+        let extra = &local[30 + large.name.len()..];
+        assert_eq!(extra_field(extra, 0x0001), Some(&[0u8; 16][..]));
+
+        let descriptor = data_descriptor(&large, 7);
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(u64_at(&descriptor, 8), 5 * GIB);
+        assert_eq!(u64_at(&descriptor, 16), 5 * GIB);
+
+        // placed behind another large file, so the offset overflows as well
+        let offset = 6 * GIB;
+        let central = central_header(&large, offset, 7);
+        assert_eq!(u32_at(&central, 20), u32::MAX);
+        assert_eq!(u32_at(&central, 24), u32::MAX);
+        assert_eq!(u32_at(&central, 42), u32::MAX);
+        let extra = &central[46 + large.name.len()..];
+        let zip64 = extra_field(extra, 0x0001).unwrap();
+        assert_eq!(zip64.len(), 24);
+        assert_eq!(
+            (u64_at(zip64, 0), u64_at(zip64, 8), u64_at(zip64, 16)),
+            (5 * GIB, 5 * GIB, offset)
+        );
+
+        let directory_offset = 11 * GIB + 200;
+        let end = end_of_central_directory(3, directory_offset, 300);
+        assert_eq!(u32_at(&end, 0), ZIP64_END);
+        assert_eq!(u64_at(&end, 24), 3);
+        assert_eq!(u64_at(&end, 40), 300);
+        assert_eq!(u64_at(&end, 48), directory_offset);
+        assert_eq!(u32_at(&end, 56), ZIP64_LOCATOR);
+        // the locator points right behind the central directory
+        assert_eq!(u64_at(&end, 64), directory_offset + 300);
+        let classic = &end[76..];
+        assert_eq!(u32_at(classic, 0), END_OF_CENTRAL_DIRECTORY);
+        assert_eq!(u32_at(classic, 16), u32::MAX);
+        assert_eq!(classic.len(), 22);
+    }
+
+    #[test]
+    fn small_entries_stay_classic() {
+        let small = entry("small.jpg", "small", 1000);
+        assert!(!small.zip64());
+        assert!(extra_field(&local_header(&small)[39..], 0x0001).is_none());
+        assert_eq!(data_descriptor(&small, 0).len(), 16);
+        assert_eq!(end_of_central_directory(1, 1100, 60).len(), 22);
+
+        let entries = [
+            entry("large.mov", "large", 5 * GIB),
+            entry("small.jpg", "small", 1000),
+        ];
+        let records: u64 = entries
+            .iter()
+            .map(|e| (local_header(e).len() + data_descriptor(e, 0).len()) as u64 + e.size)
+            .sum();
+        // the small file starts past 4 GiB, so its central header needs zip64 for the offset
+        let central = central_header(&entries[0], 0, 0).len() + 46 + 9 + 9 + 4 + 8;
+        let end = end_of_central_directory(2, records, central as u64).len() as u64;
+        assert_eq!(archive_len(&entries), records + central as u64 + end);
+    }
+
+    #[test]
+    fn dos_timestamps() {
+        assert_eq!(
+            dos_timestamp(datetime!(2024-05-17 14:30:08 UTC)),
+            ((14 << 11) | (30 << 5) | 4, (44 << 9) | (5 << 5) | 17)
+        );
+        assert_eq!(dos_timestamp(datetime!(1970-01-01 0:00 UTC)), (0, 33));
+    }
+
+    #[test]
+    fn unique_names() {
+        let mut taken = HashSet::new();
+        assert_eq!(unique_name("a.jpg", &mut taken), "a.jpg");
+        assert_eq!(unique_name("A.JPG", &mut taken), "A (1).JPG");
+        assert_eq!(unique_name("../x/y", &mut taken), ".._x_y");
+        assert_eq!(unique_name("..", &mut taken), "file");
+    }
+}
@@ -0,0 +1,372 @@
+use anyhow::anyhow;
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{
+        SaltString,
+        rand_core::{OsRng, RngCore},
+    },
+};
+use axum::{
+    Json, debug_handler,
+    extract::{FromRequestParts, State},
+    http::{HeaderMap, HeaderValue, header, request::Parts},
+    response::IntoResponse,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    AppState,
+    errors::{AppError, ErrorType, IntoAppError},
+};
+
+const SESSION_COOKIE: &str = "spaces_session";
+const SESSION_TTL: Duration = Duration::days(30);
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// `false` disables `POST /api/auth/register`.
+    pub registration_enabled: bool,
+    /// `false` drops the `Secure` flag from session cookies, e.g. when serving plain http.
+    pub secure_cookies: bool,
+    /// Users allowed to run instance maintenance.
+    pub admin_usernames: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            registration_enabled: true,
+            secure_cookies: true,
+            admin_usernames: Vec::new(),
+        }
+    }
+}
+
+/// The user behind the session cookie of the current request.
+/// Handlers that take this extractor reject unauthenticated requests with 401.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthUser {
+    pub id: String,
+    pub username: String,
+}
+
+fn unauthenticated(reason: &str) -> AppError {
+    AppError::new(
+        ErrorType::Authentication("Not logged in".into()),
+        anyhow!("{}", reason),
+    )
+}
+
+/// Tokens are only stored as their SHA256, they are random enough to not need a slow hash.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub(crate) fn session_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|cookie| {
+            let (name, value) = cookie.trim().split_once('=')?;
+            (name == SESSION_COOKIE).then(|| value.to_string())
+        })
+}
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = session_token(&parts.headers)
+            .ok_or_else(|| unauthenticated("No session cookie sent"))?;
+
+        session_user(&state.pool, &token).await
+    }
+}
+
+/// The user logged in with the session `token`, unauthenticated once it expired or was logged out.
+pub(crate) async fn session_user(pool: &PgPool, token: &str) -> Result<AuthUser, AppError> {
+    sqlx::query_as!(
+        AuthUser,
+        r#"
+        SELECT users.id, users.username FROM sessions
+        JOIN users ON users.id = sessions.user_id
+        WHERE sessions.token_hash = $1 AND sessions.expires_at > NOW()
+        "#,
+        hash_token(token)
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| unauthenticated("Session unknown or expired"))
+}
+
+/// Rejects users that aren't instance admins with 403.
+pub fn require_admin(config: &AuthConfig, user: &AuthUser) -> Result<(), AppError> {
+    if config.admin_usernames.contains(&user.username) {
+        Ok(())
+    } else {
+        Err(AppError::new(
+            ErrorType::Authorization("Only admins can do this".into()),
+            anyhow!("User {} is not an admin", user.id),
+        ))
+    }
+}
+
+pub async fn hash_password(password: String) -> Result<String, AppError> {
+    // argon2 is deliberately slow, keep it off the async workers
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow!("Hashing password failed: {}", e))
+    })
+    .await
+    .into_internal_error()?
+    .into_internal_error()
+}
+
+pub async fn verify_password(password: String, password_hash: String) -> Result<bool, AppError> {
+    tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&password_hash)
+            .map_err(|e| anyhow!("Stored password hash is invalid: {}", e))?;
+        Ok::<_, anyhow::Error>(
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+        )
+    })
+    .await
+    .into_internal_error()?
+    .into_internal_error()
+}
+
+pub fn validate_credentials(username: &str, password: &str) -> Result<(), AppError> {
+    let valid_username = (3..=32).contains(&username.chars().count())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid_username {
+        return Err(AppError::new(
+            ErrorType::Validation(
+                "Username must be 3-32 characters of letters, digits, '_', '-' or '.'".into(),
+            ),
+            anyhow!("Invalid username"),
+        ));
+    }
+    if password.chars().count() < 8 {
+        return Err(AppError::new(
+            ErrorType::Validation("Password must be at least 8 characters".into()),
+            anyhow!("Password too short"),
+        ));
+    }
+    Ok(())
+}
+
+/// Creates a session for `user_id` and returns the `Set-Cookie` header carrying it.
+async fn start_session(
+    pool: &PgPool,
+    config: &AuthConfig,
+    user_id: &str,
+) -> Result<HeaderMap, AppError> {
+    let token = generate_token();
+
+    // opportunistic cleanup, sessions are only ever looked up while unexpired
+    sqlx::query!("DELETE FROM sessions WHERE expires_at < NOW()")
+        .execute(pool)
+        .await
+        .into_db_error()?;
+
+    sqlx::query!(
+        "INSERT INTO sessions (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+        hash_token(&token),
+        user_id,
+        OffsetDateTime::now_utc() + SESSION_TTL
+    )
+    .execute(pool)
+    .await
+    .into_db_error()?;
+
+    session_cookie(config, &token, SESSION_TTL.whole_seconds())
+}
+
+fn session_cookie(config: &AuthConfig, value: &str, max_age: i64) -> Result<HeaderMap, AppError> {
+    let secure = if config.secure_cookies {
+        "; Secure"
+    } else {
+        ""
+    };
+    let cookie = format!(
+        "{}={}; HttpOnly; SameSite=Lax; Path=/; Max-Age={}{}",
+        SESSION_COOKIE, value, max_age, secure
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&cookie).into_internal_error()?,
+    );
+    Ok(headers)
+}
+
+/// Adds an account, a conflict if the username is taken.
+pub(crate) async fn create_user(
+    pool: &PgPool,
+    username: &str,
+    password: String,
+) -> Result<AuthUser, AppError> {
+    validate_credentials(username, &password)?;
+
+    let password_hash = hash_password(password).await?;
+    let id = uuid::Uuid::new_v4().to_string();
+
+    sqlx::query_as!(
+        AuthUser,
+        r#"
+        INSERT INTO users (id, username, password_hash) VALUES ($1, $2, $3)
+        ON CONFLICT (username) DO NOTHING
+        RETURNING id, username
+        "#,
+        id,
+        username,
+        password_hash
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::Conflict("Username is already taken".into()),
+            anyhow!("Username {} already registered", username),
+        )
+    })
+}
+
+/// Replaces the password of `username` and ends all of their sessions.
+pub(crate) async fn reset_password(
+    pool: &PgPool,
+    username: &str,
+    password: String,
+) -> Result<(), AppError> {
+    validate_credentials(username, &password)?;
+    let password_hash = hash_password(password).await?;
+
+    let mut tx = pool.begin().await.into_db_error()?;
+    let user_id = sqlx::query_scalar!(
+        "UPDATE users SET password_hash = $2 WHERE username = $1 RETURNING id",
+        username,
+        password_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("User not found".into()),
+            anyhow!("No user named {}", username),
+        )
+    })?;
+    sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await
+        .into_db_error()?;
+    tx.commit().await.into_db_error()?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct CredentialsRequest {
+    username: String,
+    password: String,
+}
+
+#[debug_handler()]
+pub async fn auth_register(
+    State(AppState { pool, auth, .. }): State<AppState>,
+    Json(payload): Json<CredentialsRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if !auth.registration_enabled {
+        return Err(AppError::new(
+            ErrorType::Authorization("Registration is disabled on this instance".into()),
+            anyhow!("Registration attempted while disabled"),
+        ));
+    }
+    let user = create_user(&pool, &payload.username, payload.password).await?;
+    let headers = start_session(&pool, &auth, &user.id).await?;
+
+    Ok((headers, Json::from(user)))
+}
+
+#[debug_handler()]
+pub async fn auth_login(
+    State(AppState { pool, auth, .. }): State<AppState>,
+    Json(payload): Json<CredentialsRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let invalid = || {
+        AppError::new(
+            ErrorType::Authentication("Invalid username or password".into()),
+            anyhow!("Login failed for {}", payload.username),
+        )
+    };
+
+    let rec = sqlx::query!(
+        "SELECT id, username, password_hash FROM users WHERE username = $1",
+        payload.username
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(invalid)?;
+
+    if !verify_password(payload.password, rec.password_hash).await? {
+        return Err(invalid());
+    }
+
+    let headers = start_session(&pool, &auth, &rec.id).await?;
+    let user = AuthUser {
+        id: rec.id,
+        username: rec.username,
+    };
+
+    Ok((headers, Json::from(user)))
+}
+
+#[debug_handler()]
+pub async fn auth_logout(
+    State(AppState { pool, auth, .. }): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    if let Some(token) = session_token(&headers) {
+        sqlx::query!(
+            "DELETE FROM sessions WHERE token_hash = $1",
+            hash_token(&token)
+        )
+        .execute(&pool)
+        .await
+        .into_db_error()?;
+    }
+
+    session_cookie(&auth, "", 0)
+}
+
+#[debug_handler(state = AppState)]
+pub async fn auth_me(user: AuthUser) -> Json<AuthUser> {
+    Json::from(user)
+}
@@ -0,0 +1,118 @@
+use std::path::Path;
+
+use sqlx::{PgPool, PgTransaction};
+
+use crate::{
+    errors::{AppError, IntoAppError},
+    storage::BlobStore,
+    thumbnails::{THUMBNAIL_SIZES, ThumbnailFormat, thumbnail_key},
+};
+
+/// Takes a reference on the blob `checksum` inside `tx` and makes sure it is in the blob store:
+/// the fully written temp file is handed to the store, or dropped if the blob already exists.
+/// The blob row stays locked until `tx` ends, so a concurrent purge can't remove the blob meanwhile.
+pub(crate) async fn acquire_blob(
+    tx: &mut PgTransaction<'_>,
+    store: &dyn BlobStore,
+    temp_path: &Path,
+    checksum: &str,
+    size_bytes: i64,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO blobs (checksum, size_bytes, refcount) VALUES ($1, $2, 1)
+        ON CONFLICT (checksum) DO UPDATE SET refcount = blobs.refcount + 1
+        "#,
+        checksum,
+        size_bytes
+    )
+    .execute(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    if store.exists(checksum).await? {
+        tokio::fs::remove_file(temp_path)
+            .await
+            .into_internal_error()?;
+    } else {
+        store.put(checksum, temp_path).await?;
+    }
+
+    Ok(())
+}
+
+/// Takes another reference on the blob `checksum`, which is already stored, inside `tx`.
+pub(crate) async fn reference_blob(
+    tx: &mut PgTransaction<'_>,
+    checksum: &str,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        "UPDATE blobs SET refcount = refcount + 1 WHERE checksum = $1",
+        checksum
+    )
+    .execute(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    Ok(())
+}
+
+/// Drops one reference per entry of `checksums` inside `tx`, a checksum listed twice drops two.
+/// Returns the blobs left at zero, to be passed to [`purge_blob`] once the transaction has committed.
+pub(crate) async fn release_blobs(
+    tx: &mut PgTransaction<'_>,
+    checksums: &[String],
+) -> Result<Vec<String>, AppError> {
+    let released = sqlx::query!(
+        r#"
+        UPDATE blobs SET refcount = GREATEST(0, blobs.refcount - f.refs)
+        FROM (SELECT checksum, COUNT(*) AS refs FROM UNNEST($1::TEXT[]) AS checksum GROUP BY checksum) f
+        WHERE blobs.checksum = f.checksum
+        RETURNING blobs.checksum, blobs.refcount
+        "#,
+        checksums
+    )
+    .fetch_all(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    Ok(released
+        .into_iter()
+        .filter(|blob| blob.refcount == 0)
+        .map(|blob| blob.checksum)
+        .collect())
+}
+
+/// Removes the blob `checksum` from the database and the blob store if nothing references it anymore.
+/// Returns whether the blob was removed.
+pub(crate) async fn purge_blob(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    checksum: &str,
+) -> Result<bool, AppError> {
+    let mut tx = pool.begin().await.into_db_error()?;
+
+    // deleting the row locks it, so an upload of the same content waits until the blob is gone
+    let removed = sqlx::query!(
+        "DELETE FROM blobs WHERE checksum = $1 AND refcount = 0 RETURNING checksum",
+        checksum
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .into_db_error()?;
+
+    if removed.is_none() {
+        return Ok(false);
+    }
+
+    store.delete(checksum).await?;
+    for size in THUMBNAIL_SIZES {
+        for format in ThumbnailFormat::ALL {
+            store.delete(&thumbnail_key(checksum, size, format)).await?;
+        }
+    }
+
+    tx.commit().await.into_db_error()?;
+
+    Ok(true)
+}
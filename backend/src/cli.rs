@@ -0,0 +1,354 @@
+use std::io::BufRead;
+
+use anyhow::anyhow;
+use clap::{Args, Parser, Subcommand};
+use sqlx::PgPool;
+
+use crate::{
+    auth::{create_user, generate_token, reset_password},
+    config::ConfigArgs,
+    errors::{AppError, ErrorType, IntoAppError},
+    maintenance::{Check, Maintenance, Report},
+    members::SpaceRole,
+    spaces::{CreateSpaceRequest, create_space, hash_legacy_access_codes},
+    trash::SpacePurger,
+};
+
+/// File sharing spaces. Settings are read from `spaces.toml`, the environment (and `.env`) and flags, see the README.
+#[derive(Parser)]
+#[command(name = "spaces", version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+    /// What to do, `serve` when left out.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Runs the HTTP server, after applying pending migrations.
+    Serve,
+    /// Applies pending database migrations.
+    Migrate {
+        /// Only lists the migrations that would be applied.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Corrects refcounts, removes unreferenced blobs and lists orphaned objects.
+    Gc {
+        /// Deletes orphaned objects too (`maintenance.remove_orphans` otherwise).
+        #[arg(long)]
+        remove_orphans: bool,
+    },
+    /// Re-hashes every blob and lists the corrupt ones, exits with 1 if there are any.
+    Verify {
+        /// Moves corrupt blobs to quarantine/ (`maintenance.quarantine` otherwise).
+        #[arg(long)]
+        quarantine: bool,
+    },
+    /// Manages spaces.
+    #[command(subcommand)]
+    Space(SpaceCommand),
+    /// Manages accounts.
+    #[command(subcommand)]
+    User(UserCommand),
+}
+
+#[derive(Subcommand)]
+pub enum SpaceCommand {
+    /// Adds a space.
+    Create {
+        name: String,
+        /// Username of the owner.
+        #[arg(long)]
+        owner: String,
+        #[arg(long)]
+        description: Option<String>,
+        /// Lists the space publicly.
+        #[arg(long)]
+        public: bool,
+        /// Storage quota, `quotas.space_quota_bytes` otherwise.
+        #[arg(long)]
+        quota_bytes: Option<i64>,
+        /// Largest file accepted, `quotas.max_file_size_bytes` otherwise.
+        #[arg(long)]
+        max_file_size_bytes: Option<i64>,
+    },
+    /// Lists every space, trashed ones included.
+    List,
+    /// Makes a user an owner of a space, e.g. of one created before there were accounts.
+    SetOwner { space_id: String, username: String },
+    /// Moves a space to the trash.
+    Delete {
+        space_id: String,
+        /// Deletes the space and its files for good right away instead.
+        #[arg(long)]
+        purge: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Adds an account and prints its password.
+    Create {
+        username: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+    /// Sets a new password, prints it and logs the user out everywhere.
+    ResetPassword {
+        username: String,
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct PasswordArgs {
+    /// Reads the password from the first line of stdin instead of generating one.
+    #[arg(long)]
+    password_stdin: bool,
+}
+
+impl PasswordArgs {
+    /// The password to set, and whether it has to be printed because it was generated.
+    fn password(&self) -> Result<(String, bool), AppError> {
+        if !self.password_stdin {
+            return Ok((generate_token(), true));
+        }
+        let mut password = String::new();
+        std::io::stdin()
+            .lock()
+            .read_line(&mut password)
+            .into_internal_error()?;
+        Ok((password.trim_end_matches(['\r', '\n']).to_string(), false))
+    }
+}
+
+/// Applies the pending migrations, or only lists them with `dry_run`.
+pub async fn migrate(pool: &PgPool, dry_run: bool) -> Result<(), AppError> {
+    let migrator = sqlx::migrate!("./migrations");
+
+    // the table doesn't exist before the first migration
+    let applied: Vec<i64> =
+        sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(pool)
+            .await
+            .or_else(|e| match e {
+                sqlx::Error::Database(e) if e.code().as_deref() == Some("42P01") => Ok(Vec::new()),
+                e => Err(e),
+            })
+            .into_db_error()?;
+
+    let pending: Vec<_> = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect();
+
+    if pending.is_empty() {
+        println!("The database is up to date");
+        return Ok(());
+    }
+    for migration in &pending {
+        println!("{} {}", migration.version, migration.description);
+    }
+    if dry_run {
+        println!("{} migrations would be applied", pending.len());
+        return Ok(());
+    }
+
+    migrator.run(pool).await.into_db_error()?;
+    hash_legacy_access_codes(pool).await?;
+    println!("Applied {} migrations", pending.len());
+    Ok(())
+}
+
+/// Runs a maintenance check in the foreground and prints its report.
+pub async fn maintenance(
+    maintenance: Maintenance,
+    check: Check,
+    repair: bool,
+) -> Result<Report, AppError> {
+    let report = maintenance.run(check, repair).await?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).into_internal_error()?
+    );
+    if let Some(error) = report.error() {
+        return Err(AppError::new(
+            ErrorType::Internal(format!("{:?} stopped early", check)),
+            anyhow!("{:?} stopped early: {}", check, error),
+        ));
+    }
+    Ok(report)
+}
+
+pub async fn space(
+    pool: &PgPool,
+    purger: SpacePurger,
+    command: SpaceCommand,
+) -> Result<(), AppError> {
+    match command {
+        SpaceCommand::Create {
+            name,
+            owner,
+            description,
+            public,
+            quota_bytes,
+            max_file_size_bytes,
+        } => {
+            let owner_id = user_id(pool, &owner).await?;
+            let space = create_space(
+                pool,
+                &owner_id,
+                CreateSpaceRequest {
+                    name,
+                    description,
+                    is_public: Some(public),
+                    access_code: None,
+                    strip_gps: None,
+                    conflict_policy: None,
+                    quota_bytes,
+                    max_file_size_bytes,
+                },
+            )
+            .await?;
+            println!("{}", space.id);
+        }
+        SpaceCommand::List => {
+            let spaces = sqlx::query!(
+                r#"
+                SELECT spaces.id, spaces.name, spaces.total_size_used_bytes, spaces.deleted_at,
+                    EXISTS (SELECT 1 FROM space_purges WHERE space_id = spaces.id) AS "purging!",
+                    (SELECT COUNT(*) FROM files WHERE space_id = spaces.id AND deleted_at IS NULL) AS "files!",
+                    (
+                        SELECT string_agg(users.username, ',' ORDER BY users.username)
+                        FROM space_members JOIN users ON users.id = space_members.user_id
+                        WHERE space_members.space_id = spaces.id AND space_members.role = 'owner'
+                    ) AS owners
+                FROM spaces
+                ORDER BY spaces.created_at, spaces.id
+                "#
+            )
+            .fetch_all(pool)
+            .await
+            .into_db_error()?;
+
+            println!(
+                "{:<36}  {:<8}  {:>7}  {:>14}  {:<16}  NAME",
+                "ID", "STATE", "FILES", "BYTES", "OWNERS"
+            );
+            for space in spaces {
+                let state = match (space.purging, space.deleted_at) {
+                    (true, _) => "deleting",
+                    (false, Some(_)) => "trashed",
+                    (false, None) => "active",
+                };
+                println!(
+                    "{:<36}  {:<8}  {:>7}  {:>14}  {:<16}  {}",
+                    space.id,
+                    state,
+                    space.files,
+                    space.total_size_used_bytes,
+                    space.owners.unwrap_or_default(),
+                    space.name
+                );
+            }
+        }
+        SpaceCommand::SetOwner { space_id, username } => {
+            let user_id = user_id(pool, &username).await?;
+            let added = sqlx::query!(
+                r#"
+                INSERT INTO space_members (space_id, user_id, role)
+                SELECT id, $2, $3 FROM spaces WHERE id = $1
+                ON CONFLICT (space_id, user_id) DO UPDATE SET role = EXCLUDED.role
+                "#,
+                space_id,
+                user_id,
+                SpaceRole::Owner as SpaceRole
+            )
+            .execute(pool)
+            .await
+            .into_db_error()?;
+            if added.rows_affected() == 0 {
+                return Err(AppError::new(
+                    ErrorType::NotFound("Space not found".into()),
+                    anyhow!("No space {}", space_id),
+                ));
+            }
+            println!("{} now owns space {}", username, space_id);
+        }
+        SpaceCommand::Delete { space_id, purge } => {
+            let mut tx = pool.begin().await.into_db_error()?;
+            let trashed = sqlx::query!(
+                "UPDATE spaces SET deleted_at = COALESCE(deleted_at, NOW()) WHERE id = $1 RETURNING id",
+                space_id
+            )
+            .fetch_optional(&mut *tx)
+            .await
+            .into_db_error()?;
+            if trashed.is_none() {
+                return Err(AppError::new(
+                    ErrorType::NotFound("Space not found".into()),
+                    anyhow!("No space {}", space_id),
+                ));
+            }
+            if purge {
+                sqlx::query!(
+                    "INSERT INTO space_purges (space_id) VALUES ($1) ON CONFLICT (space_id) DO NOTHING",
+                    space_id
+                )
+                .execute(&mut *tx)
+                .await
+                .into_db_error()?;
+            }
+            tx.commit().await.into_db_error()?;
+
+            if purge {
+                purger.run_queued().await?;
+                println!("Deleted space {} for good", space_id);
+            } else {
+                println!("Moved space {} to the trash", space_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn user(pool: &PgPool, command: UserCommand) -> Result<(), AppError> {
+    let (username, password) = match &command {
+        UserCommand::Create { username, password }
+        | UserCommand::ResetPassword { username, password } => (username, password),
+    };
+    let (password, generated) = password.password()?;
+
+    match command {
+        UserCommand::Create { .. } => {
+            let user = create_user(pool, username, password.clone()).await?;
+            println!("Created user {} ({})", user.username, user.id);
+        }
+        UserCommand::ResetPassword { .. } => {
+            reset_password(pool, username, password.clone()).await?;
+            println!("Reset the password of {}", username);
+        }
+    }
+    if generated {
+        println!("Password: {}", password);
+    }
+    Ok(())
+}
+
+async fn user_id(pool: &PgPool, username: &str) -> Result<String, AppError> {
+    sqlx::query_scalar!("SELECT id FROM users WHERE username = $1", username)
+        .fetch_optional(pool)
+        .await
+        .into_db_error()?
+        .ok_or_else(|| {
+            AppError::new(
+                ErrorType::NotFound("User not found".into()),
+                anyhow!("No user named {}", username),
+            )
+        })
+}
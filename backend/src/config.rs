@@ -0,0 +1,398 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use axum::http::HeaderValue;
+use clap::Args;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    auth::AuthConfig,
+    errors::{AppError, ErrorType, LogConfig},
+    maintenance::{MAX_INTERVAL_HOURS, MaintenanceConfig},
+    quotas::QuotaConfig,
+    storage::{StorageBackend, StorageConfig},
+    trash::{MAX_RETENTION_DAYS, TrashConfig},
+};
+
+// read from the working directory when no file is given
+const DEFAULT_CONFIG_FILE: &str = "spaces.toml";
+
+/// Every setting of an instance. They are read from a TOML file (see `spaces.example.toml`),
+/// then from environment variables and last from command-line flags, each overriding the one before.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub uploads: UploadsConfig,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+    pub auth: AuthConfig,
+    pub trash: TrashConfig,
+    pub maintenance: MaintenanceConfig,
+    pub quotas: QuotaConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Origins the web frontend is served from, for CORS.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 6570,
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    pub fn origins(&self) -> Result<Vec<HeaderValue>, String> {
+        self.allowed_origins
+            .iter()
+            .map(|origin| {
+                origin.parse::<HeaderValue>().map_err(|_| {
+                    format!("server.allowed_origins: {:?} is not a valid origin", origin)
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            max_connections: 10,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsConfig {
+    /// Staging area for uploads in progress, and the blob directory of the local store.
+    pub path: String,
+    /// Largest request body accepted by the multipart upload and largest tus upload.
+    pub max_bytes: u64,
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            max_bytes: 1024 * 2 * 10_u64.pow(8),
+        }
+    }
+}
+
+/// Flags overriding the configuration, accepted by every command.
+#[derive(Args)]
+pub struct ConfigArgs {
+    /// TOML file to read the configuration from, `spaces.toml` if it exists.
+    #[arg(long, global = true, env = "SPACES_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on.
+    #[arg(long, global = true)]
+    bind: Option<IpAddr>,
+    #[arg(long, global = true)]
+    port: Option<u16>,
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+    #[arg(long, global = true)]
+    upload_path: Option<String>,
+    #[arg(long, global = true)]
+    log_dir: Option<String>,
+}
+
+impl Config {
+    /// Loads and validates the configuration, listing every problem found in one error.
+    /// The server settings are only checked when `serving`.
+    pub fn load(args: &ConfigArgs, serving: bool) -> Result<Self, AppError> {
+        let mut problems = Vec::new();
+
+        let mut config = match &args.config {
+            Some(path) => Self::read(path, &mut problems),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::read(Path::new(DEFAULT_CONFIG_FILE), &mut problems)
+            }
+            None => Self::default(),
+        };
+        config.apply_env(&mut problems);
+        config.apply_args(args);
+        config.validate(serving, &mut problems);
+
+        if problems.is_empty() {
+            return Ok(config);
+        }
+        Err(AppError::new(
+            ErrorType::Configuration(problems.join("; ")),
+            anyhow!("{} configuration problems", problems.len()),
+        ))
+    }
+
+    fn read(path: &Path, problems: &mut Vec<String>) -> Self {
+        let parsed = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| toml::from_str(&content).map_err(|e| e.message().to_string()));
+        parsed.unwrap_or_else(|e| {
+            problems.push(format!("{}: {}", path.display(), e));
+            Self::default()
+        })
+    }
+
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        env_parse("BIND_ADDRESS", &mut self.server.bind, problems);
+        env_parse("PORT", &mut self.server.port, problems);
+        env_list("ALLOWED_ORIGINS", &mut self.server.allowed_origins);
+
+        env_parse("DATABASE_URL", &mut self.database.url, problems);
+        env_parse(
+            "DATABASE_MAX_CONNECTIONS",
+            &mut self.database.max_connections,
+            problems,
+        );
+
+        env_parse("UPLOAD_PATH", &mut self.uploads.path, problems);
+        env_parse("UPLOAD_MAX_BYTES", &mut self.uploads.max_bytes, problems);
+
+        env_parse("STORAGE_BACKEND", &mut self.storage.backend, problems);
+        let s3 = &mut self.storage.s3;
+        env_parse("S3_ENDPOINT", &mut s3.endpoint, problems);
+        env_parse("S3_BUCKET", &mut s3.bucket, problems);
+        env_parse("S3_REGION", &mut s3.region, problems);
+        env_parse("S3_ACCESS_KEY_ID", &mut s3.access_key_id, problems);
+        env_parse("S3_SECRET_ACCESS_KEY", &mut s3.secret_access_key, problems);
+        env_parse("S3_PATH_STYLE", &mut s3.path_style, problems);
+        env_parse("S3_PREFIX", &mut s3.prefix, problems);
+
+        env_parse("LOG_DIR", &mut self.log.dir, problems);
+        if let Ok(level) = std::env::var("RUST_LOG") {
+            self.log.level = Some(level);
+        }
+
+        env_parse(
+            "REGISTRATION_ENABLED",
+            &mut self.auth.registration_enabled,
+            problems,
+        );
+        env_parse(
+            "SESSION_COOKIE_SECURE",
+            &mut self.auth.secure_cookies,
+            problems,
+        );
+        env_list("ADMIN_USERNAMES", &mut self.auth.admin_usernames);
+
+        env_parse(
+            "TRASH_RETENTION_DAYS",
+            &mut self.trash.retention_days,
+            problems,
+        );
+
+        env_parse(
+            "GC_INTERVAL_HOURS",
+            &mut self.maintenance.gc_interval_hours,
+            problems,
+        );
+        env_parse(
+            "SCRUB_INTERVAL_HOURS",
+            &mut self.maintenance.scrub_interval_hours,
+            problems,
+        );
+        env_parse(
+            "SCRUB_QUARANTINE",
+            &mut self.maintenance.quarantine,
+            problems,
+        );
+        env_parse(
+            "GC_REMOVE_ORPHANS",
+            &mut self.maintenance.remove_orphans,
+            problems,
+        );
+
+        env_parse_opt(
+            "DEFAULT_SPACE_QUOTA_BYTES",
+            &mut self.quotas.space_quota_bytes,
+            problems,
+        );
+        env_parse_opt(
+            "DEFAULT_MAX_FILE_SIZE_BYTES",
+            &mut self.quotas.max_file_size_bytes,
+            problems,
+        );
+    }
+
+    fn apply_args(&mut self, args: &ConfigArgs) {
+        if let Some(bind) = args.bind {
+            self.server.bind = bind;
+        }
+        if let Some(port) = args.port {
+            self.server.port = port;
+        }
+        if let Some(url) = &args.database_url {
+            self.database.url = url.clone();
+        }
+        if let Some(path) = &args.upload_path {
+            self.uploads.path = path.clone();
+        }
+        if let Some(dir) = &args.log_dir {
+            self.log.dir = dir.clone();
+        }
+    }
+
+    fn validate(&self, serving: bool, problems: &mut Vec<String>) {
+        if self.database.url.is_empty() {
+            problems.push("database.url (DATABASE_URL) must be set".into());
+        }
+        if self.database.max_connections == 0 {
+            problems.push("database.max_connections must be at least 1".into());
+        }
+
+        if self.uploads.path.is_empty() {
+            problems.push("uploads.path (UPLOAD_PATH) must be set".into());
+        } else if !Path::new(&self.uploads.path).is_dir() {
+            problems.push(format!(
+                "uploads.path: {} is not an existing directory",
+                self.uploads.path
+            ));
+        }
+        if self.uploads.max_bytes == 0 {
+            problems.push("uploads.max_bytes must be at least 1".into());
+        }
+
+        if self.storage.backend == StorageBackend::S3 {
+            let s3 = &self.storage.s3;
+            for (name, value) in [
+                ("endpoint", &s3.endpoint),
+                ("bucket", &s3.bucket),
+                ("access_key_id", &s3.access_key_id),
+                ("secret_access_key", &s3.secret_access_key),
+            ] {
+                if value.is_empty() {
+                    problems.push(format!(
+                        "storage.s3.{} (S3_{}) must be set for the s3 backend",
+                        name,
+                        name.to_uppercase()
+                    ));
+                }
+            }
+            if !s3.endpoint.is_empty() && reqwest::Url::parse(&s3.endpoint).is_err() {
+                problems.push(format!(
+                    "storage.s3.endpoint: {:?} is not a valid URL",
+                    s3.endpoint
+                ));
+            }
+        }
+
+        if self.trash.retention_days > MAX_RETENTION_DAYS {
+            problems.push(format!(
+                "trash.retention_days (TRASH_RETENTION_DAYS) must be at most {}",
+                MAX_RETENTION_DAYS
+            ));
+        }
+        for (name, env, hours) in [
+            (
+                "gc_interval_hours",
+                "GC_INTERVAL_HOURS",
+                self.maintenance.gc_interval_hours,
+            ),
+            (
+                "scrub_interval_hours",
+                "SCRUB_INTERVAL_HOURS",
+                self.maintenance.scrub_interval_hours,
+            ),
+        ] {
+            if hours > MAX_INTERVAL_HOURS {
+                problems.push(format!(
+                    "maintenance.{} ({}) must be at most {}",
+                    name, env, MAX_INTERVAL_HOURS
+                ));
+            }
+        }
+
+        if let Some(level) = &self.log.level
+            && let Err(e) = EnvFilter::try_new(level)
+        {
+            problems.push(format!("log.level (RUST_LOG): {}", e));
+        }
+
+        if serving {
+            if self.server.allowed_origins.is_empty() {
+                problems.push(
+                    "server.allowed_origins (ALLOWED_ORIGINS) must list at least one origin".into(),
+                );
+            }
+            if let Err(e) = self.server.origins() {
+                problems.push(e);
+            }
+        }
+    }
+}
+
+/// Overrides `target` with the variable `name` if it is set.
+fn env_parse<T>(name: &str, target: &mut T, problems: &mut Vec<String>)
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Ok(value) = std::env::var(name) else {
+        return;
+    };
+    match value.trim().parse() {
+        Ok(value) => *target = value,
+        Err(e) => problems.push(format!("{}: {}", name, e)),
+    }
+}
+
+/// Like [`env_parse`] for optional settings, an empty variable unsets them.
+fn env_parse_opt<T>(name: &str, target: &mut Option<T>, problems: &mut Vec<String>)
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Ok(value) = std::env::var(name) else {
+        return;
+    };
+    if value.trim().is_empty() {
+        *target = None;
+        return;
+    }
+    match value.trim().parse() {
+        Ok(value) => *target = Some(value),
+        Err(e) => problems.push(format!("{}: {}", name, e)),
+    }
+}
+
+/// Overrides `target` with the comma separated variable `name` if it is set.
+fn env_list(name: &str, target: &mut Vec<String>) {
+    if let Ok(value) = std::env::var(name) {
+        *target = value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+    }
+}
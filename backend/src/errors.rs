@@ -5,7 +5,7 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tracing::{error, warn};
 use uuid::Uuid;
 
@@ -14,7 +14,6 @@ pub enum ErrorType {
     #[error("Database error: {0}")]
     Database(String),
 
-    //auth not implemented yet
     #[error("Authentication failed: {0}")]
     Authentication(String),
     #[error("Authorization failed: {0}")]
@@ -26,6 +25,18 @@ pub enum ErrorType {
     #[error("Not found: {0}")]
     NotFound(String),
 
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Unprocessable: {0}")]
+    Unprocessable(String),
+
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+
     #[error("Configuration error: {0}")]
     Configuration(String),
 
@@ -42,6 +53,10 @@ impl ErrorType {
             ErrorType::Authorization(_) => StatusCode::FORBIDDEN,
             ErrorType::Validation(_) => StatusCode::BAD_REQUEST,
             ErrorType::NotFound(_) => StatusCode::NOT_FOUND,
+            ErrorType::Conflict(_) => StatusCode::CONFLICT,
+            ErrorType::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorType::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorType::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
             ErrorType::Configuration(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ErrorType::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -172,8 +187,10 @@ impl From<anyhow::Error> for AppError {
 }
 pub trait IntoAppError<T> {
     fn into_db_error(self) -> Result<T, AppError>;
+    #[allow(dead_code)]
     fn into_auth_error(self) -> Result<T, AppError>;
     fn into_validation_error(self) -> Result<T, AppError>;
+    #[allow(dead_code)]
     fn into_not_found_error(self) -> Result<T, AppError>;
     fn into_internal_error(self) -> Result<T, AppError>;
 }
@@ -218,21 +235,35 @@ where
     }
 }
 
-pub fn init_logging() {
-    use tracing_appender::rolling::{RollingFileAppender, Rotation};
-    use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub dir: String,
+    /// A `tracing` filter like `info` or `spaces=debug,sqlx=warn`, `debug` in debug builds by default.
+    pub level: Option<String>,
+}
 
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-        if cfg!(debug_assertions) {
-            EnvFilter::new("debug")
-        } else {
-            EnvFilter::new("info")
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            dir: "./logs".to_string(),
+            level: None,
         }
-    });
+    }
+}
+
+pub fn init_logging(config: &LogConfig) {
+    use tracing_appender::rolling::{RollingFileAppender, Rotation};
+    use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
-    let log_dir = std::env::var("LOG_DIR").unwrap_or_else(|_| "./logs".to_string());
+    // the level has been validated with the rest of the configuration
+    let env_filter = match &config.level {
+        Some(level) => EnvFilter::new(level),
+        None if cfg!(debug_assertions) => EnvFilter::new("debug"),
+        None => EnvFilter::new("info"),
+    };
 
-    let file_appender = RollingFileAppender::new(Rotation::DAILY, &log_dir, "spaces.log");
+    let file_appender = RollingFileAppender::new(Rotation::DAILY, &config.dir, "spaces.log");
 
     tracing_subscriber::registry()
         .with(env_filter)
@@ -241,6 +272,11 @@ pub fn init_logging() {
                 .json()
                 .with_writer(file_appender),
         )
-        .with(tracing_subscriber::fmt::layer().json())
+        // stdout is left to the output of the commands
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(std::io::stderr),
+        )
         .init();
 }
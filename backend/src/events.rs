@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use axum::{
+    debug_handler,
+    extract::{
+        Path, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message, Utf8Bytes, WebSocket, close_code},
+    },
+    http::HeaderMap,
+    response::Response,
+};
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    AppState,
+    auth::{AuthUser, session_token, session_user},
+    errors::AppError,
+    files::SpaceFile,
+    folders::Folder,
+    members::{SpaceRole, SpaceToken, require_access},
+    spaces::SpaceDetails,
+};
+
+// events a subscriber falls behind by before it starts missing some
+const EVENT_BUFFER: usize = 256;
+// sessions and space tokens expire, spaces stop being public, subscribers notice within this
+const ACCESS_RECHECK: Duration = Duration::from_secs(60);
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SpaceEvent {
+    FileAdded { file: SpaceFile },
+    FileUpdated { file: SpaceFile },
+    FileDeleted { file_id: String },
+    FolderCreated { folder: Folder },
+    FolderUpdated { folder: Folder },
+    FolderDeleted { folder_id: String },
+    SpaceUpdated { space: SpaceDetails },
+    SpaceDeleted,
+}
+
+#[derive(Serialize)]
+struct EventMessage<'a> {
+    space_id: &'a str,
+    #[serde(flatten)]
+    event: &'a SpaceEvent,
+}
+
+#[derive(Clone)]
+struct Broadcast {
+    space_id: String,
+    // None if only who may see the space changed
+    json: Option<Utf8Bytes>,
+    // subscribers make sure they may still see the space before passing anything on
+    recheck: bool,
+    // nothing follows the deletion of a space, so its subscribers are disconnected
+    last: bool,
+}
+
+/// Fans events out to the WebSocket subscribers of every space.
+/// Events are serialized once and every subscriber picks the ones of its own space.
+#[derive(Clone)]
+pub struct SpaceEvents {
+    sender: broadcast::Sender<Broadcast>,
+}
+
+impl SpaceEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+
+    pub fn emit(&self, space_id: &str, event: SpaceEvent) {
+        let message = EventMessage {
+            space_id,
+            event: &event,
+        };
+        match serde_json::to_string(&message) {
+            Ok(json) => {
+                // fails only when nobody is listening
+                let _ = self.sender.send(Broadcast {
+                    space_id: space_id.to_string(),
+                    json: Some(json.into()),
+                    recheck: matches!(event, SpaceEvent::SpaceUpdated { .. }),
+                    last: matches!(event, SpaceEvent::SpaceDeleted),
+                });
+            }
+            Err(e) => tracing::error!("Failed to serialize space event: {}", e),
+        }
+    }
+
+    /// Has the subscribers of `space_id` check their access again, e.g. after a member was removed.
+    pub fn access_changed(&self, space_id: &str) {
+        let _ = self.sender.send(Broadcast {
+            space_id: space_id.to_string(),
+            json: None,
+            recheck: true,
+            last: false,
+        });
+    }
+}
+
+/// Who opened a subscription, to check again later whether they still may see the space.
+struct Subscriber {
+    pool: PgPool,
+    space_id: String,
+    user_id: String,
+    session: String,
+    token: SpaceToken,
+}
+
+impl Subscriber {
+    async fn has_access(&self) -> bool {
+        let result = async {
+            let user = session_user(&self.pool, &self.session).await?;
+            require_access(
+                &self.pool,
+                &self.space_id,
+                &user,
+                &self.token,
+                SpaceRole::Viewer,
+            )
+            .await?;
+            Ok::<_, AppError>(user.id == self.user_id)
+        }
+        .await;
+        match result {
+            Ok(same_user) => same_user,
+            Err(e) => {
+                tracing::info!(
+                    "Closing event subscription of user {} to space {}: {}",
+                    self.user_id,
+                    self.space_id,
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+#[debug_handler()]
+pub async fn space_events(
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(space_id): Path<String>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let subscriber = Subscriber {
+        pool,
+        space_id,
+        user_id: user.id,
+        // the user was authenticated with it just now
+        session: session_token(&headers).unwrap_or_default(),
+        token,
+    };
+    // subscribe before upgrading so nothing emitted in between is lost
+    let receiver = events.sender.subscribe();
+    Ok(ws.on_upgrade(move |socket| forward_events(socket, subscriber, receiver)))
+}
+
+async fn forward_events(
+    mut socket: WebSocket,
+    subscriber: Subscriber,
+    mut receiver: broadcast::Receiver<Broadcast>,
+) {
+    let space_id = &subscriber.space_id;
+    let mut recheck =
+        tokio::time::interval_at(tokio::time::Instant::now() + ACCESS_RECHECK, ACCESS_RECHECK);
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(broadcast) if broadcast.space_id == *space_id => {
+                    if broadcast.recheck && !subscriber.has_access().await {
+                        close_forbidden(socket).await;
+                        return;
+                    }
+                    let Some(json) = broadcast.json else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(json)).await.is_err() || broadcast.last {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("Event subscriber of space {} missed {} events", space_id, missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = recheck.tick() => {
+                if !subscriber.has_access().await {
+                    close_forbidden(socket).await;
+                    return;
+                }
+            },
+            // clients only ever send pings and close frames, axum answers the pings itself
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn close_forbidden(mut socket: WebSocket) {
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: "Access to the space was revoked".into(),
+        })))
+        .await;
+}
@@ -1,167 +1,707 @@
-use std::fs::remove_file;
-
 use anyhow::anyhow;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
 use sha2::{Digest, Sha256};
+use std::{collections::HashSet, path::PathBuf};
 use time::OffsetDateTime;
 use time::serde::rfc3339 as rfc3339_mod;
 use tokio::{fs::File, io::AsyncWriteExt};
 
 use axum::{
     Json,
-    body::Body,
+    body::{Body, Bytes},
     debug_handler,
-    extract::{Multipart, Path, State},
-    http::{HeaderMap, HeaderValue, header},
-    response::IntoResponse,
+    extract::{Multipart, Path, Query, State, multipart::Field},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
 };
-use tokio_util::io::ReaderStream;
+use futures_util::stream::{self, StreamExt};
+use sqlx::{FromRow, PgPool, PgTransaction, Postgres, QueryBuilder};
 
 use crate::{
     AppState,
+    auth::AuthUser,
+    blobs::acquire_blob,
     errors::{AppError, ErrorType, IntoAppError},
+    events::SpaceEvent,
+    folders::{double_option, ensure_folder_path, require_folder, split_relative_path},
+    geo::{Redactions, gps_redactions, location_not_removable, redact, space_strips_gps},
+    members::{
+        SpaceRole, SpaceToken, require_access, require_file_access, require_file_role, require_role,
+    },
+    metadata::{FileMetadata, extract_metadata, insert_metadata, metadata_for},
+    pagination::{KeyType, Keyed, Order, Page, PageRequest, SortKey},
+    quotas::{SpaceUsage, reserve_quota},
+    ranges::{RangeRequest, etag_matches, parse_range},
+    search::{extract_text, insert_text},
+    shares::record_share_download,
     spaces::Space,
+    storage::{BlobStore, ByteStream},
+    trash::{Trashed, purge_files},
+    versions::{resolve_conflict, save_space_file},
 };
 
-fn serialize_opt<S: Serializer>(opt: &Option<OffsetDateTime>, s: S) -> Result<S::Ok, S::Error> {
+pub(crate) fn serialize_opt<S: Serializer>(
+    opt: &Option<OffsetDateTime>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
     match opt {
         Some(dt) => rfc3339_mod::serialize(dt, s),
         None => s.serialize_none(),
     }
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize, FromRow)]
 pub struct SpaceFile {
-    id: String,
-    space_id: String,
+    pub(crate) id: String,
+    pub(crate) space_id: String,
     original_filename: String,
     file_size_bytes: i64,
-    mime_type: Option<String>,
+    pub(crate) mime_type: Option<String>,
     #[serde(with = "time::serde::rfc3339")]
     upload_date: OffsetDateTime,
     #[serde(serialize_with = "serialize_opt")]
     last_accessed: Option<OffsetDateTime>,
     download_count: i32,
-    checksum: String,
+    pub(crate) checksum: String,
+    uploaded_by: Option<String>,
+    folder_id: Option<String>,
+    #[serde(serialize_with = "serialize_opt")]
+    pub(crate) deleted_at: Option<OffsetDateTime>,
+    pub(crate) version: i32,
+}
+
+impl SpaceFile {
+    /// The file with the content of one of its earlier versions, to serve that version.
+    pub(crate) fn with_content(
+        self,
+        checksum: String,
+        file_size_bytes: i64,
+        mime_type: Option<String>,
+    ) -> Self {
+        Self {
+            checksum,
+            file_size_bytes,
+            mime_type,
+            ..self
+        }
+    }
+}
+
+/// A file together with what was extracted from its content, if anything.
+#[derive(Serialize)]
+pub struct SpaceFileDetails {
+    #[serde(flatten)]
+    pub(crate) file: SpaceFile,
+    metadata: Option<FileMetadata>,
+}
+
+/// Streams a multipart field into a temp file inside `upload_path` while hashing it.
+/// Returns the temp file path, the checksum and the size in bytes.
+async fn store_field(
+    field: &mut Field<'_>,
+    upload_path: &str,
+    usage: &SpaceUsage,
+) -> Result<(PathBuf, String, i64), AppError> {
+    let temp_path =
+        std::path::Path::new(upload_path).join(format!(".{}.part", uuid::Uuid::new_v4()));
+
+    match write_field(field, &temp_path, usage).await {
+        Ok((checksum, file_size_bytes)) => Ok((temp_path, checksum, file_size_bytes)),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            Err(e)
+        }
+    }
+}
+
+async fn write_field(
+    field: &mut Field<'_>,
+    temp_path: &std::path::Path,
+    usage: &SpaceUsage,
+) -> Result<(String, i64), AppError> {
+    let mut file = File::create_new(temp_path).await.into_internal_error()?;
+    let mut hasher = Sha256::new();
+    let mut file_size_bytes: i64 = 0;
+
+    while let Some(chunk) = field.chunk().await.into_validation_error()? {
+        file_size_bytes += chunk.len() as i64;
+        // stop as soon as the file is too large instead of after receiving all of it
+        usage.check_upload(file_size_bytes)?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.into_internal_error()?;
+    }
+    file.sync_all().await.into_internal_error()?;
+
+    Ok((format!("{:x}", hasher.finalize()), file_size_bytes))
+}
+
+/// A file about to be added to a space, its blob acquired in the same transaction.
+pub(crate) struct NewSpaceFile<'a> {
+    pub space_id: &'a str,
+    pub folder_id: Option<&'a str>,
+    pub original_filename: Option<String>,
+    pub file_size_bytes: i64,
+    pub checksum: &'a str,
+    pub mime_type: Option<String>,
+    pub uploaded_by: Option<&'a str>,
+}
+
+/// Inserts the `files` row for a blob acquired in the same transaction. Its size is added to
+/// the space's total by [`reserve_quota`].
+pub(crate) async fn insert_space_file(
+    tx: &mut PgTransaction<'_>,
+    file: NewSpaceFile<'_>,
+) -> Result<SpaceFile, AppError> {
+    let id = uuid::Uuid::new_v4();
+
+    let file_rec = sqlx::query_as!(
+        SpaceFile,
+        r#"INSERT INTO files (id, space_id, folder_id, original_filename, file_size_bytes, checksum, mime_type, uploaded_by) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *"#,
+        id.to_string(),
+        file.space_id,
+        file.folder_id,
+        file.original_filename,
+        file.file_size_bytes,
+        file.checksum,
+        file.mime_type,
+        file.uploaded_by
+    )
+    .fetch_one(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    Ok(file_rec)
+}
+
+/// Makes `file` the new current version of `file_id`, keeping the current one as an earlier version.
+/// The blob of `file` must have been acquired in the same transaction. Earlier versions keep taking
+/// up space, so all of its size is added to the space's total by [`reserve_quota`].
+pub(crate) async fn replace_space_file(
+    tx: &mut PgTransaction<'_>,
+    file_id: &str,
+    file: NewSpaceFile<'_>,
+) -> Result<SpaceFile, AppError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO file_versions (file_id, version, checksum, file_size_bytes, mime_type, uploaded_by, upload_date)
+        SELECT id, version, checksum, file_size_bytes, mime_type, uploaded_by, upload_date FROM files WHERE id = $1
+        "#,
+        file_id
+    )
+    .execute(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    let file_rec = sqlx::query_as!(
+        SpaceFile,
+        r#"
+        UPDATE files
+        SET version = version + 1, checksum = $2, file_size_bytes = $3, mime_type = $4, uploaded_by = $5,
+            upload_date = NOW()
+        WHERE id = $1
+        RETURNING *
+        "#,
+        file_id,
+        file.checksum,
+        file.file_size_bytes,
+        file.mime_type,
+        file.uploaded_by
+    )
+    .fetch_one(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    Ok(file_rec)
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    /// Folder the files are uploaded to, the top of the space if not set.
+    folder: Option<String>,
 }
 
 #[debug_handler()]
 pub async fn space_files_post(
-    State(AppState { pool, upload_path }): State<AppState>,
+    State(AppState {
+        pool,
+        upload_path,
+        store,
+        events,
+        thumbnails,
+        quotas,
+        ..
+    }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
     Path(space_id): Path<String>,
+    Query(query): Query<UploadQuery>,
     mut multipart: Multipart,
 ) -> Result<Json<Vec<SpaceFile>>, AppError> {
-    // TODO: change 2MB file upload limit
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Uploader).await?;
+    if let Some(folder_id) = &query.folder {
+        require_folder(&pool, &space_id, folder_id).await?;
+    }
+
     let mut files: Vec<SpaceFile> = Vec::new();
 
     // check if space exists
-    let rec = sqlx::query_as!(Space, "SELECT * from spaces where id = $1", space_id)
-        .fetch_optional(&pool)
-        .await
-        .into_db_error()?
-        .ok_or_else(|| {
-            AppError::new(
-                ErrorType::NotFound("Space not found".into()),
-                anyhow!("Couldn't find requested Space"),
-            )
-        })?;
-    while let Some(field) = multipart.next_field().await.into_validation_error()? {
-        let old_filename: Option<String> = field.file_name().map(|s| s.to_string());
-
+    let rec = sqlx::query_as!(
+        Space,
+        "SELECT * from spaces where id = $1 AND deleted_at IS NULL",
+        space_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Space not found".into()),
+            anyhow!("Couldn't find requested Space"),
+        )
+    })?;
+    let mut usage = SpaceUsage::of(&rec, &quotas);
+
+    while let Some(mut field) = multipart.next_field().await.into_validation_error()? {
+        // directory uploads send the path relative to the uploaded directory as the filename
+        let Some(path) = field.file_name() else {
+            return Err(AppError::new(
+                ErrorType::Validation("Every part must be a file with a filename".into()),
+                anyhow!("Multipart field {:?} without a filename", field.name()),
+            ));
+        };
+        let (folder_names, old_filename) = split_relative_path(path);
+
+        // parts without a Content-Type are plain bytes as far as multipart/form-data is concerned
         let filetype = field
             .content_type()
-            .expect("Content-Type should be set")
+            .unwrap_or("application/octet-stream")
             .to_string();
 
-        let data = field.bytes().await.into_validation_error()?;
-        let file_size_bytes = data.len() as i64;
-        let checksum = format!("{:x}", Sha256::digest(&data));
+        let (temp_path, checksum, file_size_bytes) =
+            store_field(&mut field, &upload_path, &usage).await?;
+        let metadata =
+            match extract_metadata(temp_path.clone(), &checksum, Some(filetype.clone())).await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    let _ = tokio::fs::remove_file(&temp_path).await;
+                    return Err(e);
+                }
+            };
+        let text = extract_text(temp_path.clone(), Some(filetype.clone())).await;
+
+        let mut tx = pool.begin().await.into_db_error()?;
+        let stored = async {
+            let (folder_id, created) =
+                ensure_folder_path(&mut tx, &rec.id, query.folder.clone(), &folder_names).await?;
+            let new_file = NewSpaceFile {
+                space_id: &rec.id,
+                folder_id: folder_id.as_deref(),
+                original_filename: Some(old_filename),
+                file_size_bytes,
+                checksum: &checksum,
+                mime_type: Some(filetype),
+                uploaded_by: Some(&user.id),
+            };
+            let resolution = resolve_conflict(&mut tx, &new_file).await?;
+            acquire_blob(
+                &mut tx,
+                store.as_ref(),
+                &temp_path,
+                &checksum,
+                file_size_bytes,
+            )
+            .await?;
+            insert_metadata(&mut tx, &metadata).await?;
+            insert_text(&mut tx, &checksum, text.as_deref()).await?;
+            let saved = save_space_file(&mut tx, resolution, new_file).await?;
+            reserve_quota(&mut tx, &rec.id, file_size_bytes, &quotas).await?;
+            Ok((saved, created))
+        }
+        .await;
+        let (saved, created_folders) = match stored {
+            Ok(stored) => stored,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        };
+        tx.commit().await.into_db_error()?;
+        usage.used_bytes += file_size_bytes;
+
+        for folder in created_folders {
+            events.emit(&space_id, SpaceEvent::FolderCreated { folder });
+        }
+        let file_rec = saved.file();
+        thumbnails.schedule(&file_rec.checksum, file_rec.mime_type.as_deref());
+        events.emit(&space_id, saved.event());
+        files.push(saved.into_file());
+    }
 
-        let id = uuid::Uuid::new_v4();
+    Ok(Json::from(files))
+}
 
-        let filepath = std::path::Path::new(&upload_path).join(&checksum);
-        if filepath.exists() {
-            //handle this
-        } else {
-            let mut file = File::create_new(&filepath)
-                .await
-                .expect("Filename should be unique and therefore not existant on creation!");
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileSort {
+    Name,
+    Size,
+    UploadDate,
+    DownloadCount,
+}
 
-            file.write_all(&data).await.into_internal_error()?;
+impl FileSort {
+    fn key(self) -> SortKey {
+        match self {
+            FileSort::Name => SortKey {
+                name: "name",
+                expression: "lower(original_filename)",
+                sql_type: KeyType::Text,
+                default_order: Order::Asc,
+            },
+            FileSort::Size => SortKey {
+                name: "size",
+                expression: "file_size_bytes",
+                sql_type: KeyType::BigInt,
+                default_order: Order::Desc,
+            },
+            FileSort::UploadDate => SortKey {
+                name: "upload_date",
+                expression: "upload_date",
+                sql_type: KeyType::Timestamp,
+                default_order: Order::Desc,
+            },
+            FileSort::DownloadCount => SortKey {
+                name: "download_count",
+                expression: "download_count",
+                sql_type: KeyType::Integer,
+                default_order: Order::Desc,
+            },
         }
-
-        let file_rec = sqlx::query_as!(
-            SpaceFile,
-            r#"INSERT INTO files (id, space_id, original_filename, file_size_bytes, checksum, mime_type) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *"#,
-            id.to_string(),
-            rec.id,
-            old_filename,
-            file_size_bytes,
-            checksum,
-            filetype
-        ).fetch_one(&pool).await.into_db_error()?;
-        files.push(file_rec);
     }
+}
 
-    let total_file_sizes: i64 = files.iter().map(|file| file.file_size_bytes).sum();
+#[derive(Deserialize)]
+pub struct FileListQuery {
+    sort: Option<FileSort>,
+    order: Option<Order>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    /// Prefix of the MIME type, e.g. "image/" or "video/mp4".
+    mime: Option<String>,
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    uploaded_after: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    uploaded_before: Option<OffsetDateTime>,
+    /// User id of the uploader.
+    uploaded_by: Option<String>,
+    /// Only the files directly in this folder, "root" for those at the top of the space.
+    folder: Option<String>,
+}
 
-    sqlx::query!(
-        r#"UPDATE spaces SET total_size_used_bytes = total_size_used_bytes + $2 WHERE id = $1"#,
-        space_id,
-        total_file_sizes
-    )
-    .execute(&pool)
-    .await
-    .into_db_error()?;
+/// Escapes the wildcards of LIKE so `prefix` only matches literally.
+fn like_prefix(prefix: &str) -> String {
+    let escaped = prefix
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("{}%", escaped)
+}
 
-    Ok(Json::from(files))
+fn push_file_filters<'a>(
+    builder: &mut QueryBuilder<'a, Postgres>,
+    space_id: &'a str,
+    query: &'a FileListQuery,
+) {
+    builder
+        .push(" WHERE deleted_at IS NULL AND space_id = ")
+        .push_bind(space_id);
+    if let Some(mime) = &query.mime {
+        builder
+            .push(" AND mime_type LIKE ")
+            .push_bind(like_prefix(mime));
+    }
+    if let Some(min_size) = query.min_size {
+        builder.push(" AND file_size_bytes >= ").push_bind(min_size);
+    }
+    if let Some(max_size) = query.max_size {
+        builder.push(" AND file_size_bytes <= ").push_bind(max_size);
+    }
+    if let Some(uploaded_after) = query.uploaded_after {
+        builder
+            .push(" AND upload_date >= ")
+            .push_bind(uploaded_after);
+    }
+    if let Some(uploaded_before) = query.uploaded_before {
+        builder
+            .push(" AND upload_date < ")
+            .push_bind(uploaded_before);
+    }
+    if let Some(uploaded_by) = &query.uploaded_by {
+        builder.push(" AND uploaded_by = ").push_bind(uploaded_by);
+    }
+    match query.folder.as_deref() {
+        Some("root") => {
+            builder.push(" AND folder_id IS NULL");
+        }
+        Some(folder) => {
+            builder.push(" AND folder_id = ").push_bind(folder);
+        }
+        None => {}
+    }
 }
 
 #[debug_handler()]
 pub async fn space_files_get(
     State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
     Path(space_id): Path<String>,
-) -> Result<Json<Vec<SpaceFile>>, AppError> {
-    // let rec = sqlx::query_as!(Space, "SELECT * from spaces where id = $1", space_id)
-    //     .fetch_optional(&pool)
-    //     .await?
-    //     .expect("Space doesn't exist!");
+    Query(query): Query<FileListQuery>,
+) -> Result<Json<Page<SpaceFileDetails>>, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let page = PageRequest::new(
+        query.sort.unwrap_or(FileSort::UploadDate).key(),
+        query.order,
+        query.limit,
+        query.cursor.as_deref(),
+    )?;
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM files");
+    push_file_filters(&mut count, &space_id, &query);
+    let total: i64 = count
+        .build_query_scalar()
+        .fetch_one(&pool)
+        .await
+        .into_db_error()?;
+
+    let mut select = QueryBuilder::new("SELECT *");
+    page.push_select_keys(&mut select, "id");
+    select.push(" FROM files");
+    push_file_filters(&mut select, &space_id, &query);
+    page.push_after_cursor(&mut select, "id");
+    page.push_order_and_limit(&mut select, "id");
+    let rows: Vec<Keyed<SpaceFile>> = select
+        .build_query_as()
+        .fetch_all(&pool)
+        .await
+        .into_db_error()?;
+
+    let page = page.into_page(rows, total)?;
+    Ok(Json::from(Page {
+        items: with_metadata(&pool, page.items).await?,
+        total: page.total,
+        next: page.next,
+    }))
+}
 
-    let files = sqlx::query_as!(
+/// Attaches the extracted metadata to each file.
+pub(crate) async fn with_metadata(
+    pool: &PgPool,
+    files: Vec<SpaceFile>,
+) -> Result<Vec<SpaceFileDetails>, AppError> {
+    let checksums: Vec<String> = files.iter().map(|file| file.checksum.clone()).collect();
+    let metadata = metadata_for(pool, &checksums).await?;
+    let space_ids: Vec<String> = files.iter().map(|file| file.space_id.clone()).collect();
+    let stripping: HashSet<String> = sqlx::query_scalar!(
+        "SELECT id FROM spaces WHERE strip_gps AND id = ANY($1)",
+        &space_ids
+    )
+    .fetch_all(pool)
+    .await
+    .into_db_error()?
+    .into_iter()
+    .collect();
+
+    Ok(files
+        .into_iter()
+        .map(|file| {
+            // files sharing a blob share its metadata
+            let mut metadata = metadata.get(&file.checksum).cloned();
+            if let Some(metadata) = &mut metadata
+                && stripping.contains(&file.space_id)
+            {
+                metadata.strip_location();
+            }
+            SpaceFileDetails { file, metadata }
+        })
+        .collect())
+}
+
+#[debug_handler()]
+pub async fn files_get_one(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(file_id): Path<String>,
+) -> Result<Json<SpaceFileDetails>, AppError> {
+    require_file_access(&pool, &file_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let file = get_space_file(&pool, &file_id).await?;
+    let details = with_metadata(&pool, vec![file]).await?.pop();
+
+    Ok(Json::from(details.expect("one file in, one file out")))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateFileRequest {
+    /// Moves the file, `null` moves it to the top of the space.
+    #[serde(default, deserialize_with = "double_option")]
+    folder_id: Option<Option<String>>,
+}
+
+#[debug_handler()]
+pub async fn files_update(
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
+    Path(file_id): Path<String>,
+    Json(payload): Json<UpdateFileRequest>,
+) -> Result<Json<SpaceFile>, AppError> {
+    require_file_role(&pool, &file_id, &user, SpaceRole::Editor).await?;
+
+    let file = get_space_file(&pool, &file_id).await?;
+    let Some(folder_id) = payload.folder_id else {
+        return Ok(Json::from(file));
+    };
+    if let Some(folder_id) = &folder_id {
+        require_folder(&pool, &file.space_id, folder_id).await?;
+    }
+
+    let file = sqlx::query_as!(
         SpaceFile,
-        r"SELECT * from files where space_id = $1",
-        space_id,
+        r#"UPDATE files SET folder_id = $2 WHERE id = $1 RETURNING *"#,
+        file_id,
+        folder_id
     )
-    .fetch_all(&pool)
+    .fetch_one(&pool)
     .await
     .into_db_error()?;
 
-    Ok(Json::from(files))
+    events.emit(
+        &file.space_id,
+        SpaceEvent::FileUpdated { file: file.clone() },
+    );
+
+    Ok(Json::from(file))
+}
+
+pub(crate) async fn list_space_files(
+    pool: &PgPool,
+    space_id: &str,
+) -> Result<Vec<SpaceFile>, AppError> {
+    sqlx::query_as!(
+        SpaceFile,
+        r"SELECT * from files where space_id = $1 AND deleted_at IS NULL",
+        space_id,
+    )
+    .fetch_all(pool)
+    .await
+    .into_db_error()
+}
+
+pub(crate) async fn get_space_files(
+    pool: &PgPool,
+    file_ids: &[String],
+) -> Result<Vec<SpaceFile>, AppError> {
+    sqlx::query_as!(
+        SpaceFile,
+        "SELECT * FROM files WHERE id = ANY($1) AND deleted_at IS NULL",
+        file_ids
+    )
+    .fetch_all(pool)
+    .await
+    .into_db_error()
+}
+
+pub(crate) async fn get_space_file(pool: &PgPool, file_id: &str) -> Result<SpaceFile, AppError> {
+    sqlx::query_as!(
+        SpaceFile,
+        r"SELECT * from files where id = $1 AND deleted_at IS NULL",
+        file_id,
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::Validation("File not found".into()),
+            anyhow!("Requested file not stored in database"),
+        )
+    })
 }
 
 #[debug_handler()]
 pub async fn files_download(
-    State(AppState { pool, upload_path }): State<AppState>,
+    State(AppState { pool, store, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
     Path(file_id): Path<String>,
-) -> Result<impl IntoResponse, AppError> {
-    let file_meta = sqlx::query_as!(SpaceFile, r"SELECT * from files where id = $1", file_id,)
-        .fetch_optional(&pool)
-        .await
-        .into_db_error()?
-        .ok_or_else(|| {
-            AppError::new(
-                ErrorType::Validation("File not found".into()),
-                anyhow!("Requested file not stored in database"),
-            )
-        })?;
+    method: Method,
+    request_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    require_file_access(&pool, &file_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let file_meta = get_space_file(&pool, &file_id).await?;
+
+    serve_file(
+        &pool,
+        store.as_ref(),
+        file_meta,
+        None,
+        method,
+        &request_headers,
+    )
+    .await
+}
 
+/// Answers a download of `file_meta`, honouring conditional and range requests.
+/// Downloads through a share link are counted against it as well, every range request as a whole download.
+pub(crate) async fn serve_file(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    file_meta: SpaceFile,
+    share_link_id: Option<&str>,
+    method: Method,
+    request_headers: &HeaderMap,
+) -> Result<Response, AppError> {
     let mut headers = HeaderMap::new();
+    let total = file_meta.file_size_bytes as u64;
+
+    let redactions = if space_strips_gps(pool, &file_meta.space_id).await? {
+        gps_redactions(
+            pool,
+            store,
+            &file_meta.checksum,
+            file_meta.mime_type.as_deref(),
+            total,
+        )
+        .await?
+        .ok_or_else(|| {
+            location_not_removable(&file_meta.checksum, file_meta.mime_type.as_deref())
+        })?
+    } else {
+        Redactions::default()
+    };
+
+    // blobs are content addressed, so the checksum is a strong validator
+    let etag = if redactions.is_empty() {
+        format!("\"{}\"", file_meta.checksum)
+    } else {
+        format!("\"{}-nogps\"", file_meta.checksum)
+    };
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag).into_internal_error()?,
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
 
-    if let Some(mime_type) = file_meta.mime_type {
-        headers.insert(
-            header::CONTENT_TYPE,
-            HeaderValue::from_str(&mime_type).into_internal_error()?,
-        );
+    let if_none_match = request_headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    if if_none_match.is_some_and(|v| etag_matches(v, &etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
     }
 
     let content_disposition = format!("attachment; filename=\"{}\"", file_meta.original_filename);
@@ -170,32 +710,147 @@ pub async fn files_download(
         HeaderValue::from_str(&content_disposition).into_internal_error()?,
     );
 
-    let filepath = std::path::Path::new(&upload_path).join(file_meta.checksum);
-
-    let file = File::open(filepath).await.into_internal_error()?;
+    // a Range is only honoured if If-Range (when sent) still names this exact blob
+    let if_range_matches = request_headers
+        .get(header::IF_RANGE)
+        .is_none_or(|v| v.to_str().is_ok_and(|v| v.trim() == etag));
+    let range_request = match request_headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(range) if if_range_matches => parse_range(range, total),
+        _ => RangeRequest::Full,
+    };
+
+    let is_head = method == Method::HEAD;
+    let serves_bytes = !is_head && !matches!(range_request, RangeRequest::Unsatisfiable);
+    // every request sending part of the file uses up a download of the link, reserved before
+    // serving it, otherwise fetching a file in ranges would get around max_downloads
+    if serves_bytes && let Some(share_link_id) = share_link_id {
+        record_share_download(pool, share_link_id).await?;
+    }
+    let counts_as_download = match &range_request {
+        RangeRequest::Full => true,
+        RangeRequest::Partial(ranges) => ranges[0].start == 0,
+        RangeRequest::Unsatisfiable => false,
+    };
+    if counts_as_download && !is_head {
+        sqlx::query!(
+            r#"UPDATE files SET download_count = download_count + 1 WHERE id = $1"#,
+            file_meta.id,
+        )
+        .execute(pool)
+        .await
+        .into_db_error()?;
+    }
 
-    sqlx::query!(
-        r#"UPDATE files SET download_count = download_count + 1 WHERE id = $1"#,
-        file_meta.id,
-    )
-    .execute(&pool)
-    .await
-    .into_db_error()?;
+    let mime_type = file_meta
+        .mime_type
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let (status, content_length, body) = match range_request {
+        RangeRequest::Unsatisfiable => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", total)).into_internal_error()?,
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+        RangeRequest::Full => {
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(&mime_type).into_internal_error()?,
+            );
+            let body = if is_head {
+                Body::empty()
+            } else {
+                Body::from_stream(redact(store.get(&file_meta.checksum).await?, 0, redactions))
+            };
+            (StatusCode::OK, total, body)
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(&mime_type).into_internal_error()?,
+            );
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end, total))
+                    .into_internal_error()?,
+            );
+            let body = if is_head {
+                Body::empty()
+            } else {
+                Body::from_stream(redact(
+                    store
+                        .get_range(&file_meta.checksum, range.start, range.len())
+                        .await?,
+                    range.start,
+                    redactions,
+                ))
+            };
+            (StatusCode::PARTIAL_CONTENT, range.len(), body)
+        }
+        RangeRequest::Partial(ranges) => {
+            let boundary = uuid::Uuid::new_v4().simple().to_string();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+                    .into_internal_error()?,
+            );
+
+            let mut parts: Vec<ByteStream> = Vec::new();
+            let mut content_length = 0;
+            for range in ranges {
+                let part_header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, mime_type, range.start, range.end, total
+                );
+                content_length += part_header.len() as u64 + range.len();
+                if is_head {
+                    continue;
+                }
+
+                parts.push(stream::once(async move { Ok(Bytes::from(part_header)) }).boxed());
+                parts.push(redact(
+                    store
+                        .get_range(&file_meta.checksum, range.start, range.len())
+                        .await?,
+                    range.start,
+                    redactions.clone(),
+                ));
+            }
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            content_length += closing.len() as u64;
+            parts.push(stream::once(async move { Ok(Bytes::from(closing)) }).boxed());
+
+            let body = if is_head {
+                Body::empty()
+            } else {
+                Body::from_stream(stream::iter(parts).flatten())
+            };
+            (StatusCode::PARTIAL_CONTENT, content_length, body)
+        }
+    };
 
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
 
-    Ok((headers, body))
+    Ok((status, headers, body).into_response())
 }
 
+/// Moves a file to the trash, it is purged once the trash retention period is over.
 #[debug_handler()]
 pub async fn files_delete(
-    State(AppState { pool, upload_path }): State<AppState>,
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
     Path(file_id): Path<String>,
 ) -> Result<impl IntoResponse, AppError> {
+    require_file_role(&pool, &file_id, &user, SpaceRole::Editor).await?;
+
     let file_meta = sqlx::query_as!(
         SpaceFile,
-        r#"DELETE from files where id = $1 RETURNING *"#,
+        r#"UPDATE files SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL RETURNING *"#,
         file_id,
     )
     .fetch_optional(&pool)
@@ -208,30 +863,103 @@ pub async fn files_delete(
         )
     })?;
 
-    sqlx::query!(
-        r#"UPDATE spaces SET total_size_used_bytes = GREATEST(0, total_size_used_bytes - $2) WHERE id = $1"#,
-        file_meta.space_id,
-        file_meta.file_size_bytes
-    ).execute(&pool).await.into_db_error()?;
+    events.emit(
+        &file_meta.space_id,
+        SpaceEvent::FileDeleted {
+            file_id: file_meta.id.clone(),
+        },
+    );
+
+    Ok(Json::from(file_meta))
+}
+
+/// The files in the trash of a space, most recently deleted first.
+#[debug_handler()]
+pub async fn space_trash_get(
+    State(AppState {
+        pool,
+        trash_retention,
+        ..
+    }): State<AppState>,
+    user: AuthUser,
+    Path(space_id): Path<String>,
+) -> Result<Json<Vec<Trashed<SpaceFile>>>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Editor).await?;
 
-    let other_files = sqlx::query_as!(
+    let files = sqlx::query_as!(
         SpaceFile,
-        r#"SELECT * from files where checksum = $1"#,
-        file_meta.checksum
+        r#"
+        SELECT * FROM files WHERE space_id = $1 AND deleted_at IS NOT NULL
+        ORDER BY deleted_at DESC, id
+        "#,
+        space_id
     )
     .fetch_all(&pool)
     .await
     .into_db_error()?;
 
-    let filepath = std::path::Path::new(&upload_path).join(&file_meta.checksum);
-    if other_files.len() == 0 {
-        remove_file(filepath).map_err(|e| {
-            AppError::new(
-                ErrorType::Internal("An error occured removing the file".into()),
-                e.into(),
-            )
-        })?;
-    }
+    Ok(Json::from(
+        files
+            .into_iter()
+            .map(|file| Trashed::new(file.deleted_at, trash_retention, file))
+            .collect::<Vec<_>>(),
+    ))
+}
 
-    Ok(Json::from(file_meta))
+/// Takes a file out of the trash, back into the folder it was deleted from.
+#[debug_handler()]
+pub async fn files_restore(
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
+    Path(file_id): Path<String>,
+) -> Result<Json<SpaceFile>, AppError> {
+    require_file_role(&pool, &file_id, &user, SpaceRole::Editor).await?;
+
+    let file = sqlx::query_as!(
+        SpaceFile,
+        r#"UPDATE files SET deleted_at = NULL WHERE id = $1 AND deleted_at IS NOT NULL RETURNING *"#,
+        file_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("File not found in the trash".into()),
+            anyhow!("File {} is not in the trash", file_id),
+        )
+    })?;
+
+    events.emit(&file.space_id, SpaceEvent::FileAdded { file: file.clone() });
+
+    Ok(Json::from(file))
+}
+
+/// Deletes a file in the trash for good without waiting for the retention period,
+/// so its space counts against the quota no longer.
+#[debug_handler()]
+pub async fn files_trash_delete(
+    State(AppState { pool, store, .. }): State<AppState>,
+    user: AuthUser,
+    Path(file_id): Path<String>,
+) -> Result<Json<SpaceFile>, AppError> {
+    require_file_role(&pool, &file_id, &user, SpaceRole::Editor).await?;
+
+    let file = sqlx::query_as!(
+        SpaceFile,
+        "SELECT * FROM files WHERE id = $1 AND deleted_at IS NOT NULL",
+        file_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("File not found in the trash".into()),
+            anyhow!("File {} is not in the trash", file_id),
+        )
+    })?;
+    purge_files(&pool, store.as_ref(), std::slice::from_ref(&file.id)).await?;
+
+    Ok(Json::from(file))
 }
@@ -0,0 +1,369 @@
+use anyhow::anyhow;
+use axum::{
+    Json, debug_handler,
+    extract::{Path, State},
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::{PgPool, PgTransaction};
+use time::OffsetDateTime;
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    errors::{AppError, ErrorType, IntoAppError},
+    events::SpaceEvent,
+    members::{SpaceRole, SpaceToken, require_access},
+};
+
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(Clone, Serialize)]
+pub struct Folder {
+    id: String,
+    space_id: String,
+    parent_id: Option<String>,
+    name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+/// Distinguishes a missing field (`None`) from an explicit `null` (`Some(None)`).
+pub(crate) fn double_option<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+fn folder_not_found(folder_id: &str) -> AppError {
+    AppError::new(
+        ErrorType::NotFound("Folder not found".into()),
+        anyhow!("Folder {} not found in space", folder_id),
+    )
+}
+
+fn name_taken(name: &str) -> AppError {
+    AppError::new(
+        ErrorType::Conflict(format!("There already is a folder named \"{}\" here", name)),
+        anyhow!("Folder name {} taken", name),
+    )
+}
+
+fn validate_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty()
+        || name.len() > MAX_NAME_LEN
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+    {
+        return Err(AppError::new(
+            ErrorType::Validation(format!(
+                "Folder names must be 1 to {} characters long and can't contain slashes",
+                MAX_NAME_LEN
+            )),
+            anyhow!("Invalid folder name {:?}", name),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// Splits the relative path of a directory upload ("photos/2024/beach.jpg")
+/// into the names of its folders and the name of the file.
+pub(crate) fn split_relative_path(path: &str) -> (Vec<String>, String) {
+    let mut segments: Vec<String> = path
+        .split(['/', '\\'])
+        .map(str::trim)
+        // a path never leads out of the folder it is uploaded to
+        .filter(|segment| !segment.is_empty() && *segment != "." && *segment != "..")
+        .map(|segment| segment.chars().take(MAX_NAME_LEN).collect())
+        .collect();
+    match segments.pop() {
+        Some(name) => (segments, name),
+        None => (Vec::new(), path.to_string()),
+    }
+}
+
+/// Fails unless `folder_id` is a folder of `space_id`.
+pub(crate) async fn require_folder(
+    pool: &PgPool,
+    space_id: &str,
+    folder_id: &str,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        "SELECT id FROM folders WHERE id = $1 AND space_id = $2",
+        folder_id,
+        space_id
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| folder_not_found(folder_id))?;
+    Ok(())
+}
+
+/// Looks up the folders `names` below `parent` one level after the other, creating the missing ones.
+/// Returns the innermost folder (`parent` if there are no names) and the folders created.
+pub(crate) async fn ensure_folder_path(
+    tx: &mut PgTransaction<'_>,
+    space_id: &str,
+    parent: Option<String>,
+    names: &[String],
+) -> Result<(Option<String>, Vec<Folder>), AppError> {
+    let mut parent = parent;
+    let mut created = Vec::new();
+    for name in names {
+        let name = validate_name(name)?;
+        let folder = sqlx::query_as!(
+            Folder,
+            r#"
+            INSERT INTO folders (id, space_id, parent_id, name) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (space_id, parent_id, name) DO NOTHING
+            RETURNING id, space_id, parent_id, name, created_at
+            "#,
+            uuid::Uuid::new_v4().to_string(),
+            space_id,
+            parent,
+            name
+        )
+        .fetch_optional(&mut **tx)
+        .await
+        .into_db_error()?;
+
+        parent = Some(match folder {
+            Some(folder) => {
+                let id = folder.id.clone();
+                created.push(folder);
+                id
+            }
+            None => sqlx::query_scalar!(
+                "SELECT id FROM folders WHERE space_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND name = $3",
+                space_id,
+                parent,
+                name
+            )
+            .fetch_one(&mut **tx)
+            .await
+            .into_db_error()?,
+        });
+    }
+    Ok((parent, created))
+}
+
+#[debug_handler()]
+pub async fn folders_get(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(space_id): Path<String>,
+) -> Result<Json<Vec<Folder>>, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Viewer).await?;
+
+    // the whole tree at once, clients nest it by parent_id
+    let folders = sqlx::query_as!(
+        Folder,
+        r#"
+        SELECT id, space_id, parent_id, name, created_at FROM folders
+        WHERE space_id = $1
+        ORDER BY lower(name), id
+        "#,
+        space_id
+    )
+    .fetch_all(&pool)
+    .await
+    .into_db_error()?;
+
+    Ok(Json::from(folders))
+}
+
+#[derive(Deserialize)]
+pub struct CreateFolderRequest {
+    name: String,
+    parent_id: Option<String>,
+}
+
+#[debug_handler()]
+pub async fn folders_post(
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(space_id): Path<String>,
+    Json(payload): Json<CreateFolderRequest>,
+) -> Result<Json<Folder>, AppError> {
+    // uploading a directory creates folders as well
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Uploader).await?;
+
+    let name = validate_name(&payload.name)?;
+    if let Some(parent_id) = &payload.parent_id {
+        require_folder(&pool, &space_id, parent_id).await?;
+    }
+
+    let folder = sqlx::query_as!(
+        Folder,
+        r#"
+        INSERT INTO folders (id, space_id, parent_id, name) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (space_id, parent_id, name) DO NOTHING
+        RETURNING id, space_id, parent_id, name, created_at
+        "#,
+        uuid::Uuid::new_v4().to_string(),
+        space_id,
+        payload.parent_id,
+        name
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| name_taken(&name))?;
+
+    events.emit(
+        &space_id,
+        SpaceEvent::FolderCreated {
+            folder: folder.clone(),
+        },
+    );
+
+    Ok(Json::from(folder))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateFolderRequest {
+    name: Option<String>,
+    /// Moves the folder, `null` moves it to the top of the space.
+    #[serde(default, deserialize_with = "double_option")]
+    parent_id: Option<Option<String>>,
+}
+
+#[debug_handler()]
+pub async fn folders_update(
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path((space_id, folder_id)): Path<(String, String)>,
+    Json(payload): Json<UpdateFolderRequest>,
+) -> Result<Json<Folder>, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Editor).await?;
+    require_folder(&pool, &space_id, &folder_id).await?;
+
+    let name = payload.name.as_deref().map(validate_name).transpose()?;
+    if let Some(Some(parent_id)) = &payload.parent_id {
+        require_folder(&pool, &space_id, parent_id).await?;
+
+        // a folder can't end up inside itself
+        let inside = sqlx::query_scalar!(
+            r#"
+            WITH RECURSIVE subtree AS (
+                SELECT id FROM folders WHERE id = $1
+                UNION ALL
+                SELECT folders.id FROM folders JOIN subtree ON folders.parent_id = subtree.id
+            )
+            SELECT EXISTS (SELECT 1 FROM subtree WHERE id = $2) AS "inside!"
+            "#,
+            folder_id,
+            parent_id
+        )
+        .fetch_one(&pool)
+        .await
+        .into_db_error()?;
+        if inside {
+            return Err(AppError::new(
+                ErrorType::Validation("A folder can't be moved into itself".into()),
+                anyhow!("Folder {} would be moved below itself", folder_id),
+            ));
+        }
+    }
+
+    let folder = sqlx::query_as!(
+        Folder,
+        r#"
+        UPDATE folders
+        SET
+            name = COALESCE($3, name),
+            parent_id = CASE WHEN $4 THEN $5 ELSE parent_id END
+        WHERE id = $1 AND space_id = $2
+        RETURNING id, space_id, parent_id, name, created_at
+        "#,
+        folder_id,
+        space_id,
+        name,
+        payload.parent_id.is_some(),
+        payload.parent_id.clone().flatten()
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| match &e {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+            name_taken(name.as_deref().unwrap_or("this"))
+        }
+        _ => AppError::new(ErrorType::Database(e.to_string()), e.into()),
+    })?;
+
+    events.emit(
+        &space_id,
+        SpaceEvent::FolderUpdated {
+            folder: folder.clone(),
+        },
+    );
+
+    Ok(Json::from(folder))
+}
+
+/// Deletes a folder together with its subfolders. The files inside are moved to the trash,
+/// restoring them puts them at the top of the space.
+#[debug_handler()]
+pub async fn folders_delete(
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path((space_id, folder_id)): Path<(String, String)>,
+) -> Result<Json<Folder>, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Editor).await?;
+
+    let mut tx = pool.begin().await.into_db_error()?;
+
+    // NOW() is the start of the transaction, so it only matches the files trashed right here
+    let files = sqlx::query!(
+        r#"
+        WITH RECURSIVE subtree AS (
+            SELECT id FROM folders WHERE id = $1 AND space_id = $2
+            UNION ALL
+            SELECT folders.id FROM folders JOIN subtree ON folders.parent_id = subtree.id
+        )
+        UPDATE files SET folder_id = NULL, deleted_at = COALESCE(deleted_at, NOW())
+        WHERE folder_id IN (SELECT id FROM subtree)
+        RETURNING id, deleted_at = NOW() AS "trashed!"
+        "#,
+        folder_id,
+        space_id
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .into_db_error()?;
+
+    // subfolders go with ON DELETE CASCADE
+    let folder = sqlx::query_as!(
+        Folder,
+        r#"
+        DELETE FROM folders WHERE id = $1 AND space_id = $2
+        RETURNING id, space_id, parent_id, name, created_at
+        "#,
+        folder_id,
+        space_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| folder_not_found(&folder_id))?;
+
+    tx.commit().await.into_db_error()?;
+
+    for file in files.into_iter().filter(|file| file.trashed) {
+        events.emit(&space_id, SpaceEvent::FileDeleted { file_id: file.id });
+    }
+    events.emit(
+        &space_id,
+        SpaceEvent::FolderDeleted {
+            folder_id: folder.id.clone(),
+        },
+    );
+
+    Ok(Json::from(folder))
+}
@@ -0,0 +1,874 @@
+use std::{ops::Range, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{
+    Json,
+    body::Bytes,
+    debug_handler,
+    extract::{Path, State},
+    http::{HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use futures_util::{StreamExt, future};
+use serde::Serialize;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    errors::{AppError, ErrorType, IntoAppError},
+    files::serialize_opt,
+    members::{SpaceRole, SpaceToken, require_access},
+    metadata::{Boxes, MAX_MOOV_BYTES, be_u32},
+    storage::{BlobStore, ByteStream},
+    thumbnails::is_image,
+};
+
+/// Byte ranges of a blob that are overwritten before it is handed out.
+pub(crate) type Redactions = Arc<Vec<Redaction>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Redaction {
+    range: Range<u64>,
+    /// Zeroes for binary structures, spaces for text that has to stay well-formed.
+    fill: u8,
+}
+
+fn zeroed(range: Range<u64>) -> Redaction {
+    Redaction { range, fill: 0 }
+}
+
+// EXIF sits in the first segments of a JPEG, each at most 64KiB
+const JPEG_HEADER_BYTES: u64 = 256 * 1024;
+const XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+// the part of an XMP packet that didn't fit the first segment
+const EXTENDED_XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+const MAX_EXIF_BYTES: u64 = 1024 * 1024;
+
+pub(crate) async fn space_strips_gps(pool: &PgPool, space_id: &str) -> Result<bool, AppError> {
+    sqlx::query_scalar!("SELECT strip_gps FROM spaces WHERE id = $1", space_id)
+        .fetch_one(pool)
+        .await
+        .into_db_error()
+}
+
+#[derive(Serialize)]
+struct Point {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Longitude first, as GeoJSON has it.
+    coordinates: Vec<f64>,
+}
+
+#[derive(Serialize)]
+struct FeatureProperties {
+    file_id: String,
+    original_filename: String,
+    thumbnail_url: Option<String>,
+    #[serde(serialize_with = "serialize_opt")]
+    captured_at: Option<OffsetDateTime>,
+}
+
+#[derive(Serialize)]
+struct Feature {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: String,
+    geometry: Point,
+    properties: FeatureProperties,
+}
+
+#[derive(Serialize)]
+pub struct FeatureCollection {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<Feature>,
+}
+
+/// `map.geojson`: every geotagged file of a space as a point.
+#[debug_handler()]
+pub async fn space_map(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(space_id): Path<String>,
+) -> Result<Response, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let files = if space_strips_gps(&pool, &space_id).await? {
+        Vec::new()
+    } else {
+        sqlx::query!(
+            r#"
+            SELECT files.id, files.original_filename, files.mime_type, file_metadata.captured_at,
+                file_metadata.latitude AS "latitude!", file_metadata.longitude AS "longitude!",
+                file_metadata.altitude
+            FROM files
+            JOIN file_metadata ON file_metadata.checksum = files.checksum
+            WHERE files.space_id = $1 AND files.deleted_at IS NULL
+                AND file_metadata.latitude IS NOT NULL AND file_metadata.longitude IS NOT NULL
+            ORDER BY file_metadata.captured_at NULLS LAST, files.upload_date, files.id
+            "#,
+            space_id
+        )
+        .fetch_all(&pool)
+        .await
+        .into_db_error()?
+    };
+
+    let features = files
+        .into_iter()
+        .map(|f| Feature {
+            kind: "Feature",
+            id: f.id.clone(),
+            geometry: Point {
+                kind: "Point",
+                coordinates: [f.longitude, f.latitude]
+                    .into_iter()
+                    .chain(f.altitude)
+                    .collect(),
+            },
+            properties: FeatureProperties {
+                thumbnail_url: is_image(f.mime_type.as_deref())
+                    .then(|| format!("/api/files/{}/thumbnail", f.id)),
+                file_id: f.id,
+                original_filename: f.original_filename,
+                captured_at: f.captured_at,
+            },
+        })
+        .collect();
+
+    let mut response = Json::from(FeatureCollection {
+        kind: "FeatureCollection",
+        features,
+    })
+    .into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/geo+json"),
+    );
+    Ok(response)
+}
+
+/// Finds the GPS data of blobs in spaces that strip it. Only files whose location was
+/// extracted on upload are looked at, and JPEGs, whose XMP isn't extracted. `None` means
+/// the location can't be removed, such files aren't handed out at all, see [`location_not_removable`].
+pub(crate) async fn gps_redactions(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    checksum: &str,
+    mime_type: Option<&str>,
+    size: u64,
+) -> Result<Option<Redactions>, AppError> {
+    let has_location = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM file_metadata WHERE checksum = $1 AND latitude IS NOT NULL) AS "exists!""#,
+        checksum
+    )
+    .fetch_one(pool)
+    .await
+    .into_db_error()?;
+    let jpeg = mime_type == Some("image/jpeg");
+    if !has_location && !jpeg {
+        return Ok(Some(Redactions::default()));
+    }
+
+    let redactions = match mime_type.unwrap_or_default() {
+        "image/jpeg" => {
+            let header = read_range(store, checksum, 0, size.min(JPEG_HEADER_BYTES)).await?;
+            jpeg_gps(&header)
+        }
+        "image/heic" | "image/heif" => heif_gps(store, checksum, size)
+            .await?
+            .map(|ranges| ranges.into_iter().map(zeroed).collect()),
+        mime if mime.starts_with("video/") => {
+            match find_box(store, checksum, size, b"moov", MAX_MOOV_BYTES).await? {
+                Some((start, len)) => {
+                    let moov = read_range(store, checksum, start, len).await?;
+                    Some(
+                        mp4_gps(&moov, &moov, start)
+                            .into_iter()
+                            .map(zeroed)
+                            .collect(),
+                    )
+                }
+                None => None,
+            }
+        }
+        _ => None,
+    };
+
+    match redactions {
+        Some(redactions) if !redactions.is_empty() => Ok(Some(Arc::new(redactions))),
+        // nothing was extracted and nothing found
+        _ if !has_location => Ok(Some(Redactions::default())),
+        // the location was extracted, so not finding it means it can't be removed
+        _ => Ok(None),
+    }
+}
+
+pub(crate) fn location_not_removable(checksum: &str, mime_type: Option<&str>) -> AppError {
+    AppError::new(
+        ErrorType::Unprocessable(
+            "This space hides locations and the one of this file can't be removed".into(),
+        ),
+        anyhow!(
+            "No way to strip the GPS data of blob {} ({:?})",
+            checksum,
+            mime_type
+        ),
+    )
+}
+
+/// Overwrites the `redactions` in a stream of blob bytes that starts at offset `start`.
+pub(crate) fn redact(stream: ByteStream, start: u64, redactions: Redactions) -> ByteStream {
+    if redactions.is_empty() {
+        return stream;
+    }
+    stream
+        .scan(start, move |position, chunk| {
+            let chunk = chunk.map(|chunk| {
+                let chunk_start = *position;
+                let chunk_end = chunk_start + chunk.len() as u64;
+                *position = chunk_end;
+
+                let mut bytes: Option<Vec<u8>> = None;
+                for Redaction { range, fill } in redactions.iter() {
+                    let from = range.start.max(chunk_start);
+                    let to = range.end.min(chunk_end);
+                    if from < to {
+                        bytes.get_or_insert_with(|| chunk.to_vec())
+                            [(from - chunk_start) as usize..(to - chunk_start) as usize]
+                            .fill(*fill);
+                    }
+                }
+                bytes.map(Bytes::from).unwrap_or(chunk)
+            });
+            future::ready(Some(chunk))
+        })
+        .boxed()
+}
+
+async fn read_range(
+    store: &dyn BlobStore,
+    checksum: &str,
+    start: u64,
+    len: u64,
+) -> Result<Vec<u8>, AppError> {
+    let mut data = Vec::with_capacity(len as usize);
+    let mut stream = store.get_range(checksum, start, len).await?;
+    while let Some(chunk) = stream.next().await {
+        data.extend_from_slice(&chunk.into_internal_error()?);
+    }
+    Ok(data)
+}
+
+/// Walks the top level boxes of an ISO base media file (MP4, MOV, HEIF) in the store and
+/// returns where the body of the first `kind` box starts and how long it is.
+async fn find_box(
+    store: &dyn BlobStore,
+    checksum: &str,
+    size: u64,
+    kind: &[u8; 4],
+    max_len: u64,
+) -> Result<Option<(u64, u64)>, AppError> {
+    let mut at = 0;
+    while at + 8 <= size {
+        let header = read_range(store, checksum, at, (size - at).min(16)).await?;
+        let (header_len, box_len) = match be_u32(&header, 0) {
+            Some(0) => (8, size - at),
+            Some(1) => match header.get(8..16) {
+                Some(len) => (
+                    16,
+                    u64::from_be_bytes(len.try_into().into_internal_error()?),
+                ),
+                None => break,
+            },
+            Some(len) => (8, len as u64),
+            None => break,
+        };
+        // the sizes come from the file, a box reaching past its end (or overflowing) ends the walk
+        let end = match at.checked_add(box_len) {
+            Some(end) if box_len >= header_len && end <= size => end,
+            _ => break,
+        };
+        if &header[4..8] == kind {
+            let body_len = box_len - header_len;
+            return Ok((body_len <= max_len).then_some((at + header_len, body_len)));
+        }
+        at = end;
+    }
+    Ok(None)
+}
+
+fn tiff_type_size(field_type: u16) -> u64 {
+    match field_type {
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+/// The GPS IFD of a TIFF structure (EXIF) and the values it points to, `base` is the
+/// offset of the structure in the blob. Zeroing them leaves a valid, empty IFD.
+fn tiff_gps(tiff: &[u8], base: u64) -> Option<Vec<Range<u64>>> {
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| {
+        let bytes = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let u32_at = |at: usize| {
+        let bytes = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    let ifd0 = u32_at(4)? as usize;
+    let mut gps = None;
+    for i in 0..u16_at(ifd0)? as usize {
+        let entry = ifd0 + 2 + 12 * i;
+        if u16_at(entry)? == 0x8825 {
+            gps = Some(u32_at(entry + 8)? as usize);
+        }
+    }
+    let Some(gps) = gps else {
+        return Some(Vec::new());
+    };
+
+    let count = u16_at(gps)? as usize;
+    let mut ranges = Vec::with_capacity(count + 1);
+    ranges.push(gps as u64..(gps + 2 + 12 * count + 4) as u64);
+    for i in 0..count {
+        let entry = gps + 2 + 12 * i;
+        let len = tiff_type_size(u16_at(entry + 2)?) * u32_at(entry + 4)? as u64;
+        // values of up to 4 bytes are stored in the entry itself
+        if len > 4 {
+            let offset = u32_at(entry + 8)? as u64;
+            ranges.push(offset..offset + len);
+        }
+    }
+
+    let len = tiff.len() as u64;
+    ranges
+        .into_iter()
+        .map(|range| (range.end <= len).then(|| base + range.start..base + range.end))
+        .collect()
+}
+
+/// The GPS IFD of the EXIF segment and the XMP packets holding GPS properties. XMP can put
+/// them in any namespace prefix and in attributes or elements, so such packets are blanked as
+/// a whole, an XMP packet of only whitespace is an empty one.
+fn jpeg_gps(data: &[u8]) -> Option<Vec<Redaction>> {
+    if data.get(0..2)? != [0xff, 0xd8] {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    let mut at = 2;
+    // the metadata segments end where the compressed image data starts
+    while let Some(&[0xff, marker]) = data.get(at..at + 2)
+        && !matches!(marker, 0xda | 0xd9)
+    {
+        let len = u16::from_be_bytes(data.get(at + 2..at + 4)?.try_into().ok()?) as usize;
+        let segment = data.get(at + 4..at + 2 + len)?;
+        if marker == 0xe1
+            && let Some(tiff) = segment.strip_prefix(b"Exif\0\0")
+        {
+            ranges.extend(tiff_gps(tiff, (at + 10) as u64)?.into_iter().map(zeroed));
+        }
+        if marker == 0xe1 {
+            let packet = segment
+                .strip_prefix(XMP_PREFIX)
+                .filter(|packet| packet.windows(3).any(|w| w == b"GPS"))
+                .or_else(|| segment.strip_prefix(EXTENDED_XMP_PREFIX));
+            if let Some(packet) = packet {
+                let start = (at + 4 + segment.len() - packet.len()) as u64;
+                ranges.push(Redaction {
+                    range: start..start + packet.len() as u64,
+                    fill: b' ',
+                });
+            }
+        }
+        at += 2 + len;
+    }
+    Some(ranges)
+}
+
+/// Location boxes of a movie: `©xyz` in user data and the location keys of QuickTime metadata.
+/// `data` is a part of `moov`, which starts at `base` in the blob.
+fn mp4_gps(moov: &[u8], data: &[u8], base: u64) -> Vec<Range<u64>> {
+    let offset = |part: &[u8]| base + (part.as_ptr() as usize - moov.as_ptr() as usize) as u64;
+    let whole = |part: &[u8]| offset(part)..offset(part) + part.len() as u64;
+
+    let mut ranges = Vec::new();
+    for (kind, body) in Boxes(data) {
+        match &kind {
+            b"trak" | b"udta" => ranges.extend(mp4_gps(moov, body, base)),
+            b"\xa9xyz" => ranges.push(whole(body)),
+            b"meta" => {
+                let body = if body.get(0..4) == Some(&[0, 0, 0, 0]) {
+                    &body[4..]
+                } else {
+                    body
+                };
+                let mut keys = Vec::new();
+                for (kind, body) in Boxes(body) {
+                    if &kind == b"keys" {
+                        let mut at = 8;
+                        while let Some(size) = be_u32(body, at) {
+                            let Some(key) = body.get(at + 8..at + size as usize) else {
+                                break;
+                            };
+                            keys.push(key.starts_with(b"com.apple.quicktime.location"));
+                            at += size.max(8) as usize;
+                        }
+                    }
+                }
+                for (kind, body) in Boxes(body) {
+                    if &kind != b"ilst" {
+                        continue;
+                    }
+                    for (item_kind, item) in Boxes(body) {
+                        let index = u32::from_be_bytes(item_kind) as usize;
+                        let location = index
+                            .checked_sub(1)
+                            .and_then(|i| keys.get(i))
+                            .is_some_and(|location| *location);
+                        if location || &item_kind == b"\xa9xyz" {
+                            ranges.extend(
+                                Boxes(item)
+                                    .filter(|(kind, _)| kind == b"data")
+                                    .filter_map(|(_, data)| data.get(8..))
+                                    .map(whole),
+                            );
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    ranges
+}
+
+/// HEIF (iPhone photos) keeps EXIF as an item, `iinf` names its id and `iloc` says where it is.
+fn heif_exif_location(meta: &[u8]) -> Option<(u64, u64)> {
+    // meta is a full box
+    let meta = meta.get(4..)?;
+    let mut exif_id = None;
+    let mut locations = Vec::new();
+    for (kind, body) in Boxes(meta) {
+        match &kind {
+            b"iinf" => {
+                let version = *body.first()?;
+                let entries = if version == 0 { 6 } else { 8 };
+                for (kind, infe) in Boxes(body.get(entries..)?) {
+                    let version = *infe.first()?;
+                    if &kind != b"infe" || version < 2 {
+                        continue;
+                    }
+                    let (id, item_type) = if version == 2 {
+                        (
+                            u16::from_be_bytes(infe.get(4..6)?.try_into().ok()?) as u32,
+                            infe.get(8..12)?,
+                        )
+                    } else {
+                        (be_u32(infe, 4)?, infe.get(10..14)?)
+                    };
+                    if item_type == b"Exif" {
+                        exif_id = Some(id);
+                    }
+                }
+            }
+            b"iloc" => {
+                let version = *body.first()?;
+                let offset_size = (body.get(4)? >> 4) as usize;
+                let length_size = (body.get(4)? & 0xf) as usize;
+                let base_offset_size = (body.get(5)? >> 4) as usize;
+                let index_size = if version > 0 {
+                    (body.get(5)? & 0xf) as usize
+                } else {
+                    0
+                };
+                let sized = |at: usize, size: usize| -> Option<u64> {
+                    body.get(at..at + size)?
+                        .iter()
+                        .try_fold(0u64, |n, b| Some((n << 8) | *b as u64))
+                };
+
+                let id_size = if version < 2 { 2 } else { 4 };
+                let count = sized(6, id_size)?;
+                let mut at = 6 + id_size;
+                for _ in 0..count {
+                    let id = sized(at, id_size)? as u32;
+                    at += id_size;
+                    let construction_method = if version > 0 {
+                        at += 2;
+                        sized(at - 2, 2)? & 0xf
+                    } else {
+                        0
+                    };
+                    at += 2; // data reference index
+                    let base_offset = sized(at, base_offset_size)?;
+                    at += base_offset_size;
+                    let extents = sized(at, 2)? as usize;
+                    at += 2;
+                    // only items stored as a single extent in the file itself are supported,
+                    // the others are skipped without reading them, their extents may have no width
+                    if extents == 1 && construction_method == 0 {
+                        let offset = sized(at + index_size, offset_size)?;
+                        let length = sized(at + index_size + offset_size, length_size)?;
+                        if let Some(start) = base_offset.checked_add(offset) {
+                            locations.push((id, start, length));
+                        }
+                    }
+                    at += extents * (index_size + offset_size + length_size);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let exif_id = exif_id?;
+    locations
+        .into_iter()
+        .find(|(id, _, _)| *id == exif_id)
+        .map(|(_, offset, length)| (offset, length))
+}
+
+async fn heif_gps(
+    store: &dyn BlobStore,
+    checksum: &str,
+    size: u64,
+) -> Result<Option<Vec<Range<u64>>>, AppError> {
+    let Some((start, len)) = find_box(store, checksum, size, b"meta", MAX_MOOV_BYTES).await? else {
+        return Ok(None);
+    };
+    let meta = read_range(store, checksum, start, len).await?;
+    let Some((offset, length)) = heif_exif_location(&meta) else {
+        return Ok(None);
+    };
+    if length > MAX_EXIF_BYTES || offset.checked_add(length).is_none_or(|end| end > size) {
+        return Ok(None);
+    }
+
+    let exif = read_range(store, checksum, offset, length).await?;
+    // the item starts with the offset of the TIFF header, usually past an "Exif\0\0" prefix
+    let Some(tiff_offset) = be_u32(&exif, 0).map(|o| 4 + o as usize) else {
+        return Ok(None);
+    };
+    Ok(exif
+        .get(tiff_offset..)
+        .and_then(|tiff| tiff_gps(tiff, offset + tiff_offset as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+    use crate::storage::LocalStore;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// A little-endian TIFF structure with a GPS IFD holding an inline latitude ref
+    /// and a latitude stored past the IFD.
+    fn tiff() -> Vec<u8> {
+        let mut tiff = b"II\x2a\x00".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        // IFD0 at 8: the GPS pointer, then no next IFD
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&0x8825u16.to_le_bytes());
+        tiff.extend_from_slice(&4u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&26u32.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        // GPS IFD at 26: two entries, ends at 26 + 2 + 24 + 4 = 56
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend_from_slice(&2u32.to_le_bytes());
+        tiff.extend_from_slice(b"N\0\0\0");
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend_from_slice(&5u16.to_le_bytes());
+        tiff.extend_from_slice(&3u32.to_le_bytes());
+        tiff.extend_from_slice(&56u32.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        // three rationals
+        tiff.extend_from_slice(&[7; 24]);
+        tiff
+    }
+
+    fn jpeg(segments: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8];
+        for (marker, body) in segments {
+            data.extend_from_slice(&[0xff, *marker]);
+            data.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+            data.extend_from_slice(body);
+        }
+        data.extend_from_slice(&[0xff, 0xda, 0, 2]);
+        data
+    }
+
+    #[test]
+    fn tiff_gps_ranges() {
+        assert_eq!(tiff_gps(&tiff(), 100), Some(vec![126..156, 156..180]));
+
+        // without a GPS pointer there is nothing to remove
+        let mut no_gps = tiff();
+        no_gps[10..12].copy_from_slice(&0x0110u16.to_le_bytes());
+        assert_eq!(tiff_gps(&no_gps, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn truncated_tiff() {
+        let tiff = tiff();
+        // the IFDs cut off, or the latitude value reaching past the end
+        for len in [0, 12, 30, 60] {
+            assert_eq!(tiff_gps(&tiff[..len], 0), None, "{}", len);
+        }
+        let mut pointer_out = tiff.clone();
+        pointer_out[18..22].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(tiff_gps(&pointer_out, 0), None);
+        let mut huge_count = tiff.clone();
+        huge_count[44..48].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(tiff_gps(&huge_count, 0), None);
+    }
+
+    #[test]
+    fn jpeg_exif_segments() {
+        let exif = [&b"Exif\0\0"[..], &tiff()].concat();
+        let data = jpeg(&[(0xe0, b"JFIF\0"), (0xe1, &exif)]);
+        // SOI, the 7 byte APP0 segment and the APP1 header with "Exif\0\0" come first
+        let base = 2 + 9 + 4 + 6;
+        assert_eq!(
+            jpeg_gps(&data),
+            Some(vec![
+                zeroed(base + 26..base + 56),
+                zeroed(base + 56..base + 80)
+            ])
+        );
+
+        assert_eq!(jpeg_gps(b"\x89PNG"), None);
+        assert_eq!(jpeg_gps(&jpeg(&[(0xe0, b"JFIF\0")])), Some(vec![]));
+        // a segment longer than the data
+        assert_eq!(jpeg_gps(&data[..data.len() - 30]), None);
+        // a length shorter than the length field itself
+        assert_eq!(jpeg_gps(&[0xff, 0xd8, 0xff, 0xe1, 0, 1, 0, 0]), None);
+    }
+
+    #[test]
+    fn jpeg_xmp_segments() {
+        let xmp = |packet: &str| [XMP_PREFIX, packet.as_bytes()].concat();
+        let located =
+            xmp(r#"<rdf:Description exif:GPSLatitude="51,30.4N" exif:GPSLongitude="0,7.5W"/>"#);
+        let data = jpeg(&[(0xe1, &located)]);
+        let start = (2 + 4 + XMP_PREFIX.len()) as u64;
+        let redactions = jpeg_gps(&data).unwrap();
+        assert_eq!(
+            redactions,
+            [Redaction {
+                range: start..start + (located.len() - XMP_PREFIX.len()) as u64,
+                fill: b' ',
+            }]
+        );
+        let mut stripped = data.clone();
+        stripped[redactions[0].range.start as usize..redactions[0].range.end as usize].fill(b' ');
+        assert!(!stripped.windows(3).any(|w| w == b"GPS"));
+        assert!(stripped.ends_with(&[0xff, 0xda, 0, 2]));
+
+        // XMP without a location stays
+        let rating = xmp(r#"<rdf:Description xmp:Rating="5"/>"#);
+        assert_eq!(jpeg_gps(&jpeg(&[(0xe1, &rating)])), Some(vec![]));
+    }
+
+    #[test]
+    fn movie_locations() {
+        let mut xyz = vec![0, 18, 0x15, 0xc7];
+        xyz.extend_from_slice(b"+37.3323-122.0312/");
+        let udta = mp4_box(b"udta", &mp4_box(b"\xa9xyz", &xyz));
+
+        let mut keys = vec![0, 0, 0, 0, 0, 0, 0, 2];
+        for key in [
+            "com.apple.quicktime.make",
+            "com.apple.quicktime.location.ISO6709",
+        ] {
+            keys.extend_from_slice(&((key.len() + 8) as u32).to_be_bytes());
+            keys.extend_from_slice(b"mdta");
+            keys.extend_from_slice(key.as_bytes());
+        }
+        let item = |index: u32, value: &[u8]| {
+            let data = mp4_box(b"data", &[&[0, 0, 0, 1, 0, 0, 0, 0], value].concat());
+            mp4_box(&index.to_be_bytes(), &data)
+        };
+        let ilst = [item(1, b"Apple"), item(2, b"+37.3323-122.0312/")].concat();
+        let meta = mp4_box(
+            b"meta",
+            &[vec![0; 4], mp4_box(b"keys", &keys), mp4_box(b"ilst", &ilst)].concat(),
+        );
+        let moov = [udta.clone(), meta.clone()].concat();
+
+        let ranges = mp4_gps(&moov, &moov, 1000);
+        let xyz_start = 1000 + 8 + 8;
+        let value_end = 1000 + moov.len() as u64;
+        assert_eq!(
+            ranges,
+            [
+                xyz_start..xyz_start + xyz.len() as u64,
+                value_end - 18..value_end
+            ]
+        );
+
+        // truncated boxes are skipped, nothing panics
+        for len in 0..moov.len() {
+            mp4_gps(&moov[..len], &moov[..len], 0);
+        }
+    }
+
+    /// A HEIF `meta` box body with one Exif item at `offset`.
+    fn heif_meta(offset: u32, length: u32) -> Vec<u8> {
+        let infe = mp4_box(
+            b"infe",
+            &[
+                &[2, 0, 0, 0][..],
+                &7u16.to_be_bytes(),
+                &[0, 0],
+                b"Exif",
+                b"\0",
+            ]
+            .concat(),
+        );
+        let iinf = mp4_box(b"iinf", &[&[0, 0, 0, 0, 0, 1][..], &infe].concat());
+        let iloc = mp4_box(
+            b"iloc",
+            &[
+                &[0, 0, 0, 0, 0x44, 0x00][..],
+                &1u16.to_be_bytes(),
+                &7u16.to_be_bytes(),
+                &[0, 0],
+                &1u16.to_be_bytes(),
+                &offset.to_be_bytes(),
+                &length.to_be_bytes(),
+            ]
+            .concat(),
+        );
+        [vec![0; 4], iinf, iloc].concat()
+    }
+
+    #[test]
+    fn heif_exif_items() {
+        assert_eq!(heif_exif_location(&heif_meta(4096, 300)), Some((4096, 300)));
+
+        let meta = heif_meta(4096, 300);
+        for len in 0..meta.len() {
+            assert_eq!(heif_exif_location(&meta[..len]), None, "{}", len);
+        }
+    }
+
+    #[test]
+    fn heif_extents_without_width() {
+        // every field of the extents is 0 bytes wide, each item claims the most extents it can
+        let item = [&7u16.to_be_bytes()[..], &[0, 0], &u16::MAX.to_be_bytes()].concat();
+        let items = 10_000u16;
+        let iloc = mp4_box(
+            b"iloc",
+            &[
+                &[0, 0, 0, 0, 0x00, 0x00][..],
+                &items.to_be_bytes(),
+                &item.repeat(items as usize),
+            ]
+            .concat(),
+        );
+        let started = std::time::Instant::now();
+        assert_eq!(heif_exif_location(&[vec![0; 4], iloc].concat()), None);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    async fn stored(data: &[u8]) -> (LocalStore, std::path::PathBuf) {
+        let root = std::env::temp_dir().join(format!("spaces-geo-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let store = LocalStore::new(&root);
+        let source = root.join(".blob");
+        std::fs::write(&source, data).unwrap();
+        store.put("blob", &source).await.unwrap();
+        (store, root)
+    }
+
+    #[tokio::test]
+    async fn finding_boxes() {
+        let mut data = mp4_box(b"ftyp", b"heic");
+        data.extend_from_slice(&mp4_box(b"free", &[0; 100]));
+        data.extend_from_slice(&mp4_box(b"meta", &[1; 50]));
+        let (store, root) = stored(&data).await;
+        let size = data.len() as u64;
+
+        assert_eq!(
+            find_box(&store, "blob", size, b"meta", 1000).await.unwrap(),
+            Some((128, 50))
+        );
+        // too big to be read
+        assert_eq!(
+            find_box(&store, "blob", size, b"meta", 49).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            find_box(&store, "blob", size, b"moov", 1000).await.unwrap(),
+            None
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // boxes reaching past the end, or not even covering their header, end the walk
+        for size_field in [u32::MAX, 4] {
+            let mut data = mp4_box(b"ftyp", b"heic");
+            data.extend_from_slice(&size_field.to_be_bytes());
+            data.extend_from_slice(b"free");
+            data.extend_from_slice(&mp4_box(b"meta", &[1; 50]));
+            let (store, root) = stored(&data).await;
+            assert_eq!(
+                find_box(&store, "blob", data.len() as u64, b"meta", 1000)
+                    .await
+                    .unwrap(),
+                None
+            );
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn redacting_across_chunks() {
+        let chunks: Vec<std::io::Result<Bytes>> = vec![
+            Ok(Bytes::from_static(b"abcd")),
+            Ok(Bytes::from_static(b"efgh")),
+            Ok(Bytes::from_static(b"ijkl")),
+        ];
+        // the stream starts at offset 10 of the blob
+        let redactions = Arc::new(vec![
+            zeroed(12..15),
+            Redaction {
+                range: 20..21,
+                fill: b' ',
+            },
+        ]);
+        let redacted: Vec<u8> = redact(stream::iter(chunks).boxed(), 10, redactions)
+            .map(|chunk| chunk.unwrap().to_vec())
+            .concat()
+            .await;
+        assert_eq!(redacted, b"ab\0\0\0fghij l");
+    }
+}
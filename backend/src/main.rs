@@ -1,96 +1,226 @@
-use std::path::Path;
+use std::sync::Arc;
 
-use anyhow::Context;
+use anyhow::anyhow;
 use axum::{
     Router,
-    extract::DefaultBodyLimit,
-    http::HeaderValue,
-    routing::{delete, get},
+    extract::{DefaultBodyLimit, Request},
+    http::{HeaderName, Method, header},
+    middleware::{self, Next},
+    response::IntoResponse,
+    routing::{delete, get, head, patch, post},
 };
+use clap::Parser;
 
+mod archive;
+mod auth;
+mod blobs;
+mod cli;
+mod config;
 mod errors;
+mod events;
 mod files;
+mod folders;
+mod geo;
+mod maintenance;
+mod members;
+mod metadata;
+mod pagination;
+mod quotas;
+mod ranges;
+mod s3;
+mod search;
+mod shares;
 mod spaces;
+mod storage;
+mod thumbnails;
+mod timeline;
+mod trash;
+mod uploads;
+mod versions;
 
-use sqlx::PgPool;
+use sqlx::{PgPool, postgres::PgPoolOptions};
 
-use files::{files_delete, space_files_get, space_files_post};
-use spaces::{spaces_delete, spaces_get, spaces_get_one, spaces_post, spaces_update};
-use tower_http::cors::{Any, CorsLayer};
+use files::{
+    files_delete, files_get_one, files_restore, files_trash_delete, files_update, space_files_get,
+    space_files_post, space_trash_get,
+};
+use spaces::{
+    hash_legacy_access_codes, spaces_delete, spaces_get, spaces_get_one, spaces_post,
+    spaces_restore, spaces_trash_delete, spaces_trash_get, spaces_unlock, spaces_update,
+};
+use tower::ServiceExt;
+use tower_http::cors::{AllowHeaders, AllowMethods, CorsLayer};
 
 use crate::{
+    archive::{space_archive_get, space_archive_post},
+    auth::{AuthConfig, auth_login, auth_logout, auth_me, auth_register},
+    cli::{Cli, Command},
+    config::Config,
     errors::{AppError, ErrorType, IntoAppError, init_logging},
+    events::{SpaceEvents, space_events},
     files::files_download,
+    folders::{folders_delete, folders_get, folders_post, folders_update},
+    geo::space_map,
+    maintenance::{Check, Maintenance, maintenance_get, maintenance_run},
+    members::{members_delete, members_get, members_post, members_update},
+    quotas::QuotaConfig,
+    search::search,
+    shares::{share_file_download, share_open, shares_delete, shares_get, shares_post},
+    storage::{BlobStore, blob_store},
+    thumbnails::{Thumbnailer, files_thumbnail},
+    timeline::space_timeline,
+    trash::{SpacePurger, spawn_purge},
+    uploads::{
+        spawn_expiry, uploads_delete, uploads_head, uploads_options, uploads_patch, uploads_post,
+    },
+    versions::{file_version_download, file_version_restore, file_versions_get},
 };
 
 #[derive(Clone)]
 struct AppState {
     pool: PgPool,
+    // staging area for uploads in progress (and the blob directory of the local store)
     upload_path: String,
+    upload_limit: usize,
+    store: Arc<dyn BlobStore>,
+    auth: AuthConfig,
+    events: SpaceEvents,
+    thumbnails: Thumbnailer,
+    trash_retention: time::Duration,
+    purger: SpacePurger,
+    maintenance: Maintenance,
+    quotas: QuotaConfig,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), AppError> {
+async fn main() {
     dotenvy::dotenv().ok();
-    init_logging();
-    let upload_limit: usize = 1024 * 2 * 10_usize.pow(8);
-    let allowed_origins: Vec<HeaderValue> = std::env::var("ALLOWED_ORIGINS")
-        .map_err(|e| {
-            AppError::new(
-                ErrorType::Configuration("ALLOWED_ORIGINS required.".into()),
-                e.into(),
-            )
-        })?
-        .split(",")
-        .map(|origin| {
-            let trimmed = origin.trim();
-            trimmed
-                .parse::<HeaderValue>()
-                .context(format!(
-                    "Failed to parse origin {} as valid HTTP header value",
-                    trimmed
-                ))
-                .map_err(|e| {
-                    AppError::new(ErrorType::Configuration("CORS Origin invalid.".into()), e)
-                })
-        })
-        .collect::<Result<Vec<_>, _>>()?;
-    let database_url = std::env::var("DATABASE_URL")
-        .context("DATABASE_URL not set")
-        .map_err(|e| {
-            AppError::new(
-                ErrorType::Configuration("DATABASE_URL must be set".into()),
-                e,
-            )
-        })?;
-    let upload_path = std::env::var("UPLOAD_PATH")
-        .context("UPLOAD_PATH not set")
-        .map_err(|e| {
-            AppError::new(
-                ErrorType::Configuration("UPLOAD_PATH must be set".into()),
-                e,
-            )
-        })?;
-    let upload_path_exists = Path::new(&upload_path).exists();
-    if !upload_path_exists {
-        panic!("The specified upload path doesnt exist!")
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    let config = match Config::load(&cli.config, matches!(command, Command::Serve)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    init_logging(&config.log);
+
+    if let Err(e) = run(config, command).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(config: Config, command: Command) -> Result<(), AppError> {
+    match command {
+        Command::Serve => serve(config).await,
+        Command::Migrate { dry_run } => cli::migrate(&connect(&config).await?, dry_run).await,
+        Command::Gc { remove_orphans } => {
+            let remove_orphans = remove_orphans || config.maintenance.remove_orphans;
+            cli::maintenance(maintenance(&config).await?, Check::Gc, remove_orphans).await?;
+            Ok(())
+        }
+        Command::Verify { quarantine } => {
+            let quarantine = quarantine || config.maintenance.quarantine;
+            let maintenance = maintenance(&config).await?;
+            let report = cli::maintenance(maintenance, Check::Scrub, quarantine).await?;
+            if !report.findings().is_empty() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Space(command) => {
+            let pool = connect(&config).await?;
+            let store = blob_store(&config.storage, &config.uploads.path)?;
+            let purger = SpacePurger::new(pool.clone(), store, config.uploads.path);
+            cli::space(&pool, purger, command).await
+        }
+        Command::User(command) => cli::user(&connect(&config).await?, command).await,
     }
+}
 
-    let pool = PgPool::connect(&database_url).await.into_db_error()?;
+async fn connect(config: &Config) -> Result<PgPool, AppError> {
+    PgPoolOptions::new()
+        .max_connections(config.database.max_connections)
+        .connect(&config.database.url)
+        .await
+        .into_db_error()
+}
+
+async fn maintenance(config: &Config) -> Result<Maintenance, AppError> {
+    Ok(Maintenance::new(
+        connect(config).await?,
+        blob_store(&config.storage, &config.uploads.path)?,
+        config.maintenance,
+    ))
+}
+
+async fn serve(config: Config) -> Result<(), AppError> {
+    let upload_limit = config.uploads.max_bytes as usize;
+    let allowed_origins = config
+        .server
+        .origins()
+        .map_err(|e| AppError::new(ErrorType::Configuration(e), anyhow!("Invalid origin")))?;
+    let upload_path = config.uploads.path.clone();
+
+    let store = blob_store(&config.storage, &upload_path)?;
+
+    let pool = connect(&config).await?;
 
     sqlx::migrate!("./migrations")
         .run(&pool)
         .await
         .into_db_error()?;
-    let state = AppState { pool, upload_path };
+    hash_legacy_access_codes(&pool).await?;
 
+    let trash_retention = config.trash.retention();
+    let purger = SpacePurger::new(pool.clone(), store.clone(), upload_path.clone());
+    purger.start();
+    spawn_purge(pool.clone(), store.clone(), purger.clone(), trash_retention);
+    spawn_expiry(pool.clone(), upload_path.clone());
+
+    let maintenance = Maintenance::new(pool.clone(), store.clone(), config.maintenance);
+    maintenance.start();
+
+    let thumbnails = Thumbnailer::new(pool.clone(), store.clone(), upload_path.clone());
+    let state = AppState {
+        pool,
+        upload_path,
+        upload_limit,
+        store,
+        auth: config.auth,
+        events: SpaceEvents::new(),
+        thumbnails,
+        trash_retention,
+        purger,
+        maintenance,
+        quotas: config.quotas,
+    };
+
+    // credentials (the session cookie) can't be combined with wildcards, so mirror the request
     let cors = CorsLayer::new()
-        .allow_methods(Any)
-        .allow_headers(Any)
-        .allow_origin(allowed_origins);
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request())
+        .allow_origin(allowed_origins)
+        .allow_credentials(true)
+        // tus clients need to read these
+        .expose_headers([
+            HeaderName::from_static("location"),
+            HeaderName::from_static("upload-offset"),
+            HeaderName::from_static("upload-length"),
+            HeaderName::from_static("upload-expires"),
+            HeaderName::from_static("tus-resumable"),
+            HeaderName::from_static("tus-version"),
+            HeaderName::from_static("tus-max-size"),
+            HeaderName::from_static("tus-extension"),
+        ]);
 
     let router_spaces = Router::new()
         .route("/", get(spaces_get).post(spaces_post))
+        .route("/trash", get(spaces_trash_get))
+        .route("/trash/{space_id}", delete(spaces_trash_delete))
         .route(
             "/{space_id}",
             get(spaces_get_one)
@@ -101,23 +231,105 @@ async fn main() -> Result<(), AppError> {
             "/{space_id}/files",
             get(space_files_get)
                 .post(space_files_post)
-                // 200MB upload limit
                 .layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/{space_id}/restore", post(spaces_restore))
+        .route("/{space_id}/trash", get(space_trash_get))
+        .route("/{space_id}/unlock", post(spaces_unlock))
+        .route("/{space_id}/events", get(space_events))
+        .route("/{space_id}/timeline", get(space_timeline))
+        .route("/{space_id}/map.geojson", get(space_map))
+        .route(
+            "/{space_id}/archive.zip",
+            get(space_archive_get).post(space_archive_post),
+        )
+        .route("/{space_id}/folders", get(folders_get).post(folders_post))
+        .route(
+            "/{space_id}/folders/{folder_id}",
+            patch(folders_update).delete(folders_delete),
+        )
+        .route("/{space_id}/members", get(members_get).post(members_post))
+        .route(
+            "/{space_id}/members/{user_id}",
+            patch(members_update).delete(members_delete),
+        )
+        .route("/{space_id}/shares", get(shares_get).post(shares_post))
+        .route("/{space_id}/shares/{share_id}", delete(shares_delete))
+        .route(
+            "/{space_id}/uploads",
+            post(uploads_post).options(uploads_options),
+        )
+        .route(
+            "/{space_id}/uploads/{upload_id}",
+            head(uploads_head)
+                .patch(uploads_patch)
+                .delete(uploads_delete),
         );
 
     let router_files = Router::new()
         .route("/{file_id}/download", get(files_download))
-        .route("/{file_id}", delete(files_delete));
+        .route("/{file_id}/thumbnail", get(files_thumbnail))
+        .route("/{file_id}/restore", post(files_restore))
+        .route("/{file_id}/trash", delete(files_trash_delete))
+        .route("/{file_id}/versions", get(file_versions_get))
+        .route(
+            "/{file_id}/versions/{version}/download",
+            get(file_version_download),
+        )
+        .route(
+            "/{file_id}/versions/{version}/restore",
+            post(file_version_restore),
+        )
+        .route(
+            "/{file_id}",
+            get(files_get_one).patch(files_update).delete(files_delete),
+        );
 
-    let app = Router::new()
+    let router_auth = Router::new()
+        .route("/register", post(auth_register))
+        .route("/login", post(auth_login))
+        .route("/logout", post(auth_logout))
+        .route("/me", get(auth_me));
+
+    let router_admin = Router::new()
+        .route("/maintenance", get(maintenance_get))
+        .route("/maintenance/{check}", post(maintenance_run));
+
+    let routes = Router::new()
         .route("/health", get(|| async { "spaces up and running!" }))
+        // share links are opened without an account
+        // POST for sending the password in the body
+        .route("/s/{token}", get(share_open).post(share_open))
+        .route(
+            "/s/{token}/files/{file_id}",
+            get(share_file_download).post(share_file_download),
+        )
+        .nest("/api/auth", router_auth)
         .nest("/api/spaces", router_spaces)
         .nest("/api/files", router_files)
-        .layer(cors)
+        .nest("/api/admin", router_admin)
+        .route("/api/search", get(search))
         .with_state(state);
+    // the CORS layer answers every OPTIONS request as a preflight, but tus clients also send plain
+    // ones (without Access-Control-Request-Method) to find out what the server supports
+    let app = routes.clone().layer(cors).layer(middleware::from_fn(
+        move |request: Request, next: Next| {
+            let routes = routes.clone();
+            async move {
+                if request.method() == Method::OPTIONS
+                    && !request
+                        .headers()
+                        .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+                {
+                    routes.oneshot(request).await.into_response()
+                } else {
+                    next.run(request).await
+                }
+            }
+        },
+    ));
 
-    let address = "0.0.0.0:6570";
-    let listener = tokio::net::TcpListener::bind(address)
+    let listener = tokio::net::TcpListener::bind(config.server.address())
         .await
         .into_internal_error()?;
     axum::serve(listener, app).await.into_internal_error()?;
@@ -0,0 +1,545 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use axum::{
+    Json, debug_handler,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    AppState,
+    auth::{AuthUser, require_admin},
+    blobs::purge_blob,
+    errors::{AppError, ErrorType, IntoAppError},
+    storage::BlobStore,
+    thumbnails::blob_of_key,
+};
+
+const DEFAULT_GC_INTERVAL_HOURS: u64 = 24;
+const DEFAULT_SCRUB_INTERVAL_HOURS: u64 = 24 * 7;
+// ten years, longer ones overflow the timer
+pub const MAX_INTERVAL_HOURS: u64 = 24 * 365 * 10;
+// an object without a blob row may belong to an upload that hasn't committed yet
+const ORPHAN_GRACE: Duration = Duration::hours(1);
+
+/// When the checks run on their own, 0 hours turns a schedule off.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    pub gc_interval_hours: u64,
+    pub scrub_interval_hours: u64,
+    /// Whether the scrubber moves corrupt blobs out of the way.
+    pub quarantine: bool,
+    /// Whether the garbage collector deletes orphaned objects instead of only reporting them.
+    /// Every unknown object in the upload directory or under the S3 prefix counts as orphaned.
+    pub remove_orphans: bool,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            gc_interval_hours: DEFAULT_GC_INTERVAL_HOURS,
+            scrub_interval_hours: DEFAULT_SCRUB_INTERVAL_HOURS,
+            quarantine: false,
+            remove_orphans: false,
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    /// Whether scheduled runs of `check` may change the store, see [`Maintenance::run`].
+    pub fn repair(&self, check: Check) -> bool {
+        match check {
+            Check::Gc => self.remove_orphans,
+            Check::Scrub => self.quarantine,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    /// Reconciles the blob store with the blobs table.
+    Gc,
+    /// Re-hashes every blob.
+    Scrub,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Finding {
+    /// An object nothing in the database knows about. With `remove_orphans` it is removed
+    /// once it is older than the grace period.
+    OrphanedObject {
+        key: String,
+        size_bytes: u64,
+        removed: bool,
+    },
+    /// A blob gone from the store while files still point at it.
+    MissingBlob {
+        checksum: String,
+        file_ids: Vec<String>,
+    },
+    /// A blob whose refcount didn't match the files and versions using it, it has been corrected.
+    RefcountMismatch {
+        checksum: String,
+        recorded: i64,
+        actual: i64,
+    },
+    /// A blob whose content doesn't hash to its checksum anymore.
+    CorruptBlob {
+        checksum: String,
+        actual_checksum: String,
+        file_ids: Vec<String>,
+        quarantined: bool,
+    },
+}
+
+impl Finding {
+    fn log(&self) {
+        match self {
+            Finding::OrphanedObject {
+                key,
+                size_bytes,
+                removed,
+            } => tracing::warn!(
+                finding = "orphaned_object",
+                key,
+                size_bytes,
+                removed,
+                "Object without a blob in the store"
+            ),
+            Finding::MissingBlob { checksum, file_ids } => tracing::warn!(
+                finding = "missing_blob",
+                checksum,
+                file_ids = ?file_ids,
+                "Blob missing from the store"
+            ),
+            Finding::RefcountMismatch {
+                checksum,
+                recorded,
+                actual,
+            } => tracing::warn!(
+                finding = "refcount_mismatch",
+                checksum,
+                recorded,
+                actual,
+                "Blob refcount corrected"
+            ),
+            Finding::CorruptBlob {
+                checksum,
+                actual_checksum,
+                file_ids,
+                quarantined,
+            } => tracing::warn!(
+                finding = "corrupt_blob",
+                checksum,
+                actual_checksum,
+                file_ids = ?file_ids,
+                quarantined,
+                "Blob content doesn't match its checksum"
+            ),
+        }
+    }
+}
+
+/// The outcome of one run of a check.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    check: Check,
+    #[serde(with = "time::serde::rfc3339")]
+    started_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    finished_at: OffsetDateTime,
+    blobs_checked: u64,
+    findings: Vec<Finding>,
+    /// Set when the run stopped early, the findings up to there are kept.
+    error: Option<String>,
+}
+
+impl Report {
+    fn new(check: Check) -> Self {
+        let now = OffsetDateTime::now_utc();
+        Self {
+            check,
+            started_at: now,
+            finished_at: now,
+            blobs_checked: 0,
+            findings: Vec::new(),
+            error: None,
+        }
+    }
+
+    fn add(&mut self, finding: Finding) {
+        finding.log();
+        self.findings.push(finding);
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+#[derive(Default, Clone, Serialize)]
+pub struct CheckStatus {
+    running: bool,
+    last_report: Option<Report>,
+}
+
+#[derive(Default, Clone, Serialize)]
+pub struct MaintenanceStatus {
+    gc: CheckStatus,
+    scrub: CheckStatus,
+}
+
+impl MaintenanceStatus {
+    fn check(&mut self, check: Check) -> &mut CheckStatus {
+        match check {
+            Check::Gc => &mut self.gc,
+            Check::Scrub => &mut self.scrub,
+        }
+    }
+}
+
+/// Keeps the blob store and the blobs table in line: the garbage collector removes objects
+/// nothing references and corrects refcounts, the scrubber finds blobs that rotted on disk.
+/// Each check runs on its own schedule or on demand, never twice at the same time.
+#[derive(Clone)]
+pub struct Maintenance {
+    pool: PgPool,
+    store: Arc<dyn BlobStore>,
+    config: MaintenanceConfig,
+    status: Arc<Mutex<MaintenanceStatus>>,
+}
+
+impl Maintenance {
+    pub fn new(pool: PgPool, store: Arc<dyn BlobStore>, config: MaintenanceConfig) -> Self {
+        Self {
+            pool,
+            store,
+            config,
+            status: Arc::default(),
+        }
+    }
+
+    /// Starts the schedules, the first run of each check is one interval after startup.
+    pub fn start(&self) {
+        for (check, hours) in [
+            (Check::Gc, self.config.gc_interval_hours),
+            (Check::Scrub, self.config.scrub_interval_hours),
+        ] {
+            if hours == 0 {
+                continue;
+            }
+            let maintenance = self.clone();
+            let period = std::time::Duration::from_secs(hours * 60 * 60);
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    if maintenance
+                        .spawn(check, maintenance.config.repair(check))
+                        .is_err()
+                    {
+                        tracing::info!("Skipping scheduled {:?}, it is still running", check);
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Runs `check` in the background, a conflict if it is already running.
+    pub fn spawn(&self, check: Check, repair: bool) -> Result<(), AppError> {
+        self.begin(check)?;
+        let maintenance = self.clone();
+        tokio::spawn(async move { maintenance.execute(check, repair).await });
+        Ok(())
+    }
+
+    /// Runs `check` to the end and returns its report, a conflict if it is already running.
+    /// `repair` lets the garbage collector remove orphaned objects and the scrubber
+    /// quarantine corrupt blobs, without it they are only reported.
+    pub async fn run(&self, check: Check, repair: bool) -> Result<Report, AppError> {
+        self.begin(check)?;
+        Ok(self.execute(check, repair).await)
+    }
+
+    fn begin(&self, check: Check) -> Result<(), AppError> {
+        let mut status = self.status.lock().unwrap();
+        let check_status = status.check(check);
+        if check_status.running {
+            return Err(AppError::new(
+                ErrorType::Conflict("This check is already running".into()),
+                anyhow!("{:?} already running", check),
+            ));
+        }
+        check_status.running = true;
+        Ok(())
+    }
+
+    async fn execute(&self, check: Check, repair: bool) -> Report {
+        let mut report = Report::new(check);
+        tracing::info!("Running {:?}", check);
+
+        let result = match check {
+            Check::Gc => self.collect_garbage(&mut report, repair).await,
+            Check::Scrub => self.scrub(&mut report, repair).await,
+        };
+        if let Err(e) = result {
+            tracing::warn!("{:?} stopped early: {:?}", check, e);
+            report.error = Some(e.to_string());
+        }
+        report.finished_at = OffsetDateTime::now_utc();
+        tracing::info!(
+            blobs_checked = report.blobs_checked,
+            findings = report.findings.len(),
+            "{:?} finished",
+            check
+        );
+
+        let mut status = self.status.lock().unwrap();
+        let check_status = status.check(check);
+        check_status.running = false;
+        check_status.last_report = Some(report.clone());
+        report
+    }
+
+    async fn file_ids(&self, checksum: &str) -> Result<Vec<String>, AppError> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT id AS "id!" FROM files WHERE checksum = $1
+            UNION
+            SELECT file_id FROM file_versions WHERE checksum = $1
+            ORDER BY 1
+            "#,
+            checksum
+        )
+        .fetch_all(&self.pool)
+        .await
+        .into_db_error()
+    }
+
+    async fn collect_garbage(
+        &self,
+        report: &mut Report,
+        remove_orphans: bool,
+    ) -> Result<(), AppError> {
+        // listed before the blobs, a blob added in between is in the table but maybe not in the listing
+        let objects = self.store.list().await?;
+        let blobs = sqlx::query!("SELECT checksum, refcount, created_at FROM blobs")
+            .fetch_all(&self.pool)
+            .await
+            .into_db_error()?;
+        report.blobs_checked = blobs.len() as u64;
+
+        let known: HashSet<&str> = blobs.iter().map(|blob| blob.checksum.as_str()).collect();
+        let stored: HashSet<&str> = objects.iter().map(|object| object.key.as_str()).collect();
+
+        for object in &objects {
+            let checksum = blob_of_key(&object.key);
+            if known.contains(checksum) {
+                continue;
+            }
+            let removed = remove_orphans
+                && object.modified < report.started_at - ORPHAN_GRACE
+                && self.remove_orphan(checksum, object.size_bytes).await?;
+            report.add(Finding::OrphanedObject {
+                key: object.key.clone(),
+                size_bytes: object.size_bytes,
+                removed,
+            });
+        }
+
+        for blob in &blobs {
+            if blob.refcount > 0
+                && blob.created_at < report.started_at
+                && !stored.contains(blob.checksum.as_str())
+                // it may have been purged since the listing
+                && !self.store.exists(&blob.checksum).await?
+            {
+                report.add(Finding::MissingBlob {
+                    checksum: blob.checksum.clone(),
+                    file_ids: self.file_ids(&blob.checksum).await?,
+                });
+            }
+        }
+
+        let drifted = sqlx::query_scalar!(
+            r#"
+            SELECT checksum FROM blobs
+            WHERE refcount <> (SELECT COUNT(*) FROM files WHERE files.checksum = blobs.checksum)
+                + (SELECT COUNT(*) FROM file_versions WHERE file_versions.checksum = blobs.checksum)
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .into_db_error()?;
+        for checksum in drifted {
+            self.fix_refcount(report, &checksum).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Claims an object nothing references with a blob row of its own and purges it like any
+    /// other blob. An upload of the same content that got there first keeps it.
+    async fn remove_orphan(&self, checksum: &str, size_bytes: u64) -> Result<bool, AppError> {
+        let claimed = sqlx::query!(
+            r#"
+            INSERT INTO blobs (checksum, size_bytes, refcount) VALUES ($1, $2, 0)
+            ON CONFLICT (checksum) DO NOTHING
+            "#,
+            checksum,
+            size_bytes as i64
+        )
+        .execute(&self.pool)
+        .await
+        .into_db_error()?;
+        if claimed.rows_affected() == 0 {
+            return Ok(false);
+        }
+        purge_blob(&self.pool, self.store.as_ref(), checksum).await
+    }
+
+    async fn fix_refcount(&self, report: &mut Report, checksum: &str) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await.into_db_error()?;
+
+        // uploads and deletes of the blob wait until it is recounted
+        let Some(recorded) = sqlx::query_scalar!(
+            "SELECT refcount FROM blobs WHERE checksum = $1 FOR UPDATE",
+            checksum
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .into_db_error()?
+        else {
+            return Ok(());
+        };
+        let actual = sqlx::query_scalar!(
+            r#"
+            SELECT ((SELECT COUNT(*) FROM files WHERE checksum = $1)
+                + (SELECT COUNT(*) FROM file_versions WHERE checksum = $1))::BIGINT AS "actual!"
+            "#,
+            checksum
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .into_db_error()?;
+        if actual == recorded {
+            return Ok(());
+        }
+
+        sqlx::query!(
+            "UPDATE blobs SET refcount = $2 WHERE checksum = $1",
+            checksum,
+            actual
+        )
+        .execute(&mut *tx)
+        .await
+        .into_db_error()?;
+        tx.commit().await.into_db_error()?;
+
+        report.add(Finding::RefcountMismatch {
+            checksum: checksum.to_string(),
+            recorded,
+            actual,
+        });
+        if actual == 0 {
+            purge_blob(&self.pool, self.store.as_ref(), checksum).await?;
+        }
+        Ok(())
+    }
+
+    async fn scrub(&self, report: &mut Report, quarantine: bool) -> Result<(), AppError> {
+        let checksums =
+            sqlx::query_scalar!("SELECT checksum FROM blobs WHERE refcount > 0 ORDER BY checksum")
+                .fetch_all(&self.pool)
+                .await
+                .into_db_error()?;
+
+        for checksum in checksums {
+            // missing blobs are the garbage collector's to report
+            let Ok(mut stream) = self.store.get(&checksum).await else {
+                continue;
+            };
+            let mut hasher = Sha256::new();
+            while let Some(chunk) = stream.next().await {
+                hasher.update(chunk.into_internal_error()?);
+            }
+            report.blobs_checked += 1;
+
+            let actual_checksum = format!("{:x}", hasher.finalize());
+            if actual_checksum == checksum {
+                continue;
+            }
+            if quarantine {
+                self.store.quarantine(&checksum).await?;
+            }
+            report.add(Finding::CorruptBlob {
+                file_ids: self.file_ids(&checksum).await?,
+                checksum,
+                actual_checksum,
+                quarantined: quarantine,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The last report of each check and whether it is running.
+#[debug_handler()]
+pub async fn maintenance_get(
+    State(AppState {
+        auth, maintenance, ..
+    }): State<AppState>,
+    user: AuthUser,
+) -> Result<Json<MaintenanceStatus>, AppError> {
+    require_admin(&auth, &user)?;
+    Ok(Json::from(maintenance.status()))
+}
+
+#[derive(Deserialize)]
+pub struct RunQuery {
+    /// Overrides `maintenance.quarantine` for this run of the scrubber.
+    quarantine: Option<bool>,
+    /// Overrides `maintenance.remove_orphans` for this run of the garbage collector.
+    remove_orphans: Option<bool>,
+}
+
+/// Starts a check right away, the report shows up in [`maintenance_get`] once it is done.
+#[debug_handler()]
+pub async fn maintenance_run(
+    State(AppState {
+        auth, maintenance, ..
+    }): State<AppState>,
+    user: AuthUser,
+    Path(check): Path<Check>,
+    Query(query): Query<RunQuery>,
+) -> Result<(StatusCode, Json<MaintenanceStatus>), AppError> {
+    require_admin(&auth, &user)?;
+    let repair = match check {
+        Check::Gc => query.remove_orphans,
+        Check::Scrub => query.quarantine,
+    };
+    maintenance.spawn(check, repair.unwrap_or(maintenance.config.repair(check)))?;
+    Ok((StatusCode::ACCEPTED, Json::from(maintenance.status())))
+}
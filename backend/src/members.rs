@@ -0,0 +1,402 @@
+use anyhow::anyhow;
+use axum::{
+    Json, debug_handler,
+    extract::{FromRequestParts, Path, State},
+    http::{header, request::Parts},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+use crate::{
+    AppState,
+    auth::{AuthUser, hash_token},
+    errors::{AppError, ErrorType, IntoAppError},
+};
+
+/// What a member may do in a space. Every role includes the permissions of the ones before it:
+/// viewers list and download, uploaders add files, editors delete files, owners manage the space.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[sqlx(type_name = "space_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SpaceRole {
+    Viewer,
+    Uploader,
+    Editor,
+    Owner,
+}
+
+#[derive(Serialize)]
+pub struct SpaceMember {
+    user_id: String,
+    username: String,
+    role: SpaceRole,
+    #[serde(with = "time::serde::rfc3339")]
+    added_at: OffsetDateTime,
+}
+
+/// Checks that `user` is a member of `space_id` with at least the role `min`.
+/// Returns the member's actual role.
+pub async fn require_role(
+    pool: &PgPool,
+    space_id: &str,
+    user: &AuthUser,
+    min: SpaceRole,
+) -> Result<SpaceRole, AppError> {
+    let rec = sqlx::query!(
+        r#"
+        SELECT spaces.id, space_members.role AS "role?: SpaceRole" FROM spaces
+        LEFT JOIN space_members ON space_members.space_id = spaces.id AND space_members.user_id = $2
+        WHERE spaces.id = $1 AND spaces.deleted_at IS NULL
+        "#,
+        space_id,
+        user.id
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Space not found".into()),
+            anyhow!("Couldn't find requested Space"),
+        )
+    })?;
+
+    match rec.role {
+        Some(role) if role >= min => Ok(role),
+        _ => Err(AppError::new(
+            ErrorType::Authorization(format!("This requires the {:?} role in the space", min)),
+            anyhow!(
+                "User {} has role {:?} in space {}, needs {:?}",
+                user.id,
+                rec.role,
+                space_id,
+                min
+            ),
+        )),
+    }
+}
+
+async fn space_of_file(pool: &PgPool, file_id: &str) -> Result<String, AppError> {
+    let rec = sqlx::query!("SELECT space_id FROM files WHERE id = $1", file_id)
+        .fetch_optional(pool)
+        .await
+        .into_db_error()?
+        .ok_or_else(|| {
+            AppError::new(
+                ErrorType::Validation("File not found".into()),
+                anyhow!("Requested file not stored in database"),
+            )
+        })?;
+
+    Ok(rec.space_id)
+}
+
+/// Like [`require_role`] for a file, returns the id of the space the file belongs to.
+pub async fn require_file_role(
+    pool: &PgPool,
+    file_id: &str,
+    user: &AuthUser,
+    min: SpaceRole,
+) -> Result<String, AppError> {
+    let space_id = space_of_file(pool, file_id).await?;
+    require_role(pool, &space_id, user, min).await?;
+
+    Ok(space_id)
+}
+
+/// Token handed out by `POST /api/spaces/{space_id}/unlock`. Sent as the `X-Space-Token` header,
+/// or the `space_token` query parameter when opening a WebSocket, browsers can't set headers there.
+/// Query parameters end up in logs and browser history, so other requests have to use the header.
+#[derive(Clone)]
+pub struct SpaceToken(Option<String>);
+
+impl FromRequestParts<AppState> for SpaceToken {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &AppState) -> Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get("X-Space-Token")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let websocket = parts
+            .headers
+            .get(header::UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+        let query = || {
+            if !websocket {
+                return None;
+            }
+            parts.uri.query()?.split('&').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                (name == "space_token").then(|| value.to_string())
+            })
+        };
+
+        Ok(Self(header.or_else(query)))
+    }
+}
+
+/// Checks that `user` may act on `space_id` with at least the role `min`.
+/// Members are checked by their role. Everyone else is a guest: a valid [`SpaceToken`] makes them
+/// an uploader of a space with an access code, public spaces without one can be viewed by anyone.
+pub async fn require_access(
+    pool: &PgPool,
+    space_id: &str,
+    user: &AuthUser,
+    token: &SpaceToken,
+    min: SpaceRole,
+) -> Result<(), AppError> {
+    let rec = sqlx::query!(
+        r#"
+        SELECT spaces.is_public, spaces.has_access_code, space_members.role AS "role?: SpaceRole" FROM spaces
+        LEFT JOIN space_members ON space_members.space_id = spaces.id AND space_members.user_id = $2
+        WHERE spaces.id = $1 AND spaces.deleted_at IS NULL
+        "#,
+        space_id,
+        user.id
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Space not found".into()),
+            anyhow!("Couldn't find requested Space"),
+        )
+    })?;
+
+    if let Some(role) = rec.role
+        && role >= min
+    {
+        return Ok(());
+    }
+
+    if rec.has_access_code {
+        let unlocked = match &token.0 {
+            Some(token) => sqlx::query!(
+                r#"
+                SELECT 1 AS "found!" FROM space_tokens
+                WHERE token_hash = $1 AND space_id = $2 AND user_id = $3 AND expires_at > NOW()
+                "#,
+                hash_token(token),
+                space_id,
+                user.id
+            )
+            .fetch_optional(pool)
+            .await
+            .into_db_error()?
+            .is_some(),
+            None => false,
+        };
+        if !unlocked && rec.role.is_none() {
+            return Err(AppError::new(
+                ErrorType::Authorization(
+                    "This space is locked, unlock it with its access code".into(),
+                ),
+                anyhow!(
+                    "User {} sent no valid token for space {}",
+                    user.id,
+                    space_id
+                ),
+            ));
+        }
+        if unlocked && SpaceRole::Uploader >= min {
+            return Ok(());
+        }
+    } else if rec.is_public && SpaceRole::Viewer >= min {
+        return Ok(());
+    }
+
+    Err(AppError::new(
+        ErrorType::Authorization(format!("This requires the {:?} role in the space", min)),
+        anyhow!(
+            "User {} has role {:?} in space {}, needs {:?}",
+            user.id,
+            rec.role,
+            space_id,
+            min
+        ),
+    ))
+}
+
+/// Like [`require_access`] for a file, returns the id of the space the file belongs to.
+pub async fn require_file_access(
+    pool: &PgPool,
+    file_id: &str,
+    user: &AuthUser,
+    token: &SpaceToken,
+    min: SpaceRole,
+) -> Result<String, AppError> {
+    let space_id = space_of_file(pool, file_id).await?;
+    require_access(pool, &space_id, user, token, min).await?;
+
+    Ok(space_id)
+}
+
+/// Refuses to demote or remove `user_id` if they are the last owner of the space.
+async fn ensure_not_last_owner(
+    pool: &PgPool,
+    space_id: &str,
+    user_id: &str,
+) -> Result<(), AppError> {
+    let rec = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(*) FILTER (WHERE role = 'owner' AND user_id = $2) AS "is_owner!",
+            COUNT(*) FILTER (WHERE role = 'owner' AND user_id <> $2) AS "other_owners!"
+        FROM space_members WHERE space_id = $1
+        "#,
+        space_id,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .into_db_error()?;
+
+    if rec.is_owner > 0 && rec.other_owners == 0 {
+        return Err(AppError::new(
+            ErrorType::Conflict("A space needs at least one owner".into()),
+            anyhow!("Refused to remove the last owner of space {}", space_id),
+        ));
+    }
+    Ok(())
+}
+
+#[debug_handler()]
+pub async fn members_get(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    Path(space_id): Path<String>,
+) -> Result<Json<Vec<SpaceMember>>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Viewer).await?;
+
+    let members = sqlx::query_as!(
+        SpaceMember,
+        r#"
+        SELECT space_members.user_id, users.username, space_members.role AS "role: SpaceRole", space_members.added_at
+        FROM space_members JOIN users ON users.id = space_members.user_id
+        WHERE space_members.space_id = $1
+        ORDER BY space_members.added_at
+        "#,
+        space_id
+    )
+    .fetch_all(&pool)
+    .await
+    .into_db_error()?;
+
+    Ok(Json::from(members))
+}
+
+#[derive(Deserialize)]
+pub struct AddMemberRequest {
+    username: String,
+    role: SpaceRole,
+}
+
+#[debug_handler()]
+pub async fn members_post(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    Path(space_id): Path<String>,
+    Json(payload): Json<AddMemberRequest>,
+) -> Result<Json<SpaceMember>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Owner).await?;
+
+    let member = sqlx::query_as!(
+        SpaceMember,
+        r#"
+        INSERT INTO space_members (space_id, user_id, role)
+        SELECT $1, users.id, $3 FROM users WHERE users.username = $2
+        ON CONFLICT (space_id, user_id) DO NOTHING
+        RETURNING user_id, $2 AS "username!", role AS "role: SpaceRole", added_at
+        "#,
+        space_id,
+        payload.username,
+        payload.role as SpaceRole
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::Conflict("User doesn't exist or is already a member".into()),
+            anyhow!("Couldn't add {} to space {}", payload.username, space_id),
+        )
+    })?;
+
+    Ok(Json::from(member))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateMemberRequest {
+    role: SpaceRole,
+}
+
+#[debug_handler()]
+pub async fn members_update(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    Path((space_id, user_id)): Path<(String, String)>,
+    Json(payload): Json<UpdateMemberRequest>,
+) -> Result<Json<SpaceMember>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Owner).await?;
+    if payload.role != SpaceRole::Owner {
+        ensure_not_last_owner(&pool, &space_id, &user_id).await?;
+    }
+
+    let member = sqlx::query_as!(
+        SpaceMember,
+        r#"
+        UPDATE space_members SET role = $3
+        FROM users
+        WHERE space_members.space_id = $1 AND space_members.user_id = $2 AND users.id = space_members.user_id
+        RETURNING space_members.user_id, users.username, space_members.role AS "role: SpaceRole", space_members.added_at
+        "#,
+        space_id,
+        user_id,
+        payload.role as SpaceRole
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Member not found".into()),
+            anyhow!("User {} is not a member of space {}", user_id, space_id),
+        )
+    })?;
+
+    Ok(Json::from(member))
+}
+
+#[debug_handler()]
+pub async fn members_delete(
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
+    Path((space_id, user_id)): Path<(String, String)>,
+) -> Result<Json<Option<String>>, AppError> {
+    // everyone may leave a space, removing others is up to the owners
+    if user_id == user.id {
+        require_role(&pool, &space_id, &user, SpaceRole::Viewer).await?;
+    } else {
+        require_role(&pool, &space_id, &user, SpaceRole::Owner).await?;
+    }
+    ensure_not_last_owner(&pool, &space_id, &user_id).await?;
+
+    let rec = sqlx::query!(
+        "DELETE FROM space_members WHERE space_id = $1 AND user_id = $2 RETURNING user_id",
+        space_id,
+        user_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?;
+    events.access_changed(&space_id);
+
+    Ok(Json::from(rec.map(|rec| rec.user_id)))
+}
@@ -0,0 +1,732 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use exif::{In, Tag, Value};
+use image::ImageReader;
+use lofty::prelude::*;
+use serde::Serialize;
+use sqlx::{PgPool, PgTransaction};
+use time::{
+    Date, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset,
+    format_description::well_known::{Iso8601, Rfc3339},
+};
+
+use crate::{
+    errors::{AppError, IntoAppError},
+    files::serialize_opt,
+};
+
+// the movie header of a video is small, anything bigger is not worth reading into memory
+pub(crate) const MAX_MOOV_BYTES: u64 = 16 * 1024 * 1024;
+
+/// What could be read from the content of a file. Every field is optional, most files only have a few.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct FileMetadata {
+    #[serde(skip)]
+    pub(crate) checksum: String,
+    #[serde(serialize_with = "serialize_opt")]
+    pub captured_at: Option<OffsetDateTime>,
+    /// `captured_at` in local time where it was taken, `captured_at` is UTC if the zone is unknown.
+    #[serde(skip)]
+    pub(crate) captured_local: Option<PrimitiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub orientation: Option<i16>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f64>,
+    pub duration_seconds: Option<f64>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+impl FileMetadata {
+    /// Sets the capture time from a clock reading in the local time zone, `captured_at`
+    /// keeps its offset (UTC if unknown), `captured_local` the wall-clock time.
+    fn set_captured(&mut self, captured_at: OffsetDateTime) {
+        self.captured_at = Some(captured_at);
+        self.captured_local = Some(PrimitiveDateTime::new(
+            captured_at.date(),
+            captured_at.time(),
+        ));
+    }
+
+    /// Forgets where the file was taken, for spaces that keep that private.
+    pub fn strip_location(&mut self) {
+        self.latitude = None;
+        self.longitude = None;
+        self.altitude = None;
+    }
+}
+
+/// Reads the metadata of the file at `path` (a staged upload) on the blocking thread pool.
+/// Content that can't be parsed simply yields no metadata.
+pub async fn extract_metadata(
+    path: PathBuf,
+    checksum: &str,
+    mime_type: Option<String>,
+) -> Result<FileMetadata, AppError> {
+    let mut metadata = tokio::task::spawn_blocking(move || {
+        match mime_type.as_deref().and_then(|mime| mime.split('/').next()) {
+            Some("image") => image_metadata(&path),
+            Some("video") => video_metadata(&path).unwrap_or_default(),
+            Some("audio") => audio_metadata(&path),
+            _ => FileMetadata::default(),
+        }
+    })
+    .await
+    .into_internal_error()?;
+
+    metadata.checksum = checksum.to_string();
+    Ok(metadata)
+}
+
+pub(crate) async fn insert_metadata(
+    tx: &mut PgTransaction<'_>,
+    metadata: &FileMetadata,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO file_metadata (
+            checksum, captured_at, captured_local, camera_make, camera_model, width, height,
+            orientation, latitude, longitude, altitude, duration_seconds, title, artist, album
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+        ON CONFLICT (checksum) DO NOTHING
+        "#,
+        metadata.checksum,
+        metadata.captured_at,
+        metadata.captured_local,
+        metadata.camera_make,
+        metadata.camera_model,
+        metadata.width,
+        metadata.height,
+        metadata.orientation,
+        metadata.latitude,
+        metadata.longitude,
+        metadata.altitude,
+        metadata.duration_seconds,
+        metadata.title,
+        metadata.artist,
+        metadata.album
+    )
+    .execute(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    Ok(())
+}
+
+/// Looks up the metadata of the given blobs, keyed by checksum.
+pub(crate) async fn metadata_for(
+    pool: &PgPool,
+    checksums: &[String],
+) -> Result<HashMap<String, FileMetadata>, AppError> {
+    let rows = sqlx::query_as!(
+        FileMetadata,
+        r#"
+        SELECT checksum, captured_at, captured_local, camera_make, camera_model, width, height,
+            orientation, latitude, longitude, altitude, duration_seconds, title, artist, album
+        FROM file_metadata WHERE checksum = ANY($1)
+        "#,
+        checksums
+    )
+    .fetch_all(pool)
+    .await
+    .into_db_error()?;
+
+    Ok(rows
+        .into_iter()
+        .map(|metadata| (metadata.checksum.clone(), metadata))
+        .collect())
+}
+
+fn exif_datetime(value: &Value, offset: Option<&Value>) -> Option<OffsetDateTime> {
+    let Value::Ascii(ascii) = value else {
+        return None;
+    };
+    let mut dt = exif::DateTime::from_ascii(ascii.first()?).ok()?;
+    if let Some(Value::Ascii(offset)) = offset
+        && let Some(offset) = offset.first()
+    {
+        let _ = dt.parse_offset(offset);
+    }
+
+    let date =
+        Date::from_calendar_date(dt.year as i32, Month::try_from(dt.month).ok()?, dt.day).ok()?;
+    let time = Time::from_hms(dt.hour, dt.minute, dt.second).ok()?;
+    let offset = UtcOffset::from_whole_seconds(dt.offset.unwrap_or(0) as i32 * 60).ok()?;
+    Some(PrimitiveDateTime::new(date, time).assume_offset(offset))
+}
+
+/// Degrees, minutes and seconds to decimal degrees, negative for the south and west.
+fn exif_coordinate(value: &Value, reference: Option<&Value>, negative: u8) -> Option<f64> {
+    let Value::Rational(parts) = value else {
+        return None;
+    };
+    let degrees = parts.first()?.to_f64()
+        + parts.get(1).map_or(0.0, |r| r.to_f64() / 60.0)
+        + parts.get(2).map_or(0.0, |r| r.to_f64() / 3600.0);
+    let negate = matches!(reference, Some(Value::Ascii(r)) if r.first().and_then(|r| r.first()) == Some(&negative));
+    Some(if negate { -degrees } else { degrees }).filter(|d| d.is_finite())
+}
+
+fn image_metadata(path: &Path) -> FileMetadata {
+    let mut metadata = FileMetadata::default();
+
+    if let Ok((width, height)) = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(image::ImageError::from)
+        .and_then(|reader| reader.into_dimensions())
+    {
+        metadata.width = Some(width as i32);
+        metadata.height = Some(height as i32);
+    }
+
+    let Ok(file) = File::open(path) else {
+        return metadata;
+    };
+    let Ok(exif) = exif::Reader::new().read_from_container(&mut BufReader::new(file)) else {
+        return metadata;
+    };
+    let field = |tag: Tag| exif.get_field(tag, In::PRIMARY).map(|field| &field.value);
+    let text = |tag: Tag| match field(tag)? {
+        Value::Ascii(ascii) => ascii
+            .first()
+            .map(|text| String::from_utf8_lossy(text).trim().to_string())
+            .filter(|text| !text.is_empty()),
+        _ => None,
+    };
+
+    metadata.camera_make = text(Tag::Make);
+    metadata.camera_model = text(Tag::Model);
+    metadata.orientation = field(Tag::Orientation)
+        .and_then(|v| v.get_uint(0))
+        .map(|v| v as i16);
+    if metadata.width.is_none() {
+        // formats the image crate can't decode (HEIC) still carry the dimensions in EXIF
+        metadata.width = field(Tag::PixelXDimension)
+            .and_then(|v| v.get_uint(0))
+            .map(|v| v as i32);
+        metadata.height = field(Tag::PixelYDimension)
+            .and_then(|v| v.get_uint(0))
+            .map(|v| v as i32);
+    }
+    // orientations 5-8 rotate by 90 degrees, report the size as the image is displayed
+    if matches!(metadata.orientation, Some(5..=8)) {
+        std::mem::swap(&mut metadata.width, &mut metadata.height);
+    }
+
+    if let Some(captured_at) = field(Tag::DateTimeOriginal)
+        .and_then(|v| exif_datetime(v, field(Tag::OffsetTimeOriginal)))
+        .or_else(|| field(Tag::DateTime).and_then(|v| exif_datetime(v, field(Tag::OffsetTime))))
+    {
+        metadata.set_captured(captured_at);
+    }
+
+    let latitude =
+        field(Tag::GPSLatitude).and_then(|v| exif_coordinate(v, field(Tag::GPSLatitudeRef), b'S'));
+    let longitude = field(Tag::GPSLongitude)
+        .and_then(|v| exif_coordinate(v, field(Tag::GPSLongitudeRef), b'W'));
+    // cameras without a fix sometimes write 0/0
+    if let (Some(latitude), Some(longitude)) = (latitude, longitude)
+        && (latitude, longitude) != (0.0, 0.0)
+    {
+        metadata.latitude = Some(latitude);
+        metadata.longitude = Some(longitude);
+        metadata.altitude = match field(Tag::GPSAltitude) {
+            Some(Value::Rational(altitude)) => altitude.first().map(|altitude| {
+                let below_sea_level = matches!(field(Tag::GPSAltitudeRef), Some(Value::Byte(r)) if r.first() == Some(&1));
+                if below_sea_level {
+                    -altitude.to_f64()
+                } else {
+                    altitude.to_f64()
+                }
+            }),
+            _ => None,
+        };
+    }
+
+    metadata
+}
+
+fn audio_metadata(path: &Path) -> FileMetadata {
+    let mut metadata = FileMetadata::default();
+    let Ok(tagged) = lofty::read_from_path(path) else {
+        return metadata;
+    };
+
+    let duration = tagged.properties().duration().as_secs_f64();
+    metadata.duration_seconds = Some(duration).filter(|d| *d > 0.0);
+    if let Some(tag) = tagged.primary_tag().or_else(|| tagged.first_tag()) {
+        metadata.title = tag.title().map(|v| v.to_string());
+        metadata.artist = tag.artist().map(|v| v.to_string());
+        metadata.album = tag.album().map(|v| v.to_string());
+    }
+
+    metadata
+}
+
+/// Iterates the boxes ("atoms") of an ISO base media file (MP4, MOV) held in memory.
+pub(crate) struct Boxes<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.0;
+        let size = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+        let kind: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 => (
+                16,
+                usize::try_from(u64::from_be_bytes(data.get(8..16)?.try_into().ok()?)).ok()?,
+            ),
+            size => (8, size),
+        };
+        let body = data.get(header..size)?;
+        self.0 = &data[size..];
+        Some((kind, body))
+    }
+}
+
+pub(crate) fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(at..at.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        data.get(at..at.checked_add(8)?)?.try_into().ok()?,
+    ))
+}
+
+/// Locations as written by phones: "+37.3323-122.0312+010.000/" (ISO 6709, decimal degrees).
+fn parse_iso6709(value: &str, metadata: &mut FileMetadata) {
+    let mut numbers = Vec::new();
+    let mut current = String::new();
+    for c in value.trim().chars() {
+        if matches!(c, '+' | '-' | '/') && !current.is_empty() {
+            numbers.push(current.clone());
+            current.clear();
+        }
+        if c != '/' {
+            current.push(c);
+        }
+    }
+    let numbers: Vec<f64> = numbers.iter().filter_map(|n| n.parse().ok()).collect();
+    if let [latitude, longitude, rest @ ..] = &numbers[..] {
+        metadata.latitude = Some(*latitude);
+        metadata.longitude = Some(*longitude);
+        metadata.altitude = rest.first().copied();
+    }
+}
+
+/// The `meta` box QuickTime (iPhone) videos use, values in `ilst` are indexes into `keys`.
+fn quicktime_meta(data: &[u8], metadata: &mut FileMetadata) {
+    // in MP4 files `meta` is a full box with version and flags first
+    let data = if data.get(0..4) == Some(&[0, 0, 0, 0]) {
+        &data[4..]
+    } else {
+        data
+    };
+
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for (kind, body) in Boxes(data) {
+        match &kind {
+            b"keys" => {
+                let mut at = 8;
+                while let Some(size) = be_u32(body, at) {
+                    let Some(key) = body.get(at + 8..at + size as usize) else {
+                        break;
+                    };
+                    keys.push(String::from_utf8_lossy(key).to_string());
+                    at += size.max(8) as usize;
+                }
+            }
+            b"ilst" => {
+                for (index, item) in Boxes(body) {
+                    let index = u32::from_be_bytes(index) as usize;
+                    if let Some((_, data)) = Boxes(item).find(|(kind, _)| kind == b"data")
+                        && let Some(value) = data.get(8..)
+                    {
+                        values.push((index, String::from_utf8_lossy(value).to_string()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (index, value) in values {
+        let Some(key) = index.checked_sub(1).and_then(|i| keys.get(i)) else {
+            continue;
+        };
+        match key.as_str() {
+            "com.apple.quicktime.location.ISO6709" => parse_iso6709(&value, metadata),
+            "com.apple.quicktime.make" => metadata.camera_make = Some(value),
+            "com.apple.quicktime.model" => metadata.camera_model = Some(value),
+            // unlike mvhd this one has the local time zone
+            "com.apple.quicktime.creationdate" => {
+                if let Ok(captured_at) = OffsetDateTime::parse(&value, &Rfc3339)
+                    .or_else(|_| OffsetDateTime::parse(&value, &Iso8601::DEFAULT))
+                {
+                    metadata.set_captured(captured_at);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn moov_metadata(moov: &[u8], metadata: &mut FileMetadata) {
+    // QuickTime timestamps count from 1904
+    let epoch_1904 = Date::from_calendar_date(1904, Month::January, 1)
+        .map(|date| date.midnight().assume_utc())
+        .ok();
+
+    for (kind, body) in Boxes(moov) {
+        match &kind {
+            b"mvhd" => {
+                let (creation, timescale, duration) = if body.first() == Some(&1) {
+                    (be_u64(body, 4), be_u32(body, 20), be_u64(body, 24))
+                } else {
+                    (
+                        be_u32(body, 4).map(u64::from),
+                        be_u32(body, 12),
+                        be_u32(body, 16).map(u64::from),
+                    )
+                };
+                // UTC, so there is no local time to go with it
+                if metadata.captured_at.is_none()
+                    && let (Some(epoch), Some(creation)) = (epoch_1904, creation.filter(|c| *c > 0))
+                {
+                    // crafted files can name a time no date type holds
+                    metadata.captured_at = i64::try_from(creation)
+                        .ok()
+                        .and_then(|creation| epoch.checked_add(time::Duration::seconds(creation)));
+                }
+                if let (Some(timescale), Some(duration)) = (timescale.filter(|t| *t > 0), duration)
+                {
+                    metadata.duration_seconds = Some(duration as f64 / timescale as f64);
+                }
+            }
+            b"trak" if metadata.width.is_none() => {
+                if let Some((_, tkhd)) = Boxes(body).find(|(kind, _)| kind == b"tkhd") {
+                    // width and height (16.16 fixed point) follow the times, the volume and the matrix
+                    let at = if tkhd.first() == Some(&1) { 36 } else { 24 } + 52;
+                    let width = be_u32(tkhd, at).map(|w| (w >> 16) as i32);
+                    let height = be_u32(tkhd, at + 4).map(|h| (h >> 16) as i32);
+                    // audio tracks have no size
+                    if width.is_some_and(|w| w > 0) {
+                        metadata.width = width;
+                        metadata.height = height;
+                    }
+                }
+            }
+            b"udta" => {
+                for (kind, body) in Boxes(body) {
+                    if &kind == b"\xa9xyz" {
+                        // 2 bytes length and 2 bytes language before the text
+                        if let Some(value) = body.get(4..) {
+                            parse_iso6709(&String::from_utf8_lossy(value), metadata);
+                        }
+                    }
+                }
+            }
+            b"meta" => quicktime_meta(body, metadata),
+            _ => {}
+        }
+    }
+}
+
+fn video_metadata(path: &Path) -> Option<FileMetadata> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let mut metadata = FileMetadata::default();
+
+    // only the top level boxes are walked on disk, the media data in between is skipped
+    let mut at = 0;
+    while at + 8 <= len {
+        file.seek(SeekFrom::Start(at)).ok()?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8]).ok()?;
+        let (header_len, size) = match u32::from_be_bytes(header[0..4].try_into().ok()?) {
+            0 => (8, len - at),
+            1 => {
+                file.read_exact(&mut header[8..16]).ok()?;
+                (16, u64::from_be_bytes(header[8..16].try_into().ok()?))
+            }
+            size => (8, size as u64),
+        };
+        if size < header_len {
+            break;
+        }
+
+        if &header[4..8] == b"moov" {
+            if size > MAX_MOOV_BYTES {
+                break;
+            }
+            let mut moov = vec![0u8; (size - header_len) as usize];
+            file.read_exact(&mut moov).ok()?;
+            moov_metadata(&moov, &mut metadata);
+            break;
+        }
+        // sizes come from the file, a box claiming to reach past its end stops the walk
+        match at.checked_add(size) {
+            Some(end) if end <= len => at = end,
+            _ => break,
+        }
+    }
+
+    Some(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::{datetime, offset};
+
+    use super::*;
+
+    // seconds from 1904 to 2024-05-17 14:30 UTC
+    const CREATION_1904: u32 = 3_798_801_000;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn mvhd() -> Vec<u8> {
+        let mut body = vec![0; 4];
+        body.extend_from_slice(&CREATION_1904.to_be_bytes());
+        body.extend_from_slice(&CREATION_1904.to_be_bytes());
+        body.extend_from_slice(&600u32.to_be_bytes());
+        body.extend_from_slice(&(600u32 * 90).to_be_bytes());
+        mp4_box(b"mvhd", &body)
+    }
+
+    fn kinds(data: &[u8]) -> Vec<[u8; 4]> {
+        Boxes(data).map(|(kind, _)| kind).collect()
+    }
+
+    #[test]
+    fn boxes() {
+        let mut data = mp4_box(b"ftyp", b"isom");
+        // 64-bit size
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"wide");
+        data.extend_from_slice(&20u64.to_be_bytes());
+        data.extend_from_slice(b"abcd");
+        // size 0 reaches to the end
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(b"rest");
+
+        let boxes: Vec<_> = Boxes(&data).collect();
+        assert_eq!(
+            boxes,
+            [
+                (*b"ftyp", &b"isom"[..]),
+                (*b"wide", &b"abcd"[..]),
+                (*b"mdat", &b"rest"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_and_oversized_boxes() {
+        let mut data = mp4_box(b"free", b"");
+        // claims more than there is
+        data.extend_from_slice(&100u32.to_be_bytes());
+        data.extend_from_slice(b"moov");
+        data.extend_from_slice(&[0; 10]);
+        assert_eq!(kinds(&data), [*b"free"]);
+
+        // shorter than its own header
+        assert!(kinds(&[0, 0, 0, 4, b'f', b'r', b'e', b'e']).is_empty());
+        let mut small = 1u32.to_be_bytes().to_vec();
+        small.extend_from_slice(b"free");
+        small.extend_from_slice(&4u64.to_be_bytes());
+        assert!(kinds(&small).is_empty());
+        // a 64-bit size no usize can hold
+        let mut huge = 1u32.to_be_bytes().to_vec();
+        huge.extend_from_slice(b"free");
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(kinds(&huge).is_empty());
+        // the header itself cut off
+        assert!(kinds(&[0, 0, 0, 16, b'f', b'r']).is_empty());
+    }
+
+    #[test]
+    fn movie_header() {
+        let mut tkhd = vec![0; 76];
+        tkhd.extend_from_slice(&(1920u32 << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(1080u32 << 16).to_be_bytes());
+        let trak = mp4_box(b"trak", &mp4_box(b"tkhd", &tkhd));
+        let mut xyz = vec![0, 26, 0x15, 0xc7];
+        xyz.extend_from_slice(b"+37.3323-122.0312+010.000/");
+        let udta = mp4_box(b"udta", &mp4_box(b"\xa9xyz", &xyz));
+        let moov = [mvhd(), trak, udta].concat();
+
+        let mut metadata = FileMetadata::default();
+        moov_metadata(&moov, &mut metadata);
+        assert_eq!(metadata.captured_at, Some(datetime!(2024-05-17 14:30 UTC)));
+        // mvhd is in UTC, the local time is unknown
+        assert_eq!(metadata.captured_local, None);
+        assert_eq!(metadata.duration_seconds, Some(90.0));
+        assert_eq!((metadata.width, metadata.height), (Some(1920), Some(1080)));
+        assert_eq!(metadata.latitude, Some(37.3323));
+        assert_eq!(metadata.longitude, Some(-122.0312));
+        assert_eq!(metadata.altitude, Some(10.0));
+    }
+
+    #[test]
+    fn out_of_range_creation_time() {
+        let mut body = vec![1, 0, 0, 0];
+        body.extend_from_slice(&u64::MAX.to_be_bytes());
+        body.extend_from_slice(&u64::MAX.to_be_bytes());
+        body.extend_from_slice(&600u32.to_be_bytes());
+        body.extend_from_slice(&(600u64 * 90).to_be_bytes());
+
+        let mut metadata = FileMetadata::default();
+        moov_metadata(&mp4_box(b"mvhd", &body), &mut metadata);
+        assert_eq!(metadata.captured_at, None);
+        assert_eq!(metadata.duration_seconds, Some(90.0));
+
+        // fits in an i64, but not in a date
+        body[4..12].copy_from_slice(&(i64::MAX as u64).to_be_bytes());
+        let mut metadata = FileMetadata::default();
+        moov_metadata(&mp4_box(b"mvhd", &body), &mut metadata);
+        assert_eq!(metadata.captured_at, None);
+    }
+
+    #[test]
+    fn truncated_movie_header() {
+        let moov = mvhd();
+        for len in 0..moov.len() {
+            let mut metadata = FileMetadata::default();
+            moov_metadata(&moov[..len], &mut metadata);
+            assert_eq!(metadata.captured_at, None);
+        }
+        let mut metadata = FileMetadata::default();
+        moov_metadata(
+            &mp4_box(b"trak", &mp4_box(b"tkhd", &[0; 20])),
+            &mut metadata,
+        );
+        assert_eq!(metadata.width, None);
+    }
+
+    #[test]
+    fn quicktime_keys() {
+        let key = |name: &str| {
+            let mut entry = ((name.len() + 8) as u32).to_be_bytes().to_vec();
+            entry.extend_from_slice(b"mdta");
+            entry.extend_from_slice(name.as_bytes());
+            entry
+        };
+        let keys = [
+            vec![0, 0, 0, 0, 0, 0, 0, 2],
+            key("com.apple.quicktime.make"),
+            key("com.apple.quicktime.creationdate"),
+        ]
+        .concat();
+        let value = |index: u32, text: &str| {
+            let data = mp4_box(
+                b"data",
+                &[&[0, 0, 0, 1, 0, 0, 0, 0], text.as_bytes()].concat(),
+            );
+            mp4_box(&index.to_be_bytes(), &data)
+        };
+        let ilst = [
+            value(1, "Apple"),
+            value(2, "2024-05-17T23:30:00+09:00"),
+            // no key for it
+            value(7, "ignored"),
+        ]
+        .concat();
+        let meta = [vec![0; 4], mp4_box(b"keys", &keys), mp4_box(b"ilst", &ilst)].concat();
+
+        let mut metadata = FileMetadata::default();
+        quicktime_meta(&meta, &mut metadata);
+        assert_eq!(metadata.camera_make.as_deref(), Some("Apple"));
+        assert_eq!(
+            metadata.captured_at,
+            Some(datetime!(2024-05-17 23:30 +09:00))
+        );
+        assert_eq!(metadata.captured_local, Some(datetime!(2024-05-17 23:30)));
+
+        // a key entry claiming less than its header ends the list
+        let broken = [vec![0; 8], 4u32.to_be_bytes().to_vec(), b"mdta".to_vec()].concat();
+        let mut metadata = FileMetadata::default();
+        quicktime_meta(&mp4_box(b"keys", &broken), &mut metadata);
+        assert_eq!(metadata.camera_make, None);
+    }
+
+    #[test]
+    fn exif_datetimes() {
+        let ascii = |text: &str| Value::Ascii(vec![text.as_bytes().to_vec()]);
+        let captured = exif_datetime(&ascii("2024:05:17 23:30:00"), Some(&ascii("+09:00")));
+        assert_eq!(captured, Some(datetime!(2024-05-17 23:30 +09:00)));
+        assert_eq!(captured.unwrap().offset(), offset!(+9));
+
+        // without an offset the clock reading is kept as local time, and taken as UTC
+        let mut metadata = FileMetadata::default();
+        metadata.set_captured(exif_datetime(&ascii("2024:05:17 23:30:00"), None).unwrap());
+        assert_eq!(metadata.captured_at, Some(datetime!(2024-05-17 23:30 UTC)));
+        assert_eq!(metadata.captured_local, Some(datetime!(2024-05-17 23:30)));
+
+        assert_eq!(exif_datetime(&ascii("2024:13:17 23:30:00"), None), None);
+        assert_eq!(exif_datetime(&ascii("    :  :     :  :  "), None), None);
+        assert_eq!(exif_datetime(&Value::Byte(vec![1]), None), None);
+    }
+
+    #[test]
+    fn iso6709() {
+        let mut metadata = FileMetadata::default();
+        parse_iso6709("-33.8688+151.2093/", &mut metadata);
+        assert_eq!(metadata.latitude, Some(-33.8688));
+        assert_eq!(metadata.longitude, Some(151.2093));
+        assert_eq!(metadata.altitude, None);
+
+        let mut metadata = FileMetadata::default();
+        parse_iso6709("+12.5/", &mut metadata);
+        assert_eq!(metadata.latitude, None);
+    }
+
+    #[test]
+    fn video_files() {
+        let path = std::env::temp_dir().join(format!("spaces-video-{}", uuid::Uuid::new_v4()));
+        let ftyp = mp4_box(b"ftyp", b"qt  ");
+        let mdat = mp4_box(b"mdat", &[0; 1000]);
+        let moov = mp4_box(b"moov", &mvhd());
+
+        std::fs::write(&path, [ftyp.clone(), mdat, moov.clone()].concat()).unwrap();
+        let metadata = video_metadata(&path).unwrap();
+        assert_eq!(metadata.duration_seconds, Some(90.0));
+
+        // a box claiming to reach past the end of the file ends the walk before moov
+        let mut oversized = 1u32.to_be_bytes().to_vec();
+        oversized.extend_from_slice(b"mdat");
+        oversized.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        std::fs::write(&path, [ftyp.clone(), oversized, moov.clone()].concat()).unwrap();
+        assert_eq!(video_metadata(&path).unwrap().duration_seconds, None);
+
+        // moov cut off in the middle
+        std::fs::write(&path, [ftyp, moov[..20].to_vec()].concat()).unwrap();
+        assert!(video_metadata(&path).is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,362 @@
+use anyhow::anyhow;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Postgres, QueryBuilder};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+use crate::errors::{AppError, ErrorType, IntoAppError};
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+/// One page of a listing. `total` counts every row matching the filters,
+/// `next` is passed back as `cursor` to get the following page.
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub next: Option<String>,
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            total: self.total,
+            next: self.next,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// The SQL type of a sort key, which decides how it is written into and read back from a cursor.
+#[derive(Clone, Copy)]
+pub(crate) enum KeyType {
+    Text,
+    Integer,
+    BigInt,
+    /// TIMESTAMPTZ, kept in cursors as RFC 3339 in UTC.
+    Timestamp,
+}
+
+impl KeyType {
+    fn sql(self) -> &'static str {
+        match self {
+            KeyType::Text => "TEXT",
+            KeyType::Integer => "INTEGER",
+            KeyType::BigInt => "BIGINT",
+            KeyType::Timestamp => "TIMESTAMPTZ",
+        }
+    }
+
+    /// SQL turning `expression` into the text stored in a cursor.
+    fn to_text(self, expression: &str) -> String {
+        match self {
+            KeyType::Timestamp => format!(
+                r#"to_char(({}) AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US"Z"')"#,
+                expression
+            ),
+            _ => format!("({})::TEXT", expression),
+        }
+    }
+
+    /// Whether `key` casts back to the type, a tampered cursor would fail the query otherwise.
+    fn parses(self, key: &str) -> bool {
+        match self {
+            KeyType::Text => true,
+            KeyType::Integer => key.parse::<i32>().is_ok(),
+            KeyType::BigInt => key.parse::<i64>().is_ok(),
+            KeyType::Timestamp => OffsetDateTime::parse(key, &Rfc3339).is_ok(),
+        }
+    }
+}
+
+/// A column listings can be sorted by.
+pub(crate) struct SortKey {
+    pub name: &'static str,
+    /// SQL expression of the sort key, also compared against cursors.
+    pub expression: &'static str,
+    pub sql_type: KeyType,
+    pub default_order: Order,
+}
+
+/// Where the previous page ended. Pages are cut by the sort key and id of the last row
+/// rather than an offset, so rows added in the meantime don't shift them.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    order: Order,
+    key: String,
+    id: String,
+}
+
+/// A row together with its sort key as text, to build the cursor from.
+#[derive(FromRow)]
+pub(crate) struct Keyed<T> {
+    #[sqlx(flatten)]
+    pub item: T,
+    pub sort_key: String,
+    pub row_id: String,
+}
+
+fn bad_cursor() -> AppError {
+    AppError::new(
+        ErrorType::Validation("Invalid cursor".into()),
+        anyhow!("Cursor couldn't be decoded"),
+    )
+}
+
+/// The sort, order and page size of a listing request, checked against what the listing supports.
+pub(crate) struct PageRequest {
+    key: SortKey,
+    order: Order,
+    limit: i64,
+    cursor: Option<Cursor>,
+}
+
+impl PageRequest {
+    pub fn new(
+        key: SortKey,
+        order: Option<Order>,
+        limit: Option<i64>,
+        cursor: Option<&str>,
+    ) -> Result<Self, AppError> {
+        let order = order.unwrap_or(key.default_order);
+        let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(AppError::new(
+                ErrorType::Validation(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)),
+                anyhow!("Page size {} out of range", limit),
+            ));
+        }
+
+        let cursor = match cursor {
+            Some(cursor) => {
+                let json = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| bad_cursor())?;
+                let cursor: Cursor = serde_json::from_slice(&json).map_err(|_| bad_cursor())?;
+                if cursor.sort != key.name || cursor.order != order {
+                    return Err(AppError::new(
+                        ErrorType::Validation(
+                            "The cursor belongs to a listing with a different sort".into(),
+                        ),
+                        anyhow!("Cursor for {} used to sort by {}", cursor.sort, key.name),
+                    ));
+                }
+                if !key.sql_type.parses(&cursor.key) {
+                    return Err(bad_cursor());
+                }
+                Some(cursor)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            key,
+            order,
+            limit,
+            cursor,
+        })
+    }
+
+    /// Selects the sort key and the id (`id_column`) next to the columns the caller pushed,
+    /// the query continues with FROM.
+    pub fn push_select_keys(&self, builder: &mut QueryBuilder<'_, Postgres>, id_column: &str) {
+        builder.push(format_args!(
+            ", {} AS sort_key, {} AS row_id ",
+            self.key.sql_type.to_text(self.key.expression),
+            id_column
+        ));
+    }
+
+    /// Appends the condition skipping the rows of previous pages, the query continues after WHERE.
+    pub fn push_after_cursor<'a>(
+        &'a self,
+        builder: &mut QueryBuilder<'a, Postgres>,
+        id_column: &str,
+    ) {
+        let Some(cursor) = &self.cursor else {
+            return;
+        };
+        let comparison = match self.order {
+            Order::Asc => ">",
+            Order::Desc => "<",
+        };
+        builder.push(format_args!(
+            " AND (({}), {}) {} (CAST(",
+            self.key.expression, id_column, comparison
+        ));
+        builder.push_bind(&cursor.key);
+        builder.push(format_args!(" AS {}), ", self.key.sql_type.sql()));
+        builder.push_bind(&cursor.id);
+        builder.push(") ");
+    }
+
+    /// Appends ORDER BY and LIMIT, one more row than the page holds tells whether another one follows.
+    pub fn push_order_and_limit(&self, builder: &mut QueryBuilder<'_, Postgres>, id_column: &str) {
+        let order = match self.order {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        };
+        builder.push(format_args!(
+            " ORDER BY ({}) {order}, {} {order} LIMIT {}",
+            self.key.expression,
+            id_column,
+            self.limit + 1
+        ));
+    }
+
+    pub fn into_page<T>(self, mut rows: Vec<Keyed<T>>, total: i64) -> Result<Page<T>, AppError> {
+        let next = if rows.len() as i64 > self.limit {
+            rows.truncate(self.limit as usize);
+            let last = rows.last().expect("a full page has a last row");
+            let cursor = Cursor {
+                sort: self.key.name.to_string(),
+                order: self.order,
+                key: last.sort_key.clone(),
+                id: last.row_id.clone(),
+            };
+            Some(URL_SAFE_NO_PAD.encode(serde_json::to_vec(&cursor).into_internal_error()?))
+        } else {
+            None
+        };
+
+        Ok(Page {
+            items: rows.into_iter().map(|row| row.item).collect(),
+            total,
+            next,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size_key() -> SortKey {
+        SortKey {
+            name: "size",
+            expression: "file_size_bytes",
+            sql_type: KeyType::BigInt,
+            default_order: Order::Desc,
+        }
+    }
+
+    fn date_key() -> SortKey {
+        SortKey {
+            name: "upload_date",
+            expression: "upload_date",
+            sql_type: KeyType::Timestamp,
+            default_order: Order::Desc,
+        }
+    }
+
+    fn rows(keys: &[&str]) -> Vec<Keyed<usize>> {
+        keys.iter()
+            .enumerate()
+            .map(|(i, key)| Keyed {
+                item: i,
+                sort_key: key.to_string(),
+                row_id: format!("id-{}", i),
+            })
+            .collect()
+    }
+
+    fn encode(cursor: &Cursor) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor).unwrap())
+    }
+
+    fn is_validation(result: Result<PageRequest, AppError>) -> bool {
+        matches!(
+            result.map(|_| ()).unwrap_err().error_type,
+            ErrorType::Validation(_)
+        )
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let request = PageRequest::new(date_key(), None, Some(2), None).unwrap();
+        let page = request
+            .into_page(
+                rows(&[
+                    "2026-10-18T10:00:02.000000Z",
+                    "2026-10-18T10:00:01.000000Z",
+                    "2026-10-18T10:00:00.000000Z",
+                ]),
+                3,
+            )
+            .unwrap();
+        assert_eq!(page.items, [0, 1]);
+        assert_eq!(page.total, 3);
+
+        let next = page.next.expect("a third row follows");
+        let request = PageRequest::new(date_key(), None, Some(2), Some(&next)).unwrap();
+        let cursor = request.cursor.as_ref().unwrap();
+        assert_eq!(cursor.key, "2026-10-18T10:00:01.000000Z");
+        assert_eq!(cursor.id, "id-1");
+
+        let last = request
+            .into_page(rows(&["2026-10-18T10:00:00.000000Z"]), 3)
+            .unwrap();
+        assert!(last.next.is_none());
+    }
+
+    #[test]
+    fn cursor_of_other_sort() {
+        let request = PageRequest::new(size_key(), None, Some(1), None).unwrap();
+        let next = request
+            .into_page(rows(&["2", "1"]), 2)
+            .unwrap()
+            .next
+            .unwrap();
+
+        assert!(PageRequest::new(size_key(), None, Some(1), Some(&next)).is_ok());
+        assert!(is_validation(PageRequest::new(
+            date_key(),
+            None,
+            Some(1),
+            Some(&next)
+        )));
+        assert!(is_validation(PageRequest::new(
+            size_key(),
+            Some(Order::Asc),
+            Some(1),
+            Some(&next)
+        )));
+    }
+
+    #[test]
+    fn tampered_cursor() {
+        let tampered = |sort: &str, key: &str| {
+            encode(&Cursor {
+                sort: sort.into(),
+                order: Order::Desc,
+                key: key.into(),
+                id: "id".into(),
+            })
+        };
+        for (key, cursor) in [
+            (size_key(), tampered("size", "1; DROP TABLE files")),
+            (size_key(), tampered("size", "99999999999999999999")),
+            (date_key(), tampered("upload_date", "yesterday")),
+        ] {
+            assert!(is_validation(PageRequest::new(
+                key,
+                None,
+                None,
+                Some(&cursor)
+            )));
+        }
+        assert!(is_validation(PageRequest::new(
+            size_key(),
+            None,
+            None,
+            Some("not base64!")
+        )));
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use sqlx::PgTransaction;
+
+use crate::{
+    auth::{AuthConfig, AuthUser, require_admin},
+    errors::{AppError, ErrorType, IntoAppError},
+    spaces::Space,
+};
+
+/// Limits for spaces that don't set their own, none by default.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub space_quota_bytes: Option<u64>,
+    pub max_file_size_bytes: Option<u64>,
+}
+
+/// How much a space stores and may store, with the instance defaults applied.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SpaceUsage {
+    pub used_bytes: i64,
+    pub quota_bytes: Option<i64>,
+    pub max_file_size_bytes: Option<i64>,
+}
+
+impl SpaceUsage {
+    pub fn of(space: &Space, defaults: &QuotaConfig) -> Self {
+        Self {
+            used_bytes: space.total_size_used_bytes,
+            quota_bytes: space
+                .quota_bytes
+                .or(default_limit(defaults.space_quota_bytes)),
+            max_file_size_bytes: space
+                .max_file_size_bytes
+                .or(default_limit(defaults.max_file_size_bytes)),
+        }
+    }
+
+    /// Rejects a file of `size_bytes` with 413 if it is too large for the space.
+    /// The quota is checked again with [`reserve_quota`] when the file is stored.
+    pub fn check_upload(&self, size_bytes: i64) -> Result<(), AppError> {
+        if let Some(max) = self.max_file_size_bytes
+            && size_bytes > max
+        {
+            return Err(AppError::new(
+                ErrorType::PayloadTooLarge(format!(
+                    "Files in this space are limited to {} bytes",
+                    max
+                )),
+                anyhow!(
+                    "File of at least {} bytes over the limit of {}",
+                    size_bytes,
+                    max
+                ),
+            ));
+        }
+        check_quota(self.used_bytes, size_bytes, self.quota_bytes)
+    }
+}
+
+fn default_limit(limit: Option<u64>) -> Option<i64> {
+    limit.map(|bytes| bytes.min(i64::MAX as u64) as i64)
+}
+
+/// Limits set on a space, `None` falls back to the instance default.
+pub(crate) fn check_limits(
+    quota_bytes: Option<i64>,
+    max_file_size_bytes: Option<i64>,
+) -> Result<(), AppError> {
+    for (name, limit) in [
+        ("quota_bytes", quota_bytes),
+        ("max_file_size_bytes", max_file_size_bytes),
+    ] {
+        if let Some(bytes) = limit
+            && bytes < 0
+        {
+            return Err(AppError::new(
+                ErrorType::Validation(format!("{} can't be negative", name)),
+                anyhow!("Negative {} {}", name, bytes),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Only instance admins may change the limits of a space, owners could lift them otherwise.
+pub(crate) fn require_limits_admin(
+    auth: &AuthConfig,
+    user: &AuthUser,
+    changed: bool,
+) -> Result<(), AppError> {
+    if changed {
+        require_admin(auth, user)?;
+    }
+    Ok(())
+}
+
+fn check_quota(used_bytes: i64, size_bytes: i64, quota_bytes: Option<i64>) -> Result<(), AppError> {
+    match quota_bytes {
+        Some(quota) if used_bytes.saturating_add(size_bytes) > quota => {
+            Err(quota_exceeded(used_bytes, size_bytes, quota))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn quota_exceeded(used_bytes: i64, size_bytes: i64, quota: i64) -> AppError {
+    AppError::new(
+        ErrorType::PayloadTooLarge(format!(
+            "This upload would exceed the storage quota of the space, {} of {} bytes are free",
+            (quota - used_bytes).max(0),
+            quota
+        )),
+        anyhow!(
+            "Upload of {} bytes over the quota, {} of {} used",
+            size_bytes,
+            used_bytes,
+            quota
+        ),
+    )
+}
+
+/// Adds a file of `size_bytes` to the total of the space inside `tx`, unless it doesn't fit into
+/// the quota anymore. Checked and added in one statement, so uploads running at the same time can't
+/// overshoot the quota together. The space stays locked until `tx` ends, so call it last, once
+/// the blob is stored.
+pub(crate) async fn reserve_quota(
+    tx: &mut PgTransaction<'_>,
+    space_id: &str,
+    size_bytes: i64,
+    defaults: &QuotaConfig,
+) -> Result<(), AppError> {
+    let default_quota = default_limit(defaults.space_quota_bytes);
+    let reserved = sqlx::query_scalar!(
+        r#"
+        UPDATE spaces SET total_size_used_bytes = total_size_used_bytes + $2
+        WHERE id = $1 AND (
+            COALESCE(quota_bytes, $3) IS NULL
+            OR total_size_used_bytes + $2 <= COALESCE(quota_bytes, $3)
+        )
+        RETURNING id
+        "#,
+        space_id,
+        size_bytes,
+        default_quota
+    )
+    .fetch_optional(&mut **tx)
+    .await
+    .into_db_error()?;
+    if reserved.is_some() {
+        return Ok(());
+    }
+
+    let space = sqlx::query!(
+        "SELECT total_size_used_bytes, quota_bytes FROM spaces WHERE id = $1",
+        space_id
+    )
+    .fetch_one(&mut **tx)
+    .await
+    .into_db_error()?;
+    let quota = space.quota_bytes.or(default_quota).unwrap_or(i64::MAX);
+    Err(quota_exceeded(
+        space.total_size_used_bytes,
+        size_bytes,
+        quota,
+    ))
+}
@@ -0,0 +1,172 @@
+// helpers for HTTP range requests (RFC 9110 section 14) and entity tags
+
+// more ranges than this in one request are ignored and the whole file is sent
+const MAX_RANGES: usize = 32;
+
+/// An inclusive byte range, matching the `start-end` notation of `Content-Range`.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+pub enum RangeRequest {
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header for a resource of `total` bytes.
+/// Malformed headers and units other than bytes are ignored (`Full`), as the RFC allows.
+pub fn parse_range(value: &str, total: u64) -> RangeRequest {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = Vec::new();
+    let mut any_valid = false;
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let Some((start, end)) = part.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let (start, end) = (start.trim(), end.trim());
+
+        if start.is_empty() {
+            // suffix range: the last `n` bytes
+            let Ok(suffix) = end.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            any_valid = true;
+            if suffix == 0 || total == 0 {
+                continue;
+            }
+            ranges.push(ByteRange {
+                start: total.saturating_sub(suffix),
+                end: total - 1,
+            });
+            continue;
+        }
+
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        let end = match end {
+            "" => None,
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => Some(end),
+                _ => return RangeRequest::Full,
+            },
+        };
+        any_valid = true;
+        if start >= total {
+            continue;
+        }
+        ranges.push(ByteRange {
+            start,
+            end: end.map_or(total - 1, |end| end.min(total - 1)),
+        });
+    }
+
+    if !any_valid || ranges.len() > MAX_RANGES {
+        RangeRequest::Full
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
+
+/// Weak comparison of an `If-None-Match` header against `etag`.
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(value: &str, total: u64) -> Vec<(u64, u64)> {
+        match parse_range(value, total) {
+            RangeRequest::Partial(ranges) => ranges.iter().map(|r| (r.start, r.end)).collect(),
+            RangeRequest::Full => panic!("{:?} parsed as the full resource", value),
+            RangeRequest::Unsatisfiable => panic!("{:?} parsed as unsatisfiable", value),
+        }
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(partial("bytes=0-99", 1000), [(0, 99)]);
+        assert_eq!(partial("bytes=900-", 1000), [(900, 999)]);
+        // the end is clamped to the resource
+        assert_eq!(partial("bytes=500-5000", 1000), [(500, 999)]);
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(partial("bytes=-100", 1000), [(900, 999)]);
+        // longer than the resource means all of it
+        assert_eq!(partial("bytes=-5000", 1000), [(0, 999)]);
+        assert!(matches!(
+            parse_range("bytes=-0", 1000),
+            RangeRequest::Unsatisfiable
+        ));
+        assert!(matches!(
+            parse_range("bytes=-10", 0),
+            RangeRequest::Unsatisfiable
+        ));
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(
+            partial("bytes=0-9, 20-29,-5", 100),
+            [(0, 9), (20, 29), (95, 99)]
+        );
+        // unsatisfiable parts are dropped as long as one is left
+        assert_eq!(partial("bytes=2000-,10-19", 100), [(10, 19)]);
+        assert_eq!(ByteRange { start: 20, end: 29 }.len(), 10);
+    }
+
+    #[test]
+    fn too_many_ranges_send_everything() {
+        let spec = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 2, i * 2))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert!(matches!(
+            parse_range(&format!("bytes={}", spec), 1000),
+            RangeRequest::Full
+        ));
+    }
+
+    #[test]
+    fn unsatisfiable_and_malformed() {
+        assert!(matches!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        ));
+        for value in ["items=0-1", "bytes=5-1", "bytes=a-b", "bytes=0", "bytes="] {
+            assert!(
+                matches!(parse_range(value, 1000), RangeRequest::Full),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn etags() {
+        assert!(etag_matches("\"a\", W/\"b\"", "\"b\""));
+        assert!(etag_matches("*", "\"b\""));
+        assert!(!etag_matches("\"a\"", "\"b\""));
+    }
+}
@@ -0,0 +1,228 @@
+use std::{collections::HashMap, io::Read, path::PathBuf};
+
+use anyhow::anyhow;
+use axum::{
+    Json, debug_handler,
+    extract::{Query, State},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgTransaction;
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    errors::{AppError, ErrorType, IntoAppError},
+    files::{SpaceFileDetails, get_space_files, with_metadata},
+};
+
+// the first megabyte of text is searched, that's a few hundred pages
+const MAX_TEXT_BYTES: u64 = 1024 * 1024;
+// bigger PDFs aren't parsed for their text at all
+const MAX_PDF_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_RESULTS: i64 = 50;
+const MAX_RESULTS: i64 = 200;
+// ts_headline marks the matches with these, they can't be confused with the HTML escaped text
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// Reads the text of plain text files and PDFs on the blocking thread pool,
+/// anything else or a document that can't be parsed has none.
+pub async fn extract_text(path: PathBuf, mime_type: Option<String>) -> Option<String> {
+    let text = tokio::task::spawn_blocking(move || {
+        let text = match mime_type.as_deref()? {
+            "application/pdf" => {
+                if std::fs::metadata(&path).ok()?.len() > MAX_PDF_BYTES {
+                    return None;
+                }
+                // the PDF parser panics on some malformed documents
+                std::panic::catch_unwind(|| pdf_extract::extract_text(&path))
+                    .ok()?
+                    .ok()?
+            }
+            mime if mime.starts_with("text/") => {
+                let mut data = Vec::new();
+                std::fs::File::open(&path)
+                    .ok()?
+                    .take(MAX_TEXT_BYTES)
+                    .read_to_end(&mut data)
+                    .ok()?;
+                String::from_utf8_lossy(&data).into_owned()
+            }
+            _ => return None,
+        };
+
+        let mut end = text.len().min(MAX_TEXT_BYTES as usize);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        // Postgres text can't hold NUL
+        let text = text[..end].replace('\0', "");
+        (!text.trim().is_empty()).then_some(text)
+    })
+    .await;
+
+    text.ok().flatten()
+}
+
+pub(crate) async fn insert_text(
+    tx: &mut PgTransaction<'_>,
+    checksum: &str,
+    text: Option<&str>,
+) -> Result<(), AppError> {
+    let Some(text) = text else {
+        return Ok(());
+    };
+    sqlx::query!(
+        "INSERT INTO file_texts (checksum, content) VALUES ($1, $2) ON CONFLICT (checksum) DO NOTHING",
+        checksum,
+        text
+    )
+    .execute(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    Ok(())
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            MATCH_START => escaped.push_str("<mark>"),
+            MATCH_END => escaped.push_str("</mark>"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+    /// Only search this space.
+    space_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    file: SpaceFileDetails,
+    space_name: String,
+    score: f32,
+    /// Passages of the document's text around the matches, HTML escaped with the matches in `<mark>`.
+    snippet: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    items: Vec<SearchHit>,
+}
+
+/// `/api/search`: files whose name resembles `q` or whose text contains it,
+/// in every space the caller can open without an access code, best matches first.
+#[debug_handler()]
+pub async fn search(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResults>, AppError> {
+    let q = query.q.trim();
+    // trigrams of a single character match just about everything
+    if q.chars().count() < 2 {
+        return Err(AppError::new(
+            ErrorType::Validation("q must be at least 2 characters long".into()),
+            anyhow!("Search query too short"),
+        ));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_RESULTS);
+    if !(1..=MAX_RESULTS).contains(&limit) {
+        return Err(AppError::new(
+            ErrorType::Validation(format!("limit must be between 1 and {}", MAX_RESULTS)),
+            anyhow!("Search limit {} out of range", limit),
+        ));
+    }
+
+    // spaces with an access code are unlocked one at a time, so only their members find their files
+    let hits = sqlx::query!(
+        r#"
+        WITH visible AS (
+            SELECT id FROM spaces
+            WHERE (id IN (SELECT space_id FROM space_members WHERE user_id = $1)
+                    OR (is_public AND access_code_hash IS NULL))
+                AND deleted_at IS NULL
+                AND ($4::TEXT IS NULL OR id = $4)
+        ),
+        hits AS (
+            SELECT files.id,
+                GREATEST(
+                    similarity(lower(files.original_filename), lower($2)),
+                    word_similarity(lower($2), lower(files.original_filename))
+                ) AS score,
+                FALSE AS in_text
+            FROM files
+            WHERE files.space_id IN (SELECT id FROM visible) AND files.deleted_at IS NULL
+                AND (lower($2) <% lower(files.original_filename)
+                    OR lower(files.original_filename) % lower($2))
+            UNION ALL
+            SELECT files.id, ts_rank(file_texts.search_vector, websearch_to_tsquery('simple', $2)),
+                TRUE
+            FROM files
+            JOIN file_texts ON file_texts.checksum = files.checksum
+            WHERE files.space_id IN (SELECT id FROM visible) AND files.deleted_at IS NULL
+                AND file_texts.search_vector @@ websearch_to_tsquery('simple', $2)
+        ),
+        ranked AS (
+            SELECT id, MAX(score) AS score, bool_or(in_text) AS in_text FROM hits
+            GROUP BY id
+            ORDER BY MAX(score) DESC, id
+            LIMIT $3
+        )
+        SELECT ranked.id AS "id!", ranked.score AS "score!", spaces.name AS space_name,
+            CASE WHEN ranked.in_text THEN ts_headline(
+                'simple', file_texts.content, websearch_to_tsquery('simple', $2),
+                'MaxFragments=2, MaxWords=20, MinWords=5, StartSel=' || chr(2) || ', StopSel=' || chr(3)
+            ) END AS snippet
+        FROM ranked
+        JOIN files ON files.id = ranked.id
+        JOIN spaces ON spaces.id = files.space_id
+        LEFT JOIN file_texts ON file_texts.checksum = files.checksum
+        ORDER BY ranked.score DESC, ranked.id
+        "#,
+        user.id,
+        q,
+        limit,
+        query.space_id
+    )
+    .fetch_all(&pool)
+    .await
+    .into_db_error()?;
+
+    let ids: Vec<String> = hits.iter().map(|hit| hit.id.clone()).collect();
+    let files = get_space_files(&pool, &ids).await?;
+    let mut files: HashMap<String, SpaceFileDetails> = with_metadata(&pool, files)
+        .await?
+        .into_iter()
+        .map(|details| (details.file.id.clone(), details))
+        .collect();
+
+    let items = hits
+        .into_iter()
+        .filter_map(|hit| {
+            Some(SearchHit {
+                // deleted in the meantime
+                file: files.remove(&hit.id)?,
+                space_name: hit.space_name,
+                score: hit.score,
+                snippet: hit.snippet.as_deref().map(escape_html),
+            })
+        })
+        .collect();
+
+    Ok(Json::from(SearchResults { items }))
+}
@@ -0,0 +1,373 @@
+use anyhow::anyhow;
+use axum::{
+    Json, debug_handler,
+    extract::{Path, State},
+    http::{HeaderMap, Method},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use time::OffsetDateTime;
+
+use crate::{
+    AppState,
+    auth::{AuthUser, generate_token, hash_password, hash_token, verify_password},
+    errors::{AppError, ErrorType, IntoAppError},
+    files::{get_space_file, list_space_files, serialize_opt, serve_file},
+    members::{SpaceRole, require_role},
+};
+
+/// A link handing out a single file or a whole space to people outside of it.
+#[derive(Serialize)]
+pub struct ShareLink {
+    id: String,
+    space_id: String,
+    file_id: Option<String>,
+    created_by: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(serialize_with = "serialize_opt")]
+    expires_at: Option<OffsetDateTime>,
+    max_downloads: Option<i32>,
+    download_count: i32,
+    has_password: bool,
+    #[serde(serialize_with = "serialize_opt")]
+    revoked_at: Option<OffsetDateTime>,
+}
+
+// wrong passwords a link takes before opening it backs off, doubling the wait with every further one
+const FREE_PASSWORD_ATTEMPTS: i32 = 5;
+const MAX_PASSWORD_BACKOFF_SECONDS: f64 = 60.0 * 60.0;
+
+fn link_gone() -> AppError {
+    AppError::new(
+        ErrorType::NotFound("Share link not found or no longer valid".into()),
+        anyhow!("Share link unknown, revoked, expired or used up"),
+    )
+}
+
+/// Counts a download against a share link, fails once the link has no downloads left.
+pub(crate) async fn record_share_download(pool: &PgPool, id: &str) -> Result<(), AppError> {
+    sqlx::query!(
+        r#"
+        UPDATE share_links SET download_count = download_count + 1
+        WHERE id = $1 AND (max_downloads IS NULL OR download_count < max_downloads)
+        RETURNING id
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(link_gone)?;
+
+    Ok(())
+}
+
+#[debug_handler()]
+pub async fn shares_get(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    Path(space_id): Path<String>,
+) -> Result<Json<Vec<ShareLink>>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Editor).await?;
+
+    let links = sqlx::query_as!(
+        ShareLink,
+        r#"
+        SELECT id, space_id, file_id, created_by, created_at, expires_at, max_downloads,
+            download_count, password_hash IS NOT NULL AS "has_password!", revoked_at
+        FROM share_links WHERE space_id = $1
+        ORDER BY created_at DESC
+        "#,
+        space_id
+    )
+    .fetch_all(&pool)
+    .await
+    .into_db_error()?;
+
+    Ok(Json::from(links))
+}
+
+#[derive(Deserialize)]
+pub struct CreateShareLinkRequest {
+    file_id: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    expires_at: Option<OffsetDateTime>,
+    max_downloads: Option<i32>,
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedShareLink {
+    #[serde(flatten)]
+    link: ShareLink,
+    // only ever shown once, just like session tokens only the hash is stored
+    token: String,
+    path: String,
+}
+
+#[debug_handler()]
+pub async fn shares_post(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    Path(space_id): Path<String>,
+    Json(payload): Json<CreateShareLinkRequest>,
+) -> Result<Json<CreatedShareLink>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Editor).await?;
+
+    if let Some(file_id) = &payload.file_id
+        && get_space_file(&pool, file_id).await?.space_id != space_id
+    {
+        return Err(AppError::new(
+            ErrorType::Validation("File not found".into()),
+            anyhow!("File {} is not part of space {}", file_id, space_id),
+        ));
+    }
+    if payload
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= OffsetDateTime::now_utc())
+    {
+        return Err(AppError::new(
+            ErrorType::Validation("expires_at must be in the future".into()),
+            anyhow!("Share link would already be expired"),
+        ));
+    }
+    if payload.max_downloads.is_some_and(|max| max < 1) {
+        return Err(AppError::new(
+            ErrorType::Validation("max_downloads must be at least 1".into()),
+            anyhow!("Share link would allow no downloads"),
+        ));
+    }
+
+    let password_hash = match payload.password {
+        Some(password) if !password.is_empty() => Some(hash_password(password).await?),
+        _ => None,
+    };
+    let id = uuid::Uuid::new_v4().to_string();
+    let token = generate_token();
+
+    let link = sqlx::query_as!(
+        ShareLink,
+        r#"
+        INSERT INTO share_links (id, token_hash, space_id, file_id, created_by, expires_at, max_downloads, password_hash)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING id, space_id, file_id, created_by, created_at, expires_at, max_downloads,
+            download_count, password_hash IS NOT NULL AS "has_password!", revoked_at
+        "#,
+        id,
+        hash_token(&token),
+        space_id,
+        payload.file_id,
+        user.id,
+        payload.expires_at,
+        payload.max_downloads,
+        password_hash
+    )
+    .fetch_one(&pool)
+    .await
+    .into_db_error()?;
+
+    Ok(Json::from(CreatedShareLink {
+        link,
+        path: format!("/s/{}", token),
+        token,
+    }))
+}
+
+#[debug_handler()]
+pub async fn shares_delete(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    Path((space_id, share_id)): Path<(String, String)>,
+) -> Result<Json<ShareLink>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Editor).await?;
+
+    // links are revoked rather than deleted so they still show up in the listing
+    let link = sqlx::query_as!(
+        ShareLink,
+        r#"
+        UPDATE share_links SET revoked_at = COALESCE(revoked_at, NOW())
+        WHERE id = $1 AND space_id = $2
+        RETURNING id, space_id, file_id, created_by, created_at, expires_at, max_downloads,
+            download_count, password_hash IS NOT NULL AS "has_password!", revoked_at
+        "#,
+        share_id,
+        space_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Share link not found".into()),
+            anyhow!("Share link {} not in space {}", share_id, space_id),
+        )
+    })?;
+
+    Ok(Json::from(link))
+}
+
+struct OpenedLink {
+    id: String,
+    space_id: String,
+    file_id: Option<String>,
+}
+
+/// Body of a POST opening a link, an alternative to the `X-Share-Password` header.
+#[derive(Deserialize)]
+pub struct SharePassword {
+    password: String,
+}
+
+/// Resolves `token` to a usable link. The password, if the link has one, is sent as the
+/// `X-Share-Password` header or in the body of a POST, never in the URL where it would be logged.
+async fn open_link(
+    pool: &PgPool,
+    token: &str,
+    headers: &HeaderMap,
+    body: Option<SharePassword>,
+) -> Result<OpenedLink, AppError> {
+    let rec = sqlx::query!(
+        r#"
+        SELECT id, space_id, file_id, password_hash FROM share_links
+        WHERE token_hash = $1
+            AND revoked_at IS NULL
+            AND space_id IN (SELECT id FROM spaces WHERE deleted_at IS NULL)
+            AND (expires_at IS NULL OR expires_at > NOW())
+            AND (max_downloads IS NULL OR download_count < max_downloads)
+        "#,
+        hash_token(token)
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(link_gone)?;
+
+    if let Some(password_hash) = rec.password_hash {
+        let password = headers
+            .get("X-Share-Password")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+            .or(body.map(|body| body.password));
+        let Some(password) = password else {
+            return Err(AppError::new(
+                ErrorType::Authorization("This link needs a valid password".into()),
+                anyhow!("No password for share link {}", rec.id),
+            ));
+        };
+
+        // counted as a failure up front, so parallel guesses can't all get in before the first one fails
+        sqlx::query!(
+            r#"
+            UPDATE share_links
+            SET password_failures = password_failures + 1, last_password_failure_at = NOW()
+            WHERE id = $1 AND (
+                password_failures < $2
+                OR last_password_failure_at + make_interval(secs => LEAST(
+                    $3, power(2, LEAST(password_failures - $2, 16))
+                )) <= NOW()
+            )
+            RETURNING id
+            "#,
+            rec.id,
+            FREE_PASSWORD_ATTEMPTS,
+            MAX_PASSWORD_BACKOFF_SECONDS
+        )
+        .fetch_optional(pool)
+        .await
+        .into_db_error()?
+        .ok_or_else(|| {
+            AppError::new(
+                ErrorType::TooManyRequests("Too many wrong passwords, try again later".into()),
+                anyhow!(
+                    "Opening share link {} refused after wrong passwords",
+                    rec.id
+                ),
+            )
+        })?;
+
+        if !verify_password(password, password_hash).await? {
+            return Err(AppError::new(
+                ErrorType::Authorization("This link needs a valid password".into()),
+                anyhow!("Wrong password for share link {}", rec.id),
+            ));
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE share_links SET password_failures = 0, last_password_failure_at = NULL
+            WHERE id = $1
+            "#,
+            rec.id
+        )
+        .execute(pool)
+        .await
+        .into_db_error()?;
+    }
+
+    Ok(OpenedLink {
+        id: rec.id,
+        space_id: rec.space_id,
+        file_id: rec.file_id,
+    })
+}
+
+/// `/s/{token}`: downloads the shared file, or lists the files of a shared space.
+#[debug_handler()]
+pub async fn share_open(
+    State(AppState { pool, store, .. }): State<AppState>,
+    Path(token): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+    body: Option<Json<SharePassword>>,
+) -> Result<Response, AppError> {
+    let link = open_link(&pool, &token, &headers, body.map(|Json(body)| body)).await?;
+
+    match link.file_id {
+        Some(file_id) => {
+            let file_meta = get_space_file(&pool, &file_id).await?;
+            serve_file(
+                &pool,
+                store.as_ref(),
+                file_meta,
+                Some(&link.id),
+                method,
+                &headers,
+            )
+            .await
+        }
+        None => {
+            let files = list_space_files(&pool, &link.space_id).await?;
+            Ok(Json::from(files).into_response())
+        }
+    }
+}
+
+/// `/s/{token}/files/{file_id}`: downloads a file of a shared space.
+#[debug_handler()]
+pub async fn share_file_download(
+    State(AppState { pool, store, .. }): State<AppState>,
+    Path((token, file_id)): Path<(String, String)>,
+    method: Method,
+    headers: HeaderMap,
+    body: Option<Json<SharePassword>>,
+) -> Result<Response, AppError> {
+    let link = open_link(&pool, &token, &headers, body.map(|Json(body)| body)).await?;
+
+    let file_meta = get_space_file(&pool, &file_id).await?;
+    // a file link doesn't open up the rest of its space
+    if link.file_id.is_some_and(|id| id != file_id) || file_meta.space_id != link.space_id {
+        return Err(link_gone());
+    }
+
+    serve_file(
+        &pool,
+        store.as_ref(),
+        file_meta,
+        Some(&link.id),
+        method,
+        &headers,
+    )
+    .await
+}
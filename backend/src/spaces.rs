@@ -1,17 +1,37 @@
+use anyhow::anyhow;
 use axum::{
     Json, debug_handler,
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::StatusCode,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::prelude::FromRow;
-use time::OffsetDateTime;
+use sqlx::{PgPool, PgTransaction, Postgres, QueryBuilder, prelude::FromRow};
+use time::{Duration, OffsetDateTime};
 
 use crate::{
     AppState,
-    errors::{AppError, IntoAppError},
+    auth::{AuthUser, generate_token, hash_password, hash_token, verify_password},
+    errors::{AppError, ErrorType, IntoAppError},
+    events::SpaceEvent,
+    files::serialize_opt,
+    folders::double_option,
+    members::{SpaceRole, SpaceToken, require_access, require_role},
+    pagination::{KeyType, Keyed, Order, Page, PageRequest, SortKey},
+    quotas::{QuotaConfig, SpaceUsage, check_limits, require_limits_admin},
+    trash::Trashed,
+    versions::ConflictPolicy,
 };
 
-#[derive(Debug, Serialize, FromRow)]
+const SPACE_TOKEN_TTL: Duration = Duration::days(7);
+// wrong access codes a user may send before each further attempt has to wait
+const FREE_UNLOCK_ATTEMPTS: i32 = 5;
+// the wait doubles from one second up to this
+const MAX_UNLOCK_BACKOFF_SECONDS: f64 = 60.0 * 60.0;
+// wrong access codes per space and hour from all users together, so new accounts don't help guessing.
+// Each user counts with their free attempts only, one account alone can't lock everybody out.
+const SPACE_UNLOCK_FAILURES_PER_HOUR: i64 = 100;
+
+#[derive(Debug, Clone, Serialize, FromRow)]
 pub struct Space {
     pub id: String,
     pub name: String,
@@ -22,73 +42,248 @@ pub struct Space {
     pub updated_at: OffsetDateTime,
 
     pub is_public: bool,
-    pub access_code: Option<String>,
+    // argon2 PHC string, never sent to clients
+    #[serde(skip_serializing)]
+    pub access_code_hash: Option<String>,
+    pub has_access_code: bool,
     pub total_size_used_bytes: i64,
+    pub strip_gps: bool,
+    #[serde(serialize_with = "serialize_opt")]
+    pub deleted_at: Option<OffsetDateTime>,
+    #[sqlx(try_from = "String")]
+    pub conflict_policy: ConflictPolicy,
+    /// Set by instance admins, the instance defaults apply otherwise, see [`SpaceDetails`].
+    pub quota_bytes: Option<i64>,
+    pub max_file_size_bytes: Option<i64>,
+}
+
+/// A space with its storage usage, as returned to clients.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpaceDetails {
+    #[serde(flatten)]
+    pub(crate) space: Space,
+    usage: SpaceUsage,
+}
+
+impl Space {
+    pub fn with_usage(self, defaults: &QuotaConfig) -> SpaceDetails {
+        SpaceDetails {
+            usage: SpaceUsage::of(&self, defaults),
+            space: self,
+        }
+    }
+}
+
+/// Access codes used to be stored in plaintext, this hashes the ones still left over.
+pub async fn hash_legacy_access_codes(pool: &PgPool) -> Result<(), AppError> {
+    let legacy = sqlx::query!(
+        r#"SELECT id, access_code_hash AS "access_code!" FROM spaces WHERE access_code_hash NOT LIKE '$argon2%'"#
+    )
+    .fetch_all(pool)
+    .await
+    .into_db_error()?;
+
+    for rec in legacy {
+        let access_code_hash = hash_password(rec.access_code.clone()).await?;
+        sqlx::query!(
+            "UPDATE spaces SET access_code_hash = $2 WHERE id = $1 AND access_code_hash = $3",
+            rec.id,
+            access_code_hash,
+            rec.access_code
+        )
+        .execute(pool)
+        .await
+        .into_db_error()?;
+    }
+    Ok(())
+}
+
+/// Hashes a new access code, an empty one removes the code.
+async fn hash_access_code(access_code: Option<String>) -> Result<Option<String>, AppError> {
+    match access_code {
+        Some(code) if !code.is_empty() => Ok(Some(hash_password(code).await?)),
+        _ => Ok(None),
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpaceSort {
+    Name,
+    Size,
+    CreatedAt,
+}
+
+impl SpaceSort {
+    fn key(self) -> SortKey {
+        match self {
+            SpaceSort::Name => SortKey {
+                name: "name",
+                expression: "lower(name)",
+                sql_type: KeyType::Text,
+                default_order: Order::Asc,
+            },
+            SpaceSort::Size => SortKey {
+                name: "size",
+                expression: "total_size_used_bytes",
+                sql_type: KeyType::BigInt,
+                default_order: Order::Desc,
+            },
+            SpaceSort::CreatedAt => SortKey {
+                name: "created_at",
+                expression: "created_at",
+                sql_type: KeyType::Timestamp,
+                default_order: Order::Desc,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SpaceListQuery {
+    sort: Option<SpaceSort>,
+    order: Option<Order>,
+    limit: Option<i64>,
+    cursor: Option<String>,
 }
 
 #[debug_handler()]
 pub async fn spaces_get(
-    State(AppState {
-        pool,
-        upload_path: _,
-    }): State<AppState>,
-) -> Result<Json<Vec<Space>>, AppError> {
-    let rec: Vec<Space> = sqlx::query_as!(Space, "SELECT * FROM spaces ORDER BY created_at DESC")
+    State(AppState { pool, quotas, .. }): State<AppState>,
+    user: AuthUser,
+    Query(query): Query<SpaceListQuery>,
+) -> Result<Json<Page<SpaceDetails>>, AppError> {
+    let page = PageRequest::new(
+        query.sort.unwrap_or(SpaceSort::CreatedAt).key(),
+        query.order,
+        query.limit,
+        query.cursor.as_deref(),
+    )?;
+    let push_visible = |builder: &mut QueryBuilder<'_, Postgres>| {
+        builder
+            .push(" WHERE deleted_at IS NULL AND (is_public OR id IN (SELECT space_id FROM space_members WHERE user_id = ")
+            .push_bind(user.id.clone())
+            .push("))");
+    };
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM spaces");
+    push_visible(&mut count);
+    let total: i64 = count
+        .build_query_scalar()
+        .fetch_one(&pool)
+        .await
+        .into_db_error()?;
+
+    let mut select = QueryBuilder::new("SELECT *");
+    page.push_select_keys(&mut select, "id");
+    select.push(" FROM spaces");
+    push_visible(&mut select);
+    page.push_after_cursor(&mut select, "id");
+    page.push_order_and_limit(&mut select, "id");
+    let rows: Vec<Keyed<Space>> = select
+        .build_query_as()
         .fetch_all(&pool)
         .await
         .into_db_error()?;
 
-    Ok(Json::from(rec))
+    let page = page.into_page(rows, total)?;
+    Ok(Json::from(page.map(|space| space.with_usage(&quotas))))
 }
 
 #[derive(Deserialize, FromRow)]
 pub struct CreateSpaceRequest {
-    name: String,
-    description: Option<String>,
+    pub name: String,
+    pub description: Option<String>,
     #[sqlx(default)]
-    is_public: Option<bool>,
-    access_code: Option<String>,
+    pub is_public: Option<bool>,
+    pub access_code: Option<String>,
+    pub strip_gps: Option<bool>,
+    #[sqlx(skip)]
+    pub conflict_policy: Option<ConflictPolicy>,
+    pub quota_bytes: Option<i64>,
+    pub max_file_size_bytes: Option<i64>,
 }
 
-#[debug_handler()]
-pub async fn spaces_post(
-    State(AppState {
-        pool,
-        upload_path: _,
-    }): State<AppState>,
-    Json(payload): Json<CreateSpaceRequest>,
-) -> Result<Json<Space>, AppError> {
+/// Adds a space owned by `owner_id`.
+pub(crate) async fn create_space(
+    pool: &PgPool,
+    owner_id: &str,
+    payload: CreateSpaceRequest,
+) -> Result<Space, AppError> {
+    check_limits(payload.quota_bytes, payload.max_file_size_bytes)?;
     let id = uuid::Uuid::new_v4().to_string();
+    let access_code_hash = hash_access_code(payload.access_code).await?;
+    let mut tx = pool.begin().await.into_db_error()?;
 
     let rec = sqlx::query_as!(
         Space,
-        "INSERT INTO spaces (id, name, description, is_public, access_code) VALUES ($1, $2, $3, $4, $5) RETURNING *",
+        "INSERT INTO spaces (id, name, description, is_public, access_code_hash, strip_gps, conflict_policy, quota_bytes, max_file_size_bytes) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *",
         id,
         payload.name,
         payload.description,
         payload.is_public.unwrap_or(false),
-        payload.access_code
+        access_code_hash,
+        payload.strip_gps.unwrap_or(false),
+        payload.conflict_policy.unwrap_or(ConflictPolicy::KeepBoth).as_str(),
+        payload.quota_bytes,
+        payload.max_file_size_bytes
     )
-    .fetch_one(&pool)
+    .fetch_one(&mut *tx)
     .await.into_db_error()?;
 
-    Ok(Json::from(rec))
+    // whoever creates a space owns it
+    sqlx::query!(
+        "INSERT INTO space_members (space_id, user_id, role) VALUES ($1, $2, $3)",
+        id,
+        owner_id,
+        SpaceRole::Owner as SpaceRole
+    )
+    .execute(&mut *tx)
+    .await
+    .into_db_error()?;
+
+    tx.commit().await.into_db_error()?;
+
+    Ok(rec)
 }
 
 #[debug_handler()]
-pub async fn spaces_get_one(
-    Path(space_id): Path<String>,
+pub async fn spaces_post(
     State(AppState {
-        pool,
-        upload_path: _,
+        pool, auth, quotas, ..
     }): State<AppState>,
-) -> Result<Json<Option<Space>>, AppError> {
-    let rec = sqlx::query_as!(Space, "SELECT * FROM spaces WHERE id = $1", space_id)
-        .fetch_optional(&pool)
-        .await
-        .into_db_error()?;
+    user: AuthUser,
+    Json(payload): Json<CreateSpaceRequest>,
+) -> Result<Json<SpaceDetails>, AppError> {
+    require_limits_admin(
+        &auth,
+        &user,
+        payload.quota_bytes.is_some() || payload.max_file_size_bytes.is_some(),
+    )?;
+    let rec = create_space(&pool, &user.id, payload).await?;
 
-    Ok(Json::from(rec))
+    Ok(Json::from(rec.with_usage(&quotas)))
+}
+
+#[debug_handler()]
+pub async fn spaces_get_one(
+    Path(space_id): Path<String>,
+    State(AppState { pool, quotas, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+) -> Result<Json<Option<SpaceDetails>>, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let rec = sqlx::query_as!(
+        Space,
+        "SELECT * FROM spaces WHERE id = $1 AND deleted_at IS NULL",
+        space_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?;
+
+    Ok(Json::from(rec.map(|space| space.with_usage(&quotas))))
 }
 
 #[derive(Deserialize, FromRow)]
@@ -98,18 +293,45 @@ pub struct UpdateSpaceRequest {
     #[sqlx(default)]
     is_public: Option<bool>,
     access_code: Option<String>,
+    strip_gps: Option<bool>,
+    #[sqlx(skip)]
+    conflict_policy: Option<ConflictPolicy>,
+    /// `null` goes back to the instance default.
+    #[serde(default, deserialize_with = "double_option")]
+    #[sqlx(skip)]
+    quota_bytes: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "double_option")]
+    #[sqlx(skip)]
+    max_file_size_bytes: Option<Option<i64>>,
 }
 
 #[debug_handler()]
 pub async fn spaces_update(
     State(AppState {
         pool,
-        upload_path: _,
+        events,
+        auth,
+        quotas,
+        ..
     }): State<AppState>,
+    user: AuthUser,
 
     Path(space_id): Path<String>,
     Json(payload): Json<UpdateSpaceRequest>,
-) -> Result<Json<Space>, AppError> {
+) -> Result<Json<SpaceDetails>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Owner).await?;
+    let change_quota = payload.quota_bytes.is_some();
+    let change_max_file_size = payload.max_file_size_bytes.is_some();
+    require_limits_admin(&auth, &user, change_quota || change_max_file_size)?;
+    check_limits(
+        payload.quota_bytes.flatten(),
+        payload.max_file_size_bytes.flatten(),
+    )?;
+
+    let change_access_code = payload.access_code.is_some();
+    let access_code_hash = hash_access_code(payload.access_code).await?;
+    let mut tx = pool.begin().await.into_db_error()?;
+
     let rec = sqlx::query_as!(
         Space,
         r#"
@@ -118,7 +340,11 @@ pub async fn spaces_update(
             name = COALESCE($2, name),
             description = COALESCE($3, description),
             is_public = COALESCE($4, is_public),
-            access_code = COALESCE($5, access_code)
+            access_code_hash = CASE WHEN $5 THEN $6 ELSE access_code_hash END,
+            strip_gps = COALESCE($7, strip_gps),
+            conflict_policy = COALESCE($8, conflict_policy),
+            quota_bytes = CASE WHEN $9 THEN $10 ELSE quota_bytes END,
+            max_file_size_bytes = CASE WHEN $11 THEN $12 ELSE max_file_size_bytes END
         WHERE id = $1
         RETURNING *;
         "#,
@@ -126,27 +352,48 @@ pub async fn spaces_update(
         payload.name,
         payload.description,
         payload.is_public,
-        payload.access_code
+        change_access_code,
+        access_code_hash,
+        payload.strip_gps,
+        payload.conflict_policy.map(ConflictPolicy::as_str),
+        change_quota,
+        payload.quota_bytes.flatten(),
+        change_max_file_size,
+        payload.max_file_size_bytes.flatten()
     )
-    .fetch_one(&pool)
+    .fetch_one(&mut *tx)
     .await
     .into_db_error()?;
 
+    // whoever unlocked the space with the old code has to do it again
+    if change_access_code {
+        sqlx::query!("DELETE FROM space_tokens WHERE space_id = $1", space_id)
+            .execute(&mut *tx)
+            .await
+            .into_db_error()?;
+    }
+
+    tx.commit().await.into_db_error()?;
+
+    let rec = rec.with_usage(&quotas);
+    events.emit(&space_id, SpaceEvent::SpaceUpdated { space: rec.clone() });
+
     Ok(Json::from(rec))
 }
 
+/// Moves a space to the trash together with its files, it is purged once the trash retention period is over.
 #[debug_handler()]
 pub async fn spaces_delete(
-    State(AppState {
-        pool,
-        upload_path: _,
-    }): State<AppState>,
+    State(AppState { pool, events, .. }): State<AppState>,
+    user: AuthUser,
     Path(space_id): Path<String>,
 ) -> Result<Json<Option<Space>>, AppError> {
+    require_role(&pool, &space_id, &user, SpaceRole::Owner).await?;
+
     let rec = sqlx::query_as!(
         Space,
         r#"
-        DELETE FROM spaces WHERE id = $1
+        UPDATE spaces SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL
         RETURNING *;
         "#,
         space_id
@@ -155,5 +402,282 @@ pub async fn spaces_delete(
     .await
     .into_db_error()?;
 
+    if rec.is_some() {
+        events.emit(&space_id, SpaceEvent::SpaceDeleted);
+    }
+
+    Ok(Json::from(rec))
+}
+
+/// The spaces in the trash the caller owns, most recently deleted first.
+#[debug_handler()]
+pub async fn spaces_trash_get(
+    State(AppState {
+        pool,
+        trash_retention,
+        ..
+    }): State<AppState>,
+    user: AuthUser,
+) -> Result<Json<Vec<Trashed<Space>>>, AppError> {
+    let spaces = sqlx::query_as!(
+        Space,
+        r#"
+        SELECT spaces.* FROM spaces
+        JOIN space_members ON space_members.space_id = spaces.id
+        WHERE spaces.deleted_at IS NOT NULL AND space_members.user_id = $1 AND space_members.role = $2
+            AND spaces.id NOT IN (SELECT space_id FROM space_purges)
+        ORDER BY spaces.deleted_at DESC, spaces.id
+        "#,
+        user.id,
+        SpaceRole::Owner as SpaceRole
+    )
+    .fetch_all(&pool)
+    .await
+    .into_db_error()?;
+
+    Ok(Json::from(
+        spaces
+            .into_iter()
+            .map(|space| Trashed::new(space.deleted_at, trash_retention, space))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Checks that `user` owns `space_id` and the space is in the trash, not already being deleted for good.
+/// Locks the space until `tx` ends. [`require_role`] only knows spaces outside the trash.
+async fn require_trashed_owner(
+    tx: &mut PgTransaction<'_>,
+    space_id: &str,
+    user: &AuthUser,
+) -> Result<(), AppError> {
+    let role = sqlx::query_scalar!(
+        r#"
+        SELECT space_members.role AS "role: SpaceRole" FROM spaces
+        JOIN space_members ON space_members.space_id = spaces.id AND space_members.user_id = $2
+        WHERE spaces.id = $1 AND spaces.deleted_at IS NOT NULL
+            AND spaces.id NOT IN (SELECT space_id FROM space_purges)
+        FOR UPDATE OF spaces
+        "#,
+        space_id,
+        user.id
+    )
+    .fetch_optional(&mut **tx)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Space not found in the trash".into()),
+            anyhow!(
+                "Space {} is not in the trash or {} isn't a member",
+                space_id,
+                user.id
+            ),
+        )
+    })?;
+    if role < SpaceRole::Owner {
+        return Err(AppError::new(
+            ErrorType::Authorization("This requires the Owner role in the space".into()),
+            anyhow!(
+                "User {} has role {:?} in space {}, needs Owner",
+                user.id,
+                role,
+                space_id
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[debug_handler()]
+pub async fn spaces_restore(
+    State(AppState {
+        pool,
+        events,
+        quotas,
+        ..
+    }): State<AppState>,
+    user: AuthUser,
+    Path(space_id): Path<String>,
+) -> Result<Json<SpaceDetails>, AppError> {
+    let mut tx = pool.begin().await.into_db_error()?;
+    require_trashed_owner(&mut tx, &space_id, &user).await?;
+
+    let rec = sqlx::query_as!(
+        Space,
+        "UPDATE spaces SET deleted_at = NULL WHERE id = $1 RETURNING *",
+        space_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .into_db_error()?;
+
+    tx.commit().await.into_db_error()?;
+
+    let rec = rec.with_usage(&quotas);
+    events.emit(&space_id, SpaceEvent::SpaceUpdated { space: rec.clone() });
+
     Ok(Json::from(rec))
 }
+
+/// Deletes a space in the trash for good without waiting for the retention period.
+/// The files and blobs are removed in the background, the space is gone from the trash right away.
+#[debug_handler()]
+pub async fn spaces_trash_delete(
+    State(AppState { pool, purger, .. }): State<AppState>,
+    user: AuthUser,
+    Path(space_id): Path<String>,
+) -> Result<(StatusCode, Json<Space>), AppError> {
+    let mut tx = pool.begin().await.into_db_error()?;
+    require_trashed_owner(&mut tx, &space_id, &user).await?;
+
+    let rec = sqlx::query_as!(Space, "SELECT * FROM spaces WHERE id = $1", space_id)
+        .fetch_one(&mut *tx)
+        .await
+        .into_db_error()?;
+    sqlx::query!(
+        "INSERT INTO space_purges (space_id) VALUES ($1) ON CONFLICT (space_id) DO NOTHING",
+        space_id
+    )
+    .execute(&mut *tx)
+    .await
+    .into_db_error()?;
+
+    tx.commit().await.into_db_error()?;
+    purger.wake();
+
+    Ok((StatusCode::ACCEPTED, Json::from(rec)))
+}
+
+#[derive(Deserialize)]
+pub struct UnlockSpaceRequest {
+    access_code: String,
+}
+
+#[derive(Serialize)]
+pub struct SpaceUnlock {
+    token: String,
+    #[serde(with = "time::serde::rfc3339")]
+    expires_at: OffsetDateTime,
+}
+
+#[debug_handler()]
+pub async fn spaces_unlock(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    Path(space_id): Path<String>,
+    Json(payload): Json<UnlockSpaceRequest>,
+) -> Result<Json<SpaceUnlock>, AppError> {
+    let rec = sqlx::query_as!(
+        Space,
+        "SELECT * FROM spaces WHERE id = $1 AND deleted_at IS NULL",
+        space_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Space not found".into()),
+            anyhow!("Couldn't find requested Space"),
+        )
+    })?;
+
+    let Some(access_code_hash) = rec.access_code_hash else {
+        return Err(AppError::new(
+            ErrorType::Validation("This space has no access code".into()),
+            anyhow!("Unlock attempted on space {} without access code", space_id),
+        ));
+    };
+
+    // counted as a failure up front, so parallel guesses can't all get in before the first one fails
+    let space_failures = sqlx::query_scalar!(
+        r#"
+        SELECT COALESCE(SUM(LEAST(failures, $2)), 0) AS "failures!" FROM space_unlock_failures
+        WHERE space_id = $1 AND last_failed_at > NOW() - INTERVAL '1 hour'
+        "#,
+        space_id,
+        FREE_UNLOCK_ATTEMPTS
+    )
+    .fetch_one(&pool)
+    .await
+    .into_db_error()?;
+    let counted = sqlx::query_scalar!(
+        r#"
+        INSERT INTO space_unlock_failures (space_id, user_id, failures, last_failed_at)
+        VALUES ($1, $2, 1, NOW())
+        ON CONFLICT (space_id, user_id) DO UPDATE
+        SET failures = space_unlock_failures.failures + 1, last_failed_at = NOW()
+        WHERE space_unlock_failures.failures < $3
+            OR space_unlock_failures.last_failed_at + make_interval(secs => LEAST(
+                $4, power(2, LEAST(space_unlock_failures.failures - $3, 16))
+            )) <= NOW()
+        RETURNING failures
+        "#,
+        space_id,
+        user.id,
+        FREE_UNLOCK_ATTEMPTS,
+        MAX_UNLOCK_BACKOFF_SECONDS
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?;
+    if counted.is_none() || space_failures >= SPACE_UNLOCK_FAILURES_PER_HOUR {
+        return Err(AppError::new(
+            ErrorType::TooManyRequests("Too many wrong access codes, try again later".into()),
+            anyhow!(
+                "Unlock of space {} by user {} refused, {} failures in the last hour",
+                space_id,
+                user.id,
+                space_failures
+            ),
+        ));
+    }
+
+    if !verify_password(payload.access_code, access_code_hash).await? {
+        return Err(AppError::new(
+            ErrorType::Authorization("Wrong access code".into()),
+            anyhow!(
+                "User {} sent a wrong access code for space {}",
+                user.id,
+                space_id
+            ),
+        ));
+    }
+
+    sqlx::query!(
+        "DELETE FROM space_unlock_failures WHERE space_id = $1 AND user_id = $2",
+        space_id,
+        user.id
+    )
+    .execute(&pool)
+    .await
+    .into_db_error()?;
+
+    // opportunistic cleanup, same as for sessions
+    sqlx::query!("DELETE FROM space_tokens WHERE expires_at < NOW()")
+        .execute(&pool)
+        .await
+        .into_db_error()?;
+    sqlx::query!(
+        "DELETE FROM space_unlock_failures WHERE last_failed_at < NOW() - INTERVAL '1 day'"
+    )
+    .execute(&pool)
+    .await
+    .into_db_error()?;
+
+    let token = generate_token();
+    let expires_at = OffsetDateTime::now_utc() + SPACE_TOKEN_TTL;
+    sqlx::query!(
+        "INSERT INTO space_tokens (token_hash, space_id, user_id, expires_at) VALUES ($1, $2, $3, $4)",
+        hash_token(&token),
+        space_id,
+        user.id,
+        expires_at
+    )
+    .execute(&pool)
+    .await
+    .into_db_error()?;
+
+    Ok(Json::from(SpaceUnlock { token, expires_at }))
+}
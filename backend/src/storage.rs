@@ -0,0 +1,246 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::stream::{BoxStream, StreamExt};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    errors::{AppError, IntoAppError},
+    s3::S3Store,
+};
+
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+// quarantined blobs are kept under this prefix, out of the way of everything else
+const QUARANTINE_PREFIX: &str = "quarantine";
+
+pub(crate) fn quarantine_key(key: &str) -> String {
+    format!("{}/{}", QUARANTINE_PREFIX, key)
+}
+
+/// An object in a blob store, as listed by [`BlobStore::list`].
+pub struct StoredObject {
+    pub key: String,
+    pub size_bytes: u64,
+    pub modified: OffsetDateTime,
+}
+
+/// Where blobs (files named by their checksum) are kept.
+/// Uploads are always staged in the local upload directory first and handed over with `put`.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores the fully written local file at `source` as `key`. `source` is consumed.
+    async fn put(&self, key: &str, source: &Path) -> Result<(), AppError>;
+
+    async fn get(&self, key: &str) -> Result<ByteStream, AppError>;
+
+    /// Streams `len` bytes of `key` starting at `start`.
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ByteStream, AppError>;
+
+    /// Removes `key`. Removing a blob that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError>;
+
+    /// Every blob and thumbnail in the store, quarantined ones left out.
+    async fn list(&self) -> Result<Vec<StoredObject>, AppError>;
+
+    /// Moves `key` out of the way. It is kept for inspection but no longer served.
+    async fn quarantine(&self, key: &str) -> Result<(), AppError>;
+}
+
+/// Stores blobs as plain files in a local directory.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalStore {
+    async fn put(&self, key: &str, source: &Path) -> Result<(), AppError> {
+        tokio::fs::rename(source, self.path(key))
+            .await
+            .into_internal_error()
+            .map_err(|e| e.with_context(format!("moving upload to blob {}", key)))
+    }
+
+    async fn get(&self, key: &str) -> Result<ByteStream, AppError> {
+        let file = File::open(self.path(key)).await.into_internal_error()?;
+        Ok(ReaderStream::new(file).boxed())
+    }
+
+    async fn get_range(&self, key: &str, start: u64, len: u64) -> Result<ByteStream, AppError> {
+        let mut file = File::open(self.path(key)).await.into_internal_error()?;
+        file.seek(SeekFrom::Start(start))
+            .await
+            .into_internal_error()?;
+        Ok(ReaderStream::new(file.take(len)).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e)
+                .into_internal_error()
+                .map_err(|e| e.with_context(format!("removing blob {}", key))),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        tokio::fs::try_exists(self.path(key))
+            .await
+            .into_internal_error()
+    }
+
+    async fn list(&self) -> Result<Vec<StoredObject>, AppError> {
+        let mut objects = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.root)
+            .await
+            .into_internal_error()?;
+        while let Some(entry) = entries.next_entry().await.into_internal_error()? {
+            let key = entry.file_name().to_string_lossy().into_owned();
+            // uploads in progress are staged as dot files next to the blobs
+            if key.starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata().await.into_internal_error()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            objects.push(StoredObject {
+                key,
+                size_bytes: metadata.len(),
+                modified: metadata.modified().into_internal_error()?.into(),
+            });
+        }
+        Ok(objects)
+    }
+
+    async fn quarantine(&self, key: &str) -> Result<(), AppError> {
+        let quarantine = self.root.join(QUARANTINE_PREFIX);
+        tokio::fs::create_dir_all(&quarantine)
+            .await
+            .into_internal_error()?;
+        tokio::fs::rename(self.path(key), self.path(&quarantine_key(key)))
+            .await
+            .into_internal_error()
+            .map_err(|e| e.with_context(format!("quarantining blob {}", key)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Blobs are kept inside the upload directory.
+    #[default]
+    Local,
+    S3,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "local" => Ok(StorageBackend::Local),
+            "s3" => Ok(StorageBackend::S3),
+            other => Err(format!(
+                "unknown storage backend {:?}, use local or s3",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub s3: S3Config,
+}
+
+/// Only needed with the `s3` backend.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    /// e.g. `http://localhost:9000` for the MinIO from `compose.yml`
+    pub endpoint: String,
+    /// Has to exist already.
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// `false` for virtual-hosted style (`bucket.host`) URLs.
+    pub path_style: bool,
+    /// Keeps the blobs under `prefix/` so the bucket can be shared. Everything under it
+    /// is expected to belong to spaces, see `maintenance.remove_orphans`.
+    pub prefix: String,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            path_style: true,
+            prefix: String::new(),
+        }
+    }
+}
+
+/// `prefix` with a single trailing `/`, or nothing for an empty prefix.
+fn key_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+/// Builds the blob store selected in `config`.
+pub fn blob_store(
+    config: &StorageConfig,
+    upload_path: &str,
+) -> Result<Arc<dyn BlobStore>, AppError> {
+    match config.backend {
+        StorageBackend::Local => Ok(Arc::new(LocalStore::new(upload_path))),
+        StorageBackend::S3 => {
+            let s3 = &config.s3;
+            let store = S3Store::new(
+                &s3.endpoint,
+                s3.bucket.clone(),
+                s3.region.clone(),
+                s3.access_key_id.clone(),
+                s3.secret_access_key.clone(),
+                s3.path_style,
+                key_prefix(&s3.prefix),
+            )?;
+            Ok(Arc::new(store))
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::{io::Cursor, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    debug_handler,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use image::{
+    DynamicImage, ImageDecoder, ImageReader, ImageResult, RgbImage,
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tokio::{io::AsyncWriteExt, sync::Semaphore};
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    errors::{AppError, ErrorType, IntoAppError},
+    members::{SpaceRole, SpaceToken, require_file_access},
+    ranges::etag_matches,
+    storage::BlobStore,
+};
+
+/// Longest edge of the generated thumbnails in pixels, the first one is the default.
+pub const THUMBNAIL_SIZES: [u32; 3] = [256, 512, 1024];
+// bigger images are not decoded at all, that would take too much memory
+const MAX_SOURCE_BYTES: i64 = 64 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 80;
+// decoding is CPU heavy, don't let a burst of uploads starve the request handlers
+const CONCURRENT_RENDERS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    Jpeg,
+    /// Lossless, the only WebP encoding the image crate has, so mostly bigger than the JPEG.
+    /// Only served when asked for, for the transparency it keeps.
+    Webp,
+}
+
+impl ThumbnailFormat {
+    pub const ALL: [ThumbnailFormat; 2] = [ThumbnailFormat::Jpeg, ThumbnailFormat::Webp];
+
+    fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Webp => "webp",
+        }
+    }
+
+    fn mime_type(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+        }
+    }
+}
+
+/// Thumbnails are named after the blob they show, so files sharing a blob share its thumbnails.
+pub fn thumbnail_key(checksum: &str, size: u32, format: ThumbnailFormat) -> String {
+    format!("{}-{}.{}", checksum, size, format.extension())
+}
+
+/// The blob a key of the blob store belongs to, the blob itself or one of its thumbnails.
+pub(crate) fn blob_of_key(key: &str) -> &str {
+    ThumbnailFormat::ALL
+        .iter()
+        .find_map(|format| key.strip_suffix(&format!(".{}", format.extension())))
+        .and_then(|stem| stem.rsplit_once('-'))
+        .filter(|(_, size)| THUMBNAIL_SIZES.iter().any(|s| s.to_string() == *size))
+        .map_or(key, |(checksum, _)| checksum)
+}
+
+pub(crate) fn is_image(mime_type: Option<&str>) -> bool {
+    mime_type.is_some_and(|mime| mime.starts_with("image/"))
+}
+
+/// Decodes an image and encodes it in every thumbnail size and format.
+fn render(data: &[u8]) -> ImageResult<Vec<(u32, ThumbnailFormat, Vec<u8>)>> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()?;
+    // phones store photos sideways and note the rotation in the EXIF data
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    // 8 bits per channel is all both encoders take
+    let image = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+
+    let mut thumbnails = Vec::new();
+    for size in THUMBNAIL_SIZES {
+        let thumbnail = if image.width() <= size && image.height() <= size {
+            image.clone()
+        } else {
+            image.thumbnail(size, size)
+        };
+
+        let mut webp = Vec::new();
+        thumbnail.write_with_encoder(WebPEncoder::new_lossless(&mut webp))?;
+        thumbnails.push((size, ThumbnailFormat::Webp, webp));
+
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+            .encode_image(&without_alpha(thumbnail))?;
+        thumbnails.push((size, ThumbnailFormat::Jpeg, jpeg));
+    }
+    Ok(thumbnails)
+}
+
+/// JPEG has no transparency, blends `image` onto white.
+fn without_alpha(image: DynamicImage) -> DynamicImage {
+    let DynamicImage::ImageRgba8(rgba) = image else {
+        return image;
+    };
+    DynamicImage::ImageRgb8(RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+        image::Rgb([blend(r), blend(g), blend(b)])
+    }))
+}
+
+/// Generates the thumbnails of image blobs, either in the background right after an upload
+/// or on demand for blobs that were stored before thumbnails existed.
+#[derive(Clone)]
+pub struct Thumbnailer {
+    pool: PgPool,
+    store: Arc<dyn BlobStore>,
+    upload_path: String,
+    permits: Arc<Semaphore>,
+}
+
+impl Thumbnailer {
+    pub fn new(pool: PgPool, store: Arc<dyn BlobStore>, upload_path: String) -> Self {
+        Self {
+            pool,
+            store,
+            upload_path,
+            permits: Arc::new(Semaphore::new(CONCURRENT_RENDERS)),
+        }
+    }
+
+    /// Starts generating the thumbnails of `checksum` if it is an image.
+    pub fn schedule(&self, checksum: &str, mime_type: Option<&str>) {
+        if !is_image(mime_type) {
+            return;
+        }
+        let thumbnailer = self.clone();
+        let checksum = checksum.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = thumbnailer.generate(&checksum).await {
+                tracing::warn!("Generating thumbnails of {} failed: {:?}", checksum, e);
+            }
+        });
+    }
+
+    /// Makes sure the thumbnails of `checksum` exist, returns false if the blob can't have any.
+    async fn generate(&self, checksum: &str) -> Result<bool, AppError> {
+        let _permit = self.permits.acquire().await.into_internal_error()?;
+
+        let Some(blob) = sqlx::query!(
+            "SELECT size_bytes, thumbnail_status FROM blobs WHERE checksum = $1",
+            checksum
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .into_db_error()?
+        else {
+            // purged in the meantime
+            return Ok(false);
+        };
+        match blob.thumbnail_status.as_deref() {
+            Some("ready") => return Ok(true),
+            Some(_) => return Ok(false),
+            None => {}
+        }
+
+        if blob.size_bytes > MAX_SOURCE_BYTES {
+            self.set_status(checksum, "failed").await?;
+            return Ok(false);
+        }
+
+        let mut data = Vec::with_capacity(blob.size_bytes as usize);
+        let mut stream = self.store.get(checksum).await?;
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk.into_internal_error()?);
+        }
+
+        let thumbnails = match tokio::task::spawn_blocking(move || render(&data))
+            .await
+            .into_internal_error()?
+        {
+            Ok(thumbnails) => thumbnails,
+            Err(e) => {
+                tracing::info!("Blob {} is no image that can be decoded: {}", checksum, e);
+                self.set_status(checksum, "failed").await?;
+                return Ok(false);
+            }
+        };
+
+        for (size, format, encoded) in thumbnails {
+            // blob stores take files, stage the thumbnail like an upload
+            let temp_path = std::path::Path::new(&self.upload_path)
+                .join(format!(".{}.thumbnail", uuid::Uuid::new_v4()));
+            let stored = async {
+                let mut file = tokio::fs::File::create(&temp_path)
+                    .await
+                    .into_internal_error()?;
+                file.write_all(&encoded).await.into_internal_error()?;
+                file.flush().await.into_internal_error()?;
+                self.store
+                    .put(&thumbnail_key(checksum, size, format), &temp_path)
+                    .await
+            }
+            .await;
+            if let Err(e) = stored {
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        }
+
+        self.set_status(checksum, "ready").await?;
+        Ok(true)
+    }
+
+    async fn set_status(&self, checksum: &str, status: &str) -> Result<(), AppError> {
+        sqlx::query!(
+            "UPDATE blobs SET thumbnail_status = $2 WHERE checksum = $1",
+            checksum,
+            status
+        )
+        .execute(&self.pool)
+        .await
+        .into_db_error()?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailQuery {
+    size: Option<u32>,
+    /// JPEG when left out.
+    format: Option<ThumbnailFormat>,
+}
+
+#[debug_handler()]
+pub async fn files_thumbnail(
+    State(AppState {
+        pool,
+        store,
+        thumbnails,
+        ..
+    }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(file_id): Path<String>,
+    Query(query): Query<ThumbnailQuery>,
+    request_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    require_file_access(&pool, &file_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let size = query.size.unwrap_or(THUMBNAIL_SIZES[0]);
+    if !THUMBNAIL_SIZES.contains(&size) {
+        return Err(AppError::new(
+            ErrorType::Validation(format!("size must be one of {:?}", THUMBNAIL_SIZES)),
+            anyhow!("Unsupported thumbnail size {}", size),
+        ));
+    }
+
+    let file = sqlx::query!(
+        r#"
+        SELECT files.checksum, files.mime_type, blobs.thumbnail_status FROM files
+        JOIN blobs ON blobs.checksum = files.checksum
+        WHERE files.id = $1 AND files.deleted_at IS NULL
+        "#,
+        file_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("File not found".into()),
+            anyhow!("Requested file not stored in database"),
+        )
+    })?;
+
+    let ready = match file.thumbnail_status.as_deref() {
+        Some("ready") => true,
+        None if is_image(file.mime_type.as_deref()) => thumbnails.generate(&file.checksum).await?,
+        _ => false,
+    };
+    if !ready {
+        return Err(AppError::new(
+            ErrorType::NotFound("This file has no thumbnail".into()),
+            anyhow!("No thumbnail for blob {}", file.checksum),
+        ));
+    }
+
+    let format = query.format.unwrap_or(ThumbnailFormat::Jpeg);
+
+    let mut headers = HeaderMap::new();
+    let etag = format!("\"{}-{}-{}\"", file.checksum, size, format.extension());
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag).into_internal_error()?,
+    );
+    // the thumbnail of a blob never changes
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=31536000, immutable"),
+    );
+
+    let if_none_match = request_headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    if if_none_match.is_some_and(|v| etag_matches(v, &etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.mime_type()),
+    );
+    let body = Body::from_stream(
+        store
+            .get(&thumbnail_key(&file.checksum, size, format))
+            .await?,
+    );
+
+    Ok((StatusCode::OK, headers, body).into_response())
+}
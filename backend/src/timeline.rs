@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use axum::{
+    Json, debug_handler,
+    extract::{Path, Query, State},
+};
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    errors::{AppError, ErrorType, IntoAppError},
+    files::{SpaceFileDetails, list_space_files, with_metadata},
+    members::{SpaceRole, SpaceToken, require_access},
+};
+
+// local date and time without an offset, like "2024-05-17T00:00:00"
+time::serde::format_description!(
+    local_time,
+    PrimitiveDateTime,
+    "[year]-[month]-[day]T[hour]:[minute]:[second]"
+);
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BucketSize {
+    #[default]
+    Day,
+    Hour,
+}
+
+impl BucketSize {
+    /// The `date_trunc` field and the `to_char` pattern of the bucket's label.
+    fn sql(self) -> (&'static str, &'static str) {
+        match self {
+            BucketSize::Day => ("day", "YYYY-MM-DD"),
+            BucketSize::Hour => ("hour", "YYYY-MM-DD\"T\"HH24:00"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TimelineQuery {
+    #[serde(default)]
+    bucket: BucketSize,
+    /// IANA time zone name of the caller, e.g. "Europe/Berlin".
+    tz: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TimelineFile {
+    #[serde(flatten)]
+    file: SpaceFileDetails,
+    /// Capture time if known, upload date otherwise.
+    #[serde(with = "time::serde::rfc3339")]
+    taken_at: OffsetDateTime,
+}
+
+#[derive(Serialize)]
+pub struct TimelineBucket {
+    /// The day or hour, e.g. "2024-05-17" or "2024-05-17T14:00". Files are grouped by their
+    /// local capture time where the file has one, the requested time zone is used otherwise.
+    label: String,
+    /// Beginning of the bucket as a floating local time without an offset, the files in it
+    /// may have been taken in different time zones.
+    #[serde(with = "local_time")]
+    start: PrimitiveDateTime,
+    files: Vec<TimelineFile>,
+}
+
+#[derive(Serialize)]
+pub struct Timeline {
+    bucket: BucketSize,
+    tz: String,
+    buckets: Vec<TimelineBucket>,
+}
+
+/// Files of a space ordered by when they were taken and grouped by day or hour.
+#[debug_handler()]
+pub async fn space_timeline(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(space_id): Path<String>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<Json<Timeline>, AppError> {
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let tz = query.tz.unwrap_or_else(|| "UTC".to_string());
+    let known_zone = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1) AS "exists!""#,
+        tz
+    )
+    .fetch_one(&pool)
+    .await
+    .into_db_error()?;
+    if !known_zone {
+        return Err(AppError::new(
+            ErrorType::Validation("tz must be an IANA time zone like Europe/Berlin".into()),
+            anyhow!("Unknown time zone {}", tz),
+        ));
+    }
+
+    // a photo taken at 23:30 belongs to that day wherever it was taken, so the local capture
+    // time is used as is, everything else is cut in the caller's time zone
+    let (field, label_format) = query.bucket.sql();
+    let rows = sqlx::query!(
+        r#"
+        SELECT id AS "id!", taken_at AS "taken_at!",
+            date_trunc($2, taken_local) AS "start!",
+            to_char(taken_local, $4) AS "label!"
+        FROM (
+            SELECT files.id, files.upload_date,
+                COALESCE(file_metadata.captured_at, files.upload_date) AS taken_at,
+                COALESCE(
+                    file_metadata.captured_local,
+                    COALESCE(file_metadata.captured_at, files.upload_date) AT TIME ZONE $3
+                ) AS taken_local
+            FROM files
+            LEFT JOIN file_metadata ON file_metadata.checksum = files.checksum
+            WHERE files.space_id = $1 AND files.deleted_at IS NULL
+        ) AS taken
+        ORDER BY taken_local, upload_date, id
+        "#,
+        space_id,
+        field,
+        tz,
+        label_format
+    )
+    .fetch_all(&pool)
+    .await
+    .into_db_error()?;
+
+    let mut files: HashMap<String, SpaceFileDetails> =
+        with_metadata(&pool, list_space_files(&pool, &space_id).await?)
+            .await?
+            .into_iter()
+            .map(|details| (details.file.id.clone(), details))
+            .collect();
+
+    let mut buckets: Vec<TimelineBucket> = Vec::new();
+    for row in rows {
+        // uploaded between the two queries
+        let Some(file) = files.remove(&row.id) else {
+            continue;
+        };
+        let file = TimelineFile {
+            file,
+            taken_at: row.taken_at,
+        };
+        match buckets.last_mut() {
+            Some(bucket) if bucket.label == row.label => bucket.files.push(file),
+            _ => buckets.push(TimelineBucket {
+                label: row.label,
+                start: row.start,
+                files: vec![file],
+            }),
+        }
+    }
+
+    Ok(Json::from(Timeline {
+        bucket: query.bucket,
+        tz,
+        buckets,
+    }))
+}
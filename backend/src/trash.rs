@@ -0,0 +1,341 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use tokio::sync::Notify;
+
+use crate::{
+    blobs::{purge_blob, release_blobs},
+    errors::{AppError, IntoAppError},
+    storage::BlobStore,
+    uploads::partial_path,
+};
+
+const DEFAULT_RETENTION_DAYS: u32 = 30;
+// a hundred years, longer ones overflow the dates they are added to
+pub const MAX_RETENTION_DAYS: u32 = 36_500;
+// files (and their versions) deleted per transaction when the trash is emptied or a space is deleted
+const PURGE_BATCH: i64 = 500;
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrashConfig {
+    /// How long deleted files and spaces stay in the trash.
+    pub retention_days: u32,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            retention_days: DEFAULT_RETENTION_DAYS,
+        }
+    }
+}
+
+impl TrashConfig {
+    pub fn retention(&self) -> Duration {
+        Duration::days(self.retention_days.into())
+    }
+}
+
+/// Something in the trash, with the time it is going to be purged at.
+#[derive(Serialize)]
+pub struct Trashed<T> {
+    #[serde(flatten)]
+    item: T,
+    #[serde(with = "time::serde::rfc3339")]
+    purge_at: OffsetDateTime,
+}
+
+impl<T> Trashed<T> {
+    pub fn new(deleted_at: Option<OffsetDateTime>, retention: Duration, item: T) -> Self {
+        let deleted_at = deleted_at.unwrap_or_else(OffsetDateTime::now_utc);
+        Self {
+            item,
+            purge_at: deleted_at + retention,
+        }
+    }
+}
+
+/// Purges what has been in the trash for longer than `retention`, once at startup and then every hour.
+pub fn spawn_purge(
+    pool: PgPool,
+    store: Arc<dyn BlobStore>,
+    purger: SpacePurger,
+    retention: Duration,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_expired(&pool, store.as_ref(), &purger, retention).await {
+                tracing::warn!("Purging the trash failed: {:?}", e);
+            }
+        }
+    });
+}
+
+async fn purge_expired(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    purger: &SpacePurger,
+    retention: Duration,
+) -> Result<(), AppError> {
+    let cutoff = OffsetDateTime::now_utc() - retention;
+
+    // a batch at a time, like spaces, so a full trash doesn't hold a transaction open for long
+    let mut purged = 0;
+    loop {
+        let expired = sqlx::query_scalar!(
+            "SELECT id FROM files WHERE deleted_at < $1 ORDER BY deleted_at, id LIMIT $2",
+            cutoff,
+            PURGE_BATCH
+        )
+        .fetch_all(pool)
+        .await
+        .into_db_error()?;
+        purged += purge_files(pool, store, &expired).await?;
+        if (expired.len() as i64) < PURGE_BATCH {
+            break;
+        }
+    }
+    if purged > 0 {
+        tracing::info!("Purged {} file versions from the trash", purged);
+    }
+
+    let queued = sqlx::query!(
+        r#"
+        INSERT INTO space_purges (space_id) SELECT id FROM spaces WHERE deleted_at < $1
+        ON CONFLICT (space_id) DO NOTHING
+        "#,
+        cutoff
+    )
+    .execute(pool)
+    .await
+    .into_db_error()?;
+    if queued.rows_affected() > 0 {
+        purger.wake();
+    }
+
+    Ok(())
+}
+
+/// Deletes the trashed ones of `file_ids` for good together with their earlier versions, gives
+/// their space back and deletes the blobs left without references. Returns how many versions went.
+pub(crate) async fn purge_files(
+    pool: &PgPool,
+    store: &dyn BlobStore,
+    file_ids: &[String],
+) -> Result<usize, AppError> {
+    let mut tx = pool.begin().await.into_db_error()?;
+
+    // the earlier versions of the files, they would go with ON DELETE CASCADE but hold blob references
+    let files = sqlx::query!(
+        r#"
+        WITH trashed AS (
+            SELECT id, space_id FROM files WHERE id = ANY($1) AND deleted_at IS NOT NULL
+        ),
+        versions AS (
+            DELETE FROM file_versions USING trashed WHERE file_versions.file_id = trashed.id
+            RETURNING trashed.space_id, file_versions.checksum, file_versions.file_size_bytes
+        ),
+        current AS (
+            DELETE FROM files USING trashed WHERE files.id = trashed.id
+            RETURNING files.space_id, files.checksum, files.file_size_bytes
+        )
+        SELECT space_id AS "space_id!", checksum AS "checksum!", file_size_bytes AS "file_size_bytes!"
+        FROM versions
+        UNION ALL
+        SELECT space_id, checksum, file_size_bytes FROM current
+        "#,
+        file_ids
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .into_db_error()?;
+
+    // trashed files keep taking up space until they are gone for good
+    let space_ids: Vec<String> = files.iter().map(|file| file.space_id.clone()).collect();
+    let sizes: Vec<i64> = files.iter().map(|file| file.file_size_bytes).collect();
+    sqlx::query!(
+        r#"
+        UPDATE spaces SET total_size_used_bytes = GREATEST(0, spaces.total_size_used_bytes - f.freed)
+        FROM (
+            SELECT space_id, SUM(size)::BIGINT AS freed FROM UNNEST($1::TEXT[], $2::BIGINT[]) AS f(space_id, size)
+            GROUP BY space_id
+        ) f
+        WHERE spaces.id = f.space_id
+        "#,
+        &space_ids,
+        &sizes
+    )
+    .execute(&mut *tx)
+    .await
+    .into_db_error()?;
+
+    let checksums: Vec<String> = files.iter().map(|file| file.checksum.clone()).collect();
+    let unreferenced = release_blobs(&mut tx, &checksums).await?;
+
+    tx.commit().await.into_db_error()?;
+
+    for checksum in unreferenced {
+        purge_blob(pool, store, &checksum).await?;
+    }
+
+    Ok(files.len())
+}
+
+/// Deletes spaces for good in the background, a batch of files at a time so a huge space
+/// doesn't hold a transaction open for long. The spaces to delete are queued in `space_purges`,
+/// one interrupted by a restart is picked up again where it stopped.
+#[derive(Clone)]
+pub struct SpacePurger {
+    pool: PgPool,
+    store: Arc<dyn BlobStore>,
+    upload_path: String,
+    wake: Arc<Notify>,
+}
+
+impl SpacePurger {
+    pub fn new(pool: PgPool, store: Arc<dyn BlobStore>, upload_path: String) -> Self {
+        Self {
+            pool,
+            store,
+            upload_path,
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Starts working through the queue, right away for what is left over from before a restart.
+    pub fn start(&self) {
+        let purger = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = purger.run_queued().await {
+                    tracing::warn!("Deleting spaces failed: {:?}", e);
+                }
+                tokio::select! {
+                    _ = purger.wake.notified() => {}
+                    _ = tokio::time::sleep(PURGE_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    /// Lets the job know a space was queued.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Deletes every queued space, then the blobs left without references.
+    pub(crate) async fn run_queued(&self) -> Result<(), AppError> {
+        while let Some(space_id) = sqlx::query_scalar!(
+            "SELECT space_id FROM space_purges ORDER BY requested_at, space_id LIMIT 1"
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .into_db_error()?
+        {
+            self.purge_space(&space_id).await?;
+            tracing::info!("Deleted space {} for good", space_id);
+        }
+
+        // blobs released right before a restart didn't get the chance to be removed
+        let unreferenced = sqlx::query_scalar!("SELECT checksum FROM blobs WHERE refcount = 0")
+            .fetch_all(&self.pool)
+            .await
+            .into_db_error()?;
+        for checksum in unreferenced {
+            purge_blob(&self.pool, self.store.as_ref(), &checksum).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn purge_space(&self, space_id: &str) -> Result<(), AppError> {
+        // uploads in progress only exist on disk
+        let uploads = sqlx::query_scalar!("SELECT id FROM uploads WHERE space_id = $1", space_id)
+            .fetch_all(&self.pool)
+            .await
+            .into_db_error()?;
+        for upload_id in uploads {
+            match tokio::fs::remove_file(partial_path(&self.upload_path, &upload_id)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).into_internal_error();
+                }
+                _ => {}
+            }
+        }
+
+        loop {
+            let mut tx = self.pool.begin().await.into_db_error()?;
+
+            // the earlier versions would go with ON DELETE CASCADE but hold blob references
+            let checksums = sqlx::query_scalar!(
+                r#"
+                WITH batch AS (
+                    SELECT id FROM files WHERE space_id = $1 LIMIT $2
+                ),
+                versions AS (
+                    DELETE FROM file_versions USING batch WHERE file_versions.file_id = batch.id
+                    RETURNING file_versions.checksum
+                ),
+                current AS (
+                    DELETE FROM files USING batch WHERE files.id = batch.id
+                    RETURNING files.checksum
+                )
+                SELECT checksum AS "checksum!" FROM versions
+                UNION ALL
+                SELECT checksum FROM current
+                "#,
+                space_id,
+                PURGE_BATCH
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .into_db_error()?;
+
+            if checksums.is_empty() {
+                // waits for uploads still adding a file, the next statement sees what they added
+                sqlx::query!("SELECT id FROM spaces WHERE id = $1 FOR UPDATE", space_id)
+                    .fetch_optional(&mut *tx)
+                    .await
+                    .into_db_error()?;
+                let remaining = sqlx::query_scalar!(
+                    r#"SELECT EXISTS (SELECT 1 FROM files WHERE space_id = $1) AS "remaining!""#,
+                    space_id
+                )
+                .fetch_one(&mut *tx)
+                .await
+                .into_db_error()?;
+                if remaining {
+                    continue;
+                }
+
+                // takes the space_purges row along
+                sqlx::query!("DELETE FROM spaces WHERE id = $1", space_id)
+                    .execute(&mut *tx)
+                    .await
+                    .into_db_error()?;
+                tx.commit().await.into_db_error()?;
+                return Ok(());
+            }
+
+            let unreferenced = release_blobs(&mut tx, &checksums).await?;
+            sqlx::query!(
+                "UPDATE space_purges SET files_purged = files_purged + $2 WHERE space_id = $1",
+                space_id,
+                checksums.len() as i64
+            )
+            .execute(&mut *tx)
+            .await
+            .into_db_error()?;
+            tx.commit().await.into_db_error()?;
+
+            for checksum in unreferenced {
+                purge_blob(&self.pool, self.store.as_ref(), &checksum).await?;
+            }
+        }
+    }
+}
@@ -0,0 +1,774 @@
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    debug_handler,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::IntoResponse,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use time::{OffsetDateTime, UtcOffset, macros::format_description};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    blobs::acquire_blob,
+    errors::{AppError, ErrorType, IntoAppError},
+    events::SpaceEvent,
+    files::NewSpaceFile,
+    folders::{Folder, ensure_folder_path, require_folder, split_relative_path},
+    members::{SpaceRole, SpaceToken, require_access},
+    metadata::{extract_metadata, insert_metadata},
+    quotas::{QuotaConfig, SpaceUsage, reserve_quota},
+    search::{extract_text, insert_text},
+    spaces::Space,
+    storage::BlobStore,
+    versions::{Saved, resolve_conflict, save_space_file},
+};
+
+// resumable uploads following https://tus.io/protocols/resumable-upload
+// (core protocol plus the creation, termination and expiration extensions)
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+// how often a PATCH still streaming its body renews its claim, well within the 5 minutes after
+// which the migration's claims count as stale
+const CLAIM_REFRESH: std::time::Duration = std::time::Duration::from_secs(60);
+// how long an upload is kept without progress, every PATCH pushes it back
+const UPLOAD_EXPIRY: time::Duration = time::Duration::days(1);
+const EXPIRY_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+struct Upload {
+    id: String,
+    space_id: String,
+    original_filename: String,
+    mime_type: Option<String>,
+    upload_length: i64,
+    upload_offset: i64,
+    created_by: Option<String>,
+    folder_id: Option<String>,
+    /// Folders of the relative path of a directory upload, separated by slashes.
+    folder_path: Option<String>,
+    expires_at: OffsetDateTime,
+}
+
+pub(crate) fn partial_path(upload_path: &str, upload_id: &str) -> PathBuf {
+    std::path::Path::new(upload_path).join(format!(".{}.upload", upload_id))
+}
+
+/// Where a complete upload is linked to while it is handed to the blob store.
+fn staged_path(upload_path: &str, upload_id: &str) -> PathBuf {
+    std::path::Path::new(upload_path).join(format!(".{}.finish", upload_id))
+}
+
+async fn remove_if_exists(path: &std::path::Path) -> Result<(), AppError> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).into_internal_error(),
+        _ => Ok(()),
+    }
+}
+
+fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+    headers
+}
+
+/// `Upload-Expires` as an HTTP date.
+fn expires_header(expires_at: OffsetDateTime) -> Result<HeaderValue, AppError> {
+    let date = expires_at
+        .to_offset(UtcOffset::UTC)
+        .format(format_description!(
+            "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+        ))
+        .into_internal_error()?;
+    HeaderValue::from_str(&date).into_internal_error()
+}
+
+fn check_tus_version(headers: &HeaderMap) -> Result<(), AppError> {
+    match headers.get("Tus-Resumable").and_then(|v| v.to_str().ok()) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(AppError::new(
+            ErrorType::Validation(format!("Tus-Resumable must be {}", TUS_VERSION)),
+            anyhow!("Unsupported or missing Tus-Resumable header"),
+        )),
+    }
+}
+
+fn header_i64(headers: &HeaderMap, name: &str) -> Result<i64, AppError> {
+    headers
+        .get(name)
+        .ok_or_else(|| anyhow!("{} header missing", name))
+        .and_then(|v| Ok(v.to_str()?.parse::<i64>()?))
+        .into_validation_error()
+        .and_then(|value| {
+            if value < 0 {
+                Err(AppError::new(
+                    ErrorType::Validation(format!("{} must not be negative", name)),
+                    anyhow!("Negative {}", name),
+                ))
+            } else {
+                Ok(value)
+            }
+        })
+}
+
+/// Looks up a value in an `Upload-Metadata` header (`key base64value,key2 base64value2`).
+fn metadata_value(metadata: &str, key: &str) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        if parts.next()? != key {
+            return None;
+        }
+        let decoded = STANDARD.decode(parts.next().unwrap_or("").trim()).ok()?;
+        String::from_utf8(decoded).ok()
+    })
+}
+
+/// Lets clients discover what the server supports, it needs neither a session nor `Tus-Resumable`.
+#[debug_handler()]
+pub async fn uploads_options(
+    State(AppState { upload_limit, .. }): State<AppState>,
+) -> impl IntoResponse {
+    let mut headers = tus_headers();
+    headers.insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("Tus-Max-Size", HeaderValue::from(upload_limit));
+    headers.insert("Tus-Extension", HeaderValue::from_static(TUS_EXTENSIONS));
+    (StatusCode::NO_CONTENT, headers)
+}
+
+/// Removes uploads that expired together with their partial files, once at startup and then every hour.
+pub fn spawn_expiry(pool: PgPool, upload_path: String) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = remove_expired(&pool, &upload_path).await {
+                tracing::warn!("Removing expired uploads failed: {:?}", e);
+            }
+        }
+    });
+}
+
+async fn remove_expired(pool: &PgPool, upload_path: &str) -> Result<(), AppError> {
+    // one still being written to is left alone, its PATCH extends the expiry when it is done
+    let expired = sqlx::query_scalar!(
+        r#"
+        DELETE FROM uploads
+        WHERE expires_at < NOW() AND (claim IS NULL OR claimed_at < NOW() - INTERVAL '5 minutes')
+        RETURNING id
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .into_db_error()?;
+
+    for upload_id in &expired {
+        remove_if_exists(&partial_path(upload_path, upload_id)).await?;
+    }
+    if !expired.is_empty() {
+        tracing::info!("Removed {} expired uploads", expired.len());
+    }
+
+    Ok(())
+}
+
+#[debug_handler()]
+pub async fn uploads_post(
+    State(AppState {
+        pool,
+        upload_path,
+        upload_limit,
+        store,
+        events,
+        thumbnails,
+        quotas,
+        ..
+    }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(space_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_tus_version(&headers)?;
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Uploader).await?;
+
+    let upload_length = header_i64(&headers, "Upload-Length")?;
+    if upload_length as u64 > upload_limit as u64 {
+        return Err(AppError::new(
+            ErrorType::PayloadTooLarge(format!("Uploads are limited to {} bytes", upload_limit)),
+            anyhow!("Upload-Length {} exceeds Tus-Max-Size", upload_length),
+        ));
+    }
+
+    let metadata = headers
+        .get("Upload-Metadata")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    // directory uploads send the path relative to the uploaded directory along
+    let path = metadata_value(metadata, "relativePath")
+        .or_else(|| metadata_value(metadata, "filename"))
+        .or_else(|| metadata_value(metadata, "name"))
+        .ok_or_else(|| {
+            AppError::new(
+                ErrorType::Validation("Upload-Metadata must contain a filename".into()),
+                anyhow!("No filename in Upload-Metadata"),
+            )
+        })?;
+    let (folder_names, original_filename) = split_relative_path(&path);
+    let folder_path = (!folder_names.is_empty()).then(|| folder_names.join("/"));
+    let folder_id = metadata_value(metadata, "folder");
+    let mime_type =
+        metadata_value(metadata, "filetype").or_else(|| metadata_value(metadata, "type"));
+
+    let space = sqlx::query_as!(
+        Space,
+        "SELECT * from spaces where id = $1 AND deleted_at IS NULL",
+        space_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Space not found".into()),
+            anyhow!("Couldn't find requested Space"),
+        )
+    })?;
+    // checked up front so clients don't send a file that can't be stored, and again when it is complete
+    SpaceUsage::of(&space, &quotas).check_upload(upload_length)?;
+    if let Some(folder_id) = &folder_id {
+        require_folder(&pool, &space_id, folder_id).await?;
+    }
+    check_conflict_early(
+        &pool,
+        &space_id,
+        folder_id.clone(),
+        &folder_names,
+        &original_filename,
+    )
+    .await?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    File::create_new(partial_path(&upload_path, &id))
+        .await
+        .into_internal_error()?;
+
+    let upload = sqlx::query_as!(
+        Upload,
+        r#"
+        INSERT INTO uploads (id, space_id, original_filename, mime_type, upload_length, created_by, folder_id,
+            folder_path, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        RETURNING id, space_id, original_filename, mime_type, upload_length, upload_offset, created_by,
+            folder_id, folder_path, expires_at
+        "#,
+        id,
+        space_id,
+        original_filename,
+        mime_type,
+        upload_length,
+        user.id,
+        folder_id,
+        folder_path,
+        OffsetDateTime::now_utc() + UPLOAD_EXPIRY
+    )
+    .fetch_one(&pool)
+    .await
+    .into_db_error()?;
+
+    if upload.upload_length == 0 {
+        let mut tx = pool.begin().await.into_db_error()?;
+        let (saved, folders) =
+            finish_upload(&mut tx, &upload_path, store.as_ref(), &quotas, &upload).await?;
+        tx.commit().await.into_db_error()?;
+        remove_if_exists(&partial_path(&upload_path, &upload.id)).await?;
+        for folder in folders {
+            events.emit(&upload.space_id, SpaceEvent::FolderCreated { folder });
+        }
+        let file = saved.file();
+        thumbnails.schedule(&file.checksum, file.mime_type.as_deref());
+        events.emit(&upload.space_id, saved.event());
+    }
+
+    let mut response_headers = tus_headers();
+    if upload.upload_length > 0 {
+        response_headers.insert("Upload-Expires", expires_header(upload.expires_at)?);
+    }
+    let location = format!("/api/spaces/{}/uploads/{}", upload.space_id, upload.id);
+    response_headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(&location).into_internal_error()?,
+    );
+    response_headers.insert("Upload-Offset", HeaderValue::from(upload.upload_offset));
+
+    Ok((StatusCode::CREATED, response_headers))
+}
+
+#[debug_handler()]
+pub async fn uploads_head(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path((space_id, upload_id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_tus_version(&headers)?;
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Uploader).await?;
+
+    let upload = sqlx::query_as!(
+        Upload,
+        r#"
+        SELECT id, space_id, original_filename, mime_type, upload_length, upload_offset, created_by,
+            folder_id, folder_path, expires_at
+        FROM uploads WHERE id = $1 AND space_id = $2 AND expires_at > NOW()
+        "#,
+        upload_id,
+        space_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Upload not found".into()),
+            anyhow!("Requested upload not stored in database"),
+        )
+    })?;
+
+    let mut response_headers = tus_headers();
+    response_headers.insert("Upload-Offset", HeaderValue::from(upload.upload_offset));
+    response_headers.insert("Upload-Length", HeaderValue::from(upload.upload_length));
+    response_headers.insert("Upload-Expires", expires_header(upload.expires_at)?);
+    response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+    Ok((StatusCode::OK, response_headers))
+}
+
+#[debug_handler()]
+pub async fn uploads_patch(
+    State(AppState {
+        pool,
+        upload_path,
+        store,
+        events,
+        thumbnails,
+        quotas,
+        ..
+    }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path((space_id, upload_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, AppError> {
+    check_tus_version(&headers)?;
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Uploader).await?;
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if content_type != Some(OFFSET_CONTENT_TYPE) {
+        return Err(AppError::new(
+            ErrorType::Validation(format!("Content-Type must be {}", OFFSET_CONTENT_TYPE)),
+            anyhow!("Invalid Content-Type for PATCH"),
+        ));
+    }
+    let offset = header_i64(&headers, "Upload-Offset")?;
+
+    // the body can take long to arrive, so the upload is claimed instead of locked in a transaction
+    let claim = uuid::Uuid::new_v4().to_string();
+    let upload = claim_upload(&pool, &space_id, &upload_id, &claim).await?;
+    let expires_at = OffsetDateTime::now_utc() + UPLOAD_EXPIRY;
+    let patched = patch_claimed(
+        &pool,
+        &upload_path,
+        store.as_ref(),
+        &quotas,
+        &upload,
+        &claim,
+        offset,
+        expires_at,
+        body,
+    )
+    .await;
+    let (new_offset, finished) = match patched {
+        Ok(patched) => patched,
+        Err(e) => {
+            release_claim(&pool, &upload.id, &claim).await?;
+            return Err(e);
+        }
+    };
+
+    let mut response_headers = tus_headers();
+    response_headers.insert("Upload-Offset", HeaderValue::from(new_offset));
+    if let Some((saved, folders)) = finished {
+        remove_if_exists(&partial_path(&upload_path, &upload.id)).await?;
+        for folder in folders {
+            events.emit(&upload.space_id, SpaceEvent::FolderCreated { folder });
+        }
+        let file = saved.file();
+        thumbnails.schedule(&file.checksum, file.mime_type.as_deref());
+        events.emit(&upload.space_id, saved.event());
+    } else {
+        response_headers.insert("Upload-Expires", expires_header(expires_at)?);
+    }
+
+    Ok((StatusCode::NO_CONTENT, response_headers))
+}
+
+async fn claim_upload(
+    pool: &PgPool,
+    space_id: &str,
+    upload_id: &str,
+    claim: &str,
+) -> Result<Upload, AppError> {
+    let claimed = sqlx::query_as!(
+        Upload,
+        r#"
+        UPDATE uploads SET claim = $3, claimed_at = NOW()
+        WHERE id = $1 AND space_id = $2 AND expires_at > NOW()
+            AND (claim IS NULL OR claimed_at < NOW() - INTERVAL '5 minutes')
+        RETURNING id, space_id, original_filename, mime_type, upload_length, upload_offset, created_by,
+            folder_id, folder_path, expires_at
+        "#,
+        upload_id,
+        space_id,
+        claim
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?;
+    if let Some(upload) = claimed {
+        return Ok(upload);
+    }
+
+    let exists = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (SELECT 1 FROM uploads WHERE id = $1 AND space_id = $2 AND expires_at > NOW())
+            AS "exists!"
+        "#,
+        upload_id,
+        space_id
+    )
+    .fetch_one(pool)
+    .await
+    .into_db_error()?;
+    Err(if exists {
+        AppError::new(
+            ErrorType::Conflict("Upload is locked by another request".into()),
+            anyhow!("Upload {} is claimed by another PATCH", upload_id),
+        )
+    } else {
+        AppError::new(
+            ErrorType::NotFound("Upload not found".into()),
+            anyhow!("Requested upload not stored in database"),
+        )
+    })
+}
+
+async fn release_claim(pool: &PgPool, upload_id: &str, claim: &str) -> Result<(), AppError> {
+    sqlx::query!(
+        "UPDATE uploads SET claim = NULL, claimed_at = NULL WHERE id = $1 AND claim = $2",
+        upload_id,
+        claim
+    )
+    .execute(pool)
+    .await
+    .into_db_error()?;
+    Ok(())
+}
+
+fn claim_lost(upload_id: &str) -> AppError {
+    AppError::new(
+        ErrorType::Conflict("Upload is locked by another request".into()),
+        anyhow!(
+            "Claim on upload {} went stale and was taken over",
+            upload_id
+        ),
+    )
+}
+
+/// Appends the body to a claimed upload and records the new offset, finishing the upload once
+/// it is complete, otherwise its expiry is pushed back to `expires_at`. Returns the new offset and
+/// the finished file.
+#[allow(clippy::too_many_arguments)]
+async fn patch_claimed(
+    pool: &PgPool,
+    upload_path: &str,
+    store: &dyn BlobStore,
+    quotas: &QuotaConfig,
+    upload: &Upload,
+    claim: &str,
+    offset: i64,
+    expires_at: OffsetDateTime,
+    body: Body,
+) -> Result<(i64, Option<(Saved, Vec<Folder>)>), AppError> {
+    if offset != upload.upload_offset {
+        return Err(AppError::new(
+            ErrorType::Conflict(format!(
+                "Upload-Offset {} does not match current offset {}",
+                offset, upload.upload_offset
+            )),
+            anyhow!("Upload-Offset mismatch"),
+        ));
+    }
+
+    let remaining = (upload.upload_length - upload.upload_offset) as u64;
+    let (written, body_error) = append_body(
+        pool,
+        upload,
+        claim,
+        offset as u64,
+        remaining,
+        body,
+        &partial_path(upload_path, &upload.id),
+    )
+    .await?;
+    let new_offset = upload.upload_offset + written as i64;
+    let finishing = body_error.is_none() && new_offset == upload.upload_length;
+
+    let mut tx = pool.begin().await.into_db_error()?;
+    // progress is kept even when the body was cut off, so the client can resume from there
+    sqlx::query!(
+        r#"
+        UPDATE uploads SET upload_offset = $3, expires_at = $4, claim = NULL, claimed_at = NULL
+        WHERE id = $1 AND claim = $2
+        RETURNING id
+        "#,
+        upload.id,
+        claim,
+        new_offset,
+        expires_at
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| claim_lost(&upload.id))?;
+
+    if let Some(e) = body_error {
+        tx.commit().await.into_db_error()?;
+        return Err(e);
+    }
+
+    let finished = if finishing {
+        Some(finish_upload(&mut tx, upload_path, store, quotas, upload).await?)
+    } else {
+        None
+    };
+    tx.commit().await.into_db_error()?;
+
+    Ok((new_offset, finished))
+}
+
+#[debug_handler()]
+pub async fn uploads_delete(
+    State(AppState {
+        pool, upload_path, ..
+    }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path((space_id, upload_id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_tus_version(&headers)?;
+    require_access(&pool, &space_id, &user, &token, SpaceRole::Uploader).await?;
+
+    let upload = sqlx::query!(
+        "DELETE FROM uploads WHERE id = $1 AND space_id = $2 RETURNING id",
+        upload_id,
+        space_id
+    )
+    .fetch_optional(&pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Upload not found".into()),
+            anyhow!("Requested upload not stored in database"),
+        )
+    })?;
+
+    remove_if_exists(&partial_path(&upload_path, &upload.id)).await?;
+
+    Ok((StatusCode::NO_CONTENT, tus_headers()))
+}
+
+/// Appends the request body to the partial file at `offset`, accepting at most `remaining` bytes.
+/// Returns how many bytes were written, plus the error that stopped the body early (if any).
+/// The claim on `upload` is renewed while the body arrives.
+async fn append_body(
+    pool: &PgPool,
+    upload: &Upload,
+    claim: &str,
+    offset: u64,
+    remaining: u64,
+    body: Body,
+    path: &std::path::Path,
+) -> Result<(u64, Option<AppError>), AppError> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await
+        .into_internal_error()?;
+    // drop anything an interrupted request wrote past the last recorded offset
+    file.set_len(offset).await.into_internal_error()?;
+    file.seek(std::io::SeekFrom::Start(offset))
+        .await
+        .into_internal_error()?;
+
+    let mut written: u64 = 0;
+    let mut body_error = None;
+    let mut refreshed = std::time::Instant::now();
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk.into_validation_error() {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                body_error = Some(e);
+                break;
+            }
+        };
+        // checked before writing, a request stalled for long may have lost the upload to another one
+        if refreshed.elapsed() >= CLAIM_REFRESH {
+            let renewed = sqlx::query!(
+                "UPDATE uploads SET claimed_at = NOW() WHERE id = $1 AND claim = $2",
+                upload.id,
+                claim
+            )
+            .execute(pool)
+            .await
+            .into_db_error()?;
+            if renewed.rows_affected() == 0 {
+                return Err(claim_lost(&upload.id));
+            }
+            refreshed = std::time::Instant::now();
+        }
+        if written + chunk.len() as u64 > remaining {
+            body_error = Some(AppError::new(
+                ErrorType::PayloadTooLarge("Body exceeds the declared Upload-Length".into()),
+                anyhow!("Received more bytes than Upload-Length"),
+            ));
+            break;
+        }
+        file.write_all(&chunk).await.into_internal_error()?;
+        written += chunk.len() as u64;
+    }
+    file.sync_all().await.into_internal_error()?;
+
+    Ok((written, body_error))
+}
+
+async fn hash_file(path: &std::path::Path) -> Result<String, AppError> {
+    let mut file = File::open(path).await.into_internal_error()?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await.into_internal_error()?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Applies the conflict policy before any of the file is sent, so a client doesn't upload all of it
+/// only to have it rejected for its name. Nothing is kept, `finish_upload` checks again at the end.
+async fn check_conflict_early(
+    pool: &PgPool,
+    space_id: &str,
+    parent: Option<String>,
+    folder_names: &[String],
+    original_filename: &str,
+) -> Result<(), AppError> {
+    let mut tx = pool.begin().await.into_db_error()?;
+    let (folder_id, _) = ensure_folder_path(&mut tx, space_id, parent, folder_names).await?;
+    resolve_conflict(
+        &mut tx,
+        &NewSpaceFile {
+            space_id,
+            folder_id: folder_id.as_deref(),
+            original_filename: Some(original_filename.to_string()),
+            file_size_bytes: 0,
+            // not known before the upload is complete, the policy only looks at the name
+            checksum: "",
+            mime_type: None,
+            uploaded_by: None,
+        },
+    )
+    .await?;
+    // the folders created for the check go again
+    tx.rollback().await.into_db_error()
+}
+
+/// Turns a complete upload into a regular `files` row, deduplicating its blob by checksum.
+/// Also returns the folders created for its relative path. The partial file is left in place for
+/// the caller to remove once `tx` has committed, so an upload that failed to finish can be finished again.
+async fn finish_upload(
+    tx: &mut sqlx::PgTransaction<'_>,
+    upload_path: &str,
+    store: &dyn BlobStore,
+    quotas: &QuotaConfig,
+    upload: &Upload,
+) -> Result<(Saved, Vec<Folder>), AppError> {
+    let partial = partial_path(upload_path, &upload.id);
+    let checksum = hash_file(&partial).await?;
+    let metadata = extract_metadata(partial.clone(), &checksum, upload.mime_type.clone()).await?;
+    let text = extract_text(partial.clone(), upload.mime_type.clone()).await;
+
+    let folder_names: Vec<String> = match &upload.folder_path {
+        Some(path) => path.split('/').map(str::to_string).collect(),
+        None => Vec::new(),
+    };
+    let (folder_id, folders) = ensure_folder_path(
+        tx,
+        &upload.space_id,
+        upload.folder_id.clone(),
+        &folder_names,
+    )
+    .await?;
+    let new_file = NewSpaceFile {
+        space_id: &upload.space_id,
+        folder_id: folder_id.as_deref(),
+        original_filename: Some(upload.original_filename.clone()),
+        file_size_bytes: upload.upload_length,
+        checksum: &checksum,
+        mime_type: upload.mime_type.clone(),
+        uploaded_by: upload.created_by.as_deref(),
+    };
+    let resolution = resolve_conflict(tx, &new_file).await?;
+
+    // the store takes a link to the partial file instead of the file itself
+    let staged = staged_path(upload_path, &upload.id);
+    remove_if_exists(&staged).await?;
+    if tokio::fs::hard_link(&partial, &staged).await.is_err() {
+        tokio::fs::copy(&partial, &staged)
+            .await
+            .into_internal_error()?;
+    }
+    let acquired = acquire_blob(tx, store, &staged, &checksum, upload.upload_length).await;
+    // left over when the store copied it, or when handing it over failed
+    remove_if_exists(&staged).await?;
+    acquired?;
+    insert_metadata(tx, &metadata).await?;
+    insert_text(tx, &checksum, text.as_deref()).await?;
+    let saved = save_space_file(tx, resolution, new_file).await?;
+
+    sqlx::query!("DELETE FROM uploads WHERE id = $1", upload.id)
+        .execute(&mut **tx)
+        .await
+        .into_db_error()?;
+    reserve_quota(tx, &upload.space_id, upload.upload_length, quotas).await?;
+
+    Ok((saved, folders))
+}
@@ -0,0 +1,353 @@
+use anyhow::anyhow;
+use axum::{
+    Json, debug_handler,
+    extract::{Path, State},
+    http::{HeaderMap, Method},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, PgTransaction};
+use time::OffsetDateTime;
+
+use crate::{
+    AppState,
+    auth::AuthUser,
+    blobs::reference_blob,
+    errors::{AppError, ErrorType, IntoAppError},
+    events::SpaceEvent,
+    files::{
+        NewSpaceFile, SpaceFile, get_space_file, insert_space_file, replace_space_file, serve_file,
+    },
+    members::{SpaceRole, SpaceToken, require_file_access, require_file_role},
+    quotas::reserve_quota,
+};
+
+/// What happens when a file is uploaded next to one with the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// The new file gets a numbered name, "itinerary (1).pdf".
+    KeepBoth,
+    /// The new file becomes the current version of the existing one.
+    NewVersion,
+    Reject,
+}
+
+impl ConflictPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConflictPolicy::KeepBoth => "keep_both",
+            ConflictPolicy::NewVersion => "new_version",
+            ConflictPolicy::Reject => "reject",
+        }
+    }
+}
+
+// the column is constrained to the values of as_str
+impl From<String> for ConflictPolicy {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "new_version" => ConflictPolicy::NewVersion,
+            "reject" => ConflictPolicy::Reject,
+            _ => ConflictPolicy::KeepBoth,
+        }
+    }
+}
+
+/// How a new file is stored, decided by [`resolve_conflict`].
+pub(crate) enum Resolution {
+    Insert { original_filename: Option<String> },
+    NewVersion { file_id: String },
+}
+
+/// A file stored by [`save_space_file`].
+pub(crate) enum Saved {
+    Added(SpaceFile),
+    Versioned(SpaceFile),
+}
+
+impl Saved {
+    pub fn file(&self) -> &SpaceFile {
+        match self {
+            Saved::Added(file) | Saved::Versioned(file) => file,
+        }
+    }
+
+    pub fn event(&self) -> SpaceEvent {
+        match self {
+            Saved::Added(file) => SpaceEvent::FileAdded { file: file.clone() },
+            Saved::Versioned(file) => SpaceEvent::FileUpdated { file: file.clone() },
+        }
+    }
+
+    pub fn into_file(self) -> SpaceFile {
+        match self {
+            Saved::Added(file) | Saved::Versioned(file) => file,
+        }
+    }
+}
+
+/// Splits "itinerary.pdf" into "itinerary" and ".pdf", a leading dot doesn't start an extension.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+/// "itinerary.pdf" becomes "itinerary (1).pdf".
+fn numbered_name(name: &str, n: u32) -> String {
+    let (stem, extension) = split_extension(name);
+    format!("{} ({}){}", stem, n, extension)
+}
+
+/// Applies the conflict policy of the space to a file about to be stored as `file`.
+/// Call it before acquiring the blob, so a rejected upload doesn't leave one behind.
+pub(crate) async fn resolve_conflict(
+    tx: &mut PgTransaction<'_>,
+    file: &NewSpaceFile<'_>,
+) -> Result<Resolution, AppError> {
+    let Some(name) = &file.original_filename else {
+        return Ok(Resolution::Insert {
+            original_filename: None,
+        });
+    };
+
+    // uploads of the same name wait for each other until the first one is committed
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock(hashtext($1 || '/' || COALESCE($2, '') || '/' || $3))",
+        file.space_id,
+        file.folder_id,
+        name
+    )
+    .execute(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    let policy: ConflictPolicy = sqlx::query_scalar!(
+        "SELECT conflict_policy FROM spaces WHERE id = $1",
+        file.space_id
+    )
+    .fetch_one(&mut **tx)
+    .await
+    .into_db_error()?
+    .into();
+
+    let existing = sqlx::query_scalar!(
+        r#"
+        SELECT id FROM files
+        WHERE space_id = $1 AND folder_id IS NOT DISTINCT FROM $2 AND original_filename = $3
+            AND deleted_at IS NULL
+        ORDER BY upload_date, id
+        LIMIT 1
+        "#,
+        file.space_id,
+        file.folder_id,
+        name
+    )
+    .fetch_optional(&mut **tx)
+    .await
+    .into_db_error()?;
+
+    let Some(file_id) = existing else {
+        return Ok(Resolution::Insert {
+            original_filename: Some(name.clone()),
+        });
+    };
+
+    match policy {
+        ConflictPolicy::NewVersion => Ok(Resolution::NewVersion { file_id }),
+        ConflictPolicy::Reject => Err(AppError::new(
+            ErrorType::Conflict(format!("There already is a file named \"{}\" here", name)),
+            anyhow!("File name {} taken in space {}", name, file.space_id),
+        )),
+        ConflictPolicy::KeepBoth => {
+            let taken = sqlx::query_scalar!(
+                r#"
+                SELECT original_filename FROM files
+                WHERE space_id = $1 AND folder_id IS NOT DISTINCT FROM $2 AND deleted_at IS NULL
+                    AND starts_with(original_filename, $3)
+                "#,
+                file.space_id,
+                file.folder_id,
+                split_extension(name).0
+            )
+            .fetch_all(&mut **tx)
+            .await
+            .into_db_error()?;
+
+            let original_filename = (1..)
+                .map(|n| numbered_name(name, n))
+                .find(|candidate| !taken.contains(candidate))
+                .expect("some number is free");
+            Ok(Resolution::Insert {
+                original_filename: Some(original_filename),
+            })
+        }
+    }
+}
+
+/// Stores `file` as [`resolve_conflict`] decided.
+pub(crate) async fn save_space_file(
+    tx: &mut PgTransaction<'_>,
+    resolution: Resolution,
+    file: NewSpaceFile<'_>,
+) -> Result<Saved, AppError> {
+    match resolution {
+        Resolution::Insert { original_filename } => Ok(Saved::Added(
+            insert_space_file(
+                tx,
+                NewSpaceFile {
+                    original_filename,
+                    ..file
+                },
+            )
+            .await?,
+        )),
+        Resolution::NewVersion { file_id } => Ok(Saved::Versioned(
+            replace_space_file(tx, &file_id, file).await?,
+        )),
+    }
+}
+
+#[derive(Serialize)]
+pub struct FileVersion {
+    version: i32,
+    checksum: String,
+    file_size_bytes: i64,
+    mime_type: Option<String>,
+    uploaded_by: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    upload_date: OffsetDateTime,
+    current: bool,
+}
+
+async fn get_version(pool: &PgPool, file_id: &str, version: i32) -> Result<FileVersion, AppError> {
+    sqlx::query_as!(
+        FileVersion,
+        r#"
+        SELECT version, checksum, file_size_bytes, mime_type, uploaded_by, upload_date,
+            FALSE AS "current!"
+        FROM file_versions WHERE file_id = $1 AND version = $2
+        "#,
+        file_id,
+        version
+    )
+    .fetch_optional(pool)
+    .await
+    .into_db_error()?
+    .ok_or_else(|| {
+        AppError::new(
+            ErrorType::NotFound("Version not found".into()),
+            anyhow!("File {} has no earlier version {}", file_id, version),
+        )
+    })
+}
+
+/// Every version of a file, the current one first.
+#[debug_handler()]
+pub async fn file_versions_get(
+    State(AppState { pool, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path(file_id): Path<String>,
+) -> Result<Json<Vec<FileVersion>>, AppError> {
+    require_file_access(&pool, &file_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let versions = sqlx::query_as!(
+        FileVersion,
+        r#"
+        SELECT version AS "version!", checksum AS "checksum!", file_size_bytes AS "file_size_bytes!",
+            mime_type, uploaded_by, upload_date AS "upload_date!", current AS "current!"
+        FROM (
+            SELECT version, checksum, file_size_bytes, mime_type, uploaded_by, upload_date, TRUE AS current
+            FROM files WHERE id = $1 AND deleted_at IS NULL
+            UNION ALL
+            SELECT version, checksum, file_size_bytes, mime_type, uploaded_by, upload_date, FALSE
+            FROM file_versions WHERE file_id = $1
+                AND EXISTS (SELECT 1 FROM files WHERE id = $1 AND deleted_at IS NULL)
+        ) AS versions
+        ORDER BY version DESC
+        "#,
+        file_id
+    )
+    .fetch_all(&pool)
+    .await
+    .into_db_error()?;
+
+    Ok(Json::from(versions))
+}
+
+#[debug_handler()]
+pub async fn file_version_download(
+    State(AppState { pool, store, .. }): State<AppState>,
+    user: AuthUser,
+    token: SpaceToken,
+    Path((file_id, version)): Path<(String, i32)>,
+    method: Method,
+    request_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    require_file_access(&pool, &file_id, &user, &token, SpaceRole::Viewer).await?;
+
+    let file_meta = get_space_file(&pool, &file_id).await?;
+    let file_meta = if version == file_meta.version {
+        file_meta
+    } else {
+        let earlier = get_version(&pool, &file_id, version).await?;
+        file_meta.with_content(earlier.checksum, earlier.file_size_bytes, earlier.mime_type)
+    };
+
+    serve_file(
+        &pool,
+        store.as_ref(),
+        file_meta,
+        None,
+        method,
+        &request_headers,
+    )
+    .await
+}
+
+/// Makes an earlier version current again. It becomes a new version, so nothing is lost.
+#[debug_handler()]
+pub async fn file_version_restore(
+    State(AppState {
+        pool,
+        events,
+        thumbnails,
+        quotas,
+        ..
+    }): State<AppState>,
+    user: AuthUser,
+    Path((file_id, version)): Path<(String, i32)>,
+) -> Result<Json<SpaceFile>, AppError> {
+    let space_id = require_file_role(&pool, &file_id, &user, SpaceRole::Editor).await?;
+    // trashed files have to be restored first
+    get_space_file(&pool, &file_id).await?;
+    let earlier = get_version(&pool, &file_id, version).await?;
+
+    let mut tx = pool.begin().await.into_db_error()?;
+    reference_blob(&mut tx, &earlier.checksum).await?;
+    let file = replace_space_file(
+        &mut tx,
+        &file_id,
+        NewSpaceFile {
+            space_id: &space_id,
+            folder_id: None,
+            original_filename: None,
+            file_size_bytes: earlier.file_size_bytes,
+            checksum: &earlier.checksum,
+            mime_type: earlier.mime_type,
+            uploaded_by: Some(&user.id),
+        },
+    )
+    .await?;
+    // the restored version is stored again on top of the ones kept
+    reserve_quota(&mut tx, &space_id, earlier.file_size_bytes, &quotas).await?;
+    tx.commit().await.into_db_error()?;
+
+    thumbnails.schedule(&file.checksum, file.mime_type.as_deref());
+    events.emit(&space_id, SpaceEvent::FileUpdated { file: file.clone() });
+
+    Ok(Json::from(file))
+}
@@ -2,6 +2,8 @@ import { defineConfig } from "@solidjs/start/config";
 import tailwindcss from "@tailwindcss/vite";
 
 export default defineConfig({
+	// the pages fetch from the backend with the browser's session cookie, which the server doesn't have
+	ssr: false,
 	server: {
 		preset: "bun",
 	},
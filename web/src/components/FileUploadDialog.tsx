@@ -3,6 +3,7 @@ import { action } from "@solidjs/router"
 import { Plus } from "lucide-solid"
 import { Setter } from "solid-js"
 import { createSignal, JSX, Show } from "solid-js"
+import { api } from "~/lib/helpers"
 import { File } from "~/routes/spaces/[id]"
 export default function FileUploadDialog(props: { spaceID: string }) {
 	const [open, setOpen] = createSignal(false)
@@ -13,7 +14,7 @@ export default function FileUploadDialog(props: { spaceID: string }) {
 		const form = e.target as HTMLFormElement
 		const formData = new FormData(form)
 		try {
-			const res = await fetch(`${import.meta.env.VITE_BACKEND_URL}/api/spaces/${props.spaceID}/files`, {
+			const res = await api(`/api/spaces/${props.spaceID}/files`, {
 				method: "POST",
 				body: formData,
 				// Don't set Content-Type header - browser will set it with correct boundary
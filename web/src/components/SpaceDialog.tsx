@@ -1,6 +1,7 @@
 import { Dialog } from "@kobalte/core/dialog"
 import { Plus } from "lucide-solid"
 import { createSignal, Setter } from "solid-js"
+import { api } from "~/lib/helpers"
 import { Space } from "~/routes"
 export default function SpaceDialog(props: { mutateSpace: Setter<Space[] | undefined> }) {
 	const [name, setName] = createSignal("")
@@ -16,7 +17,7 @@ export default function SpaceDialog(props: { mutateSpace: Setter<Space[] | undef
 			return
 		}
 
-		const res = await fetch(`${import.meta.env.VITE_BACKEND_URL}/api/spaces`, {
+		const res = await api("/api/spaces", {
 			method: "POST",
 			headers: {
 				"Content-Type": "application/json",
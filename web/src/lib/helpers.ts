@@ -13,3 +13,8 @@ export function formatBytes(size: number) {
 
 
 }
+
+/** Calls the backend, with the session cookie it set on login. */
+export function api(path: string, init?: RequestInit) {
+	return fetch(`${import.meta.env.VITE_BACKEND_URL}${path}`, { credentials: "include", ...init })
+}
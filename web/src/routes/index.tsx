@@ -1,9 +1,10 @@
 import { Dialog } from "@kobalte/core/dialog";
-import { A } from "@solidjs/router";
-import { Plus } from "lucide-solid";
+import { A, useNavigate } from "@solidjs/router";
+import { LogOut, Plus } from "lucide-solid";
 import { createResource, For } from "solid-js";
 import SpaceCard from "~/components/SpaceCard";
 import SpaceDialog from "~/components/SpaceDialog";
+import { api } from "~/lib/helpers";
 
 export interface Space {
 	id: string,
@@ -13,24 +14,53 @@ export interface Space {
 	total_size_used_bytes: number,
 	created_at: string,
 	updated_at: string
-	access_code?: string
+	has_access_code: boolean
+	strip_gps: boolean
+	conflict_policy: "keep_both" | "new_version" | "reject"
+	quota_bytes?: number
+	max_file_size_bytes?: number
+	usage: SpaceUsage
+}
+
+export interface SpaceUsage {
+	used_bytes: number,
+	quota_bytes?: number,
+	max_file_size_bytes?: number
+}
+
+export interface Page<T> {
+	items: T[],
+	total: number,
+	next?: string
 }
 
 export default function Home() {
+	const navigate = useNavigate()
 	const [spaces, { mutate }] = createResource(async () => {
-		const res = await fetch(`${import.meta.env.VITE_BACKEND_URL!}/api/spaces`)
-		if (!res.ok) {
+		const res = await api("/api/spaces")
+		if (res.status == 401) {
+			navigate("/login")
+		} else if (!res.ok) {
 			console.error(await res.text())
 		} else {
-			const data = await res.json()
-			return data as Space[]
+			const data = await res.json() as Page<Space>
+			return data.items
 		}
 	})
+	const logout = async () => {
+		await api("/api/auth/logout", { method: "POST" })
+		navigate("/login")
+	}
 	return (
 		<main class="grid items-start p-4">
 			<div class="py-4 items-center justify-between flex flex-row">
 				<h1 class="lg:text-6xl text-rose-800">Spaces</h1>
-				<SpaceDialog mutateSpace={mutate} />
+				<div class="flex flex-row items-center gap-4">
+					<SpaceDialog mutateSpace={mutate} />
+					<button onClick={logout} title="Log out">
+						<LogOut class="size-10 stroke-rose-800" />
+					</button>
+				</div>
 
 			</div>
 			<div class="grid lg:grid-cols-5 grid-cols-1 gap-4">
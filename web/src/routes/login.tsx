@@ -0,0 +1,53 @@
+import { useNavigate } from "@solidjs/router";
+import { createSignal, Show } from "solid-js";
+import { api } from "~/lib/helpers";
+
+export default function Login() {
+	const navigate = useNavigate()
+	const [username, setUsername] = createSignal("")
+	const [password, setPassword] = createSignal("")
+	const [message, setMessage] = createSignal("")
+
+	// both log in right away, registering creates the account first
+	const submit = async (path: "/api/auth/login" | "/api/auth/register") => {
+		if (username() == "" || password() == "") {
+			return
+		}
+		const res = await api(path, {
+			method: "POST",
+			headers: {
+				"Content-Type": "application/json",
+			},
+			body: JSON.stringify({ username: username(), password: password() })
+		})
+		if (!res.ok) {
+			const error = await res.json().catch(() => undefined)
+			setMessage(error?.message ?? res.statusText)
+		} else {
+			navigate("/")
+		}
+	}
+
+	return (
+		<main class="grid items-start p-4">
+			<h1 class="lg:text-6xl text-rose-800 py-4">Spaces</h1>
+			<form
+				class="flex flex-col gap-4 max-w-md"
+				onSubmit={(ev) => {
+					ev.preventDefault()
+					submit("/api/auth/login")
+				}}
+			>
+				<Show when={message()}>
+					<p class="text-rose-800">{message()}</p>
+				</Show>
+				<input type="text" placeholder="Username" autocomplete="username" class="text-xl border-2 border-gray-700" value={username()} onInput={(ev) => setUsername(ev.currentTarget.value)} />
+				<input type="password" placeholder="Password" autocomplete="current-password" class="text-xl border-2 border-gray-700" value={password()} onInput={(ev) => setPassword(ev.currentTarget.value)} />
+				<div class="flex flex-row gap-4">
+					<button class="bg-rose-800 py-2 px-4 text-white" type="submit">Log in</button>
+					<button class="border-2 border-rose-800 py-2 px-4 text-rose-800" type="button" onClick={() => submit("/api/auth/register")}>Register</button>
+				</div>
+			</form>
+		</main>
+	);
+}
@@ -1,9 +1,9 @@
 import { A, createAsync, query, redirect, useParams } from "@solidjs/router";
-import { Space } from "..";
+import { Page, Space } from "..";
 import { Show, Suspense } from "solid-js";
 import { ArrowLeft } from "lucide-solid";
 import Table, { Column } from "~/components/Table";
-import { formatBytes } from "~/lib/helpers";
+import { api, formatBytes } from "~/lib/helpers";
 import FileUploadDialog from "~/components/FileUploadDialog";
 
 export interface File {
@@ -15,22 +15,28 @@ export interface File {
 	upload_date: Date,
 	last_accessed?: Date,
 	download_count: number,
-	checksum: string
+	checksum: string,
+	uploaded_by?: string
+	folder_id?: string
+	version: number
 }
 const getSpaceWithFiles = query(async () => {
 	const params = useParams();
 
-	const res = await fetch(`${import.meta.env.VITE_BACKEND_URL!}/api/spaces/${params.id}`)
+	const res = await api(`/api/spaces/${params.id}`)
+	if (res.status == 401) {
+		throw redirect("/login")
+	}
 	if (!res.ok) {
 		throw redirect("/")
 	}
 
 	const space = await res.json() as Space;
-	const res_files = await fetch(`${import.meta.env.VITE_BACKEND_URL!}/api/spaces/${space.id}/files`)
-	if (!res.ok) {
+	const res_files = await api(`/api/spaces/${space.id}/files`)
+	if (!res_files.ok) {
 		throw redirect("/")
 	}
-	const files = await res_files.json() as File[];
+	const files = (await res_files.json() as Page<File>).items;
 	return { space, files }
 
 }, "getSpaceWithFiles")